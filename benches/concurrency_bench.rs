@@ -7,7 +7,6 @@ use rand::prelude::*;
 use std::iter::Iterator;
 use std::sync::{atomic::AtomicBool, Arc};
 use tempfile::TempDir;
-use tokio;
 
 const CHARSET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789)(*&^%$#@!~";
 const KEY_LEN: usize = 10;
@@ -90,9 +89,10 @@ fn write(c: &mut Criterion) {
         let state = Arc::new(AtomicBool::new(true));
         let mut server = KvsServer::new(engine, state.clone());
         rt.spawn(async move {
-            if let Err(_) = server
+            if server
                 .start(format!("127.0.0.1:{}", port + thread_num))
                 .await
+                .is_err()
             {
                 eprintln!("bind error");
             }
@@ -159,9 +159,10 @@ fn read(c: &mut Criterion) {
         let state = Arc::new(AtomicBool::new(true));
         let mut server = KvsServer::new(engine, state.clone());
         rt.spawn(async move {
-            if let Err(_) = server
+            if server
                 .start(format!("127.0.0.1:{}", port + thread_num))
                 .await
+                .is_err()
             {
                 eprintln!("bind error");
             }
@@ -210,9 +211,10 @@ fn read(c: &mut Criterion) {
         let state = Arc::new(AtomicBool::new(true));
         let mut server = KvsServer::new(engine, state.clone());
         rt.spawn(async move {
-            if let Err(_) = server
+            if server
                 .start(format!("127.0.0.1:{}", port + thread_num))
                 .await
+                .is_err()
             {
                 eprintln!("bind error");
             }
@@ -245,12 +247,7 @@ fn read(c: &mut Criterion) {
     group.finish();
 }
 
-async fn async_sets(
-    keys: &Vec<String>,
-    values: &Vec<&'static str>,
-    port: usize,
-    thread_num: usize,
-) {
+async fn async_sets(keys: &[String], values: &[&'static str], port: usize, thread_num: usize) {
     let mut wg = WaitGroup::new();
     for i in 0..1000 {
         let worker = wg.worker();
@@ -262,7 +259,7 @@ async fn async_sets(
                 client = KvsClient::connect(format!("127.0.0.1:{}", port + thread_num)).await;
                 eprintln!("connect error {}", e);
             }
-            let mut client = client.unwrap();
+            let client = client.unwrap();
             if let Err(e) = client.set(key.clone(), value.clone()).await {
                 eprintln!("set error {}", e);
             }
@@ -272,12 +269,7 @@ async fn async_sets(
     wg.wait().await;
 }
 
-async fn async_gets(
-    keys: &Vec<String>,
-    values: &Vec<&'static str>,
-    port: usize,
-    thread_num: usize,
-) {
+async fn async_gets(keys: &[String], values: &[&'static str], port: usize, thread_num: usize) {
     let mut wg = WaitGroup::new();
     for i in 0..1000 {
         let worker = wg.worker();
@@ -285,10 +277,10 @@ async fn async_gets(
         let value = values[i].to_owned();
         tokio::spawn(async move {
             let mut client = KvsClient::connect(format!("127.0.0.1:{}", port + thread_num)).await;
-            while let Err(_) = client {
+            while client.is_err() {
                 client = KvsClient::connect(format!("127.0.0.1:{}", port + thread_num)).await;
             }
-            let mut client = client.unwrap();
+            let client = client.unwrap();
             assert_eq!(client.get(key.clone()).await.unwrap(), Some(value.clone()));
             client.close();
             drop(worker);
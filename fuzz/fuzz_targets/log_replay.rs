@@ -0,0 +1,31 @@
+//! Replay arbitrary bytes as the only log of a `KvStore`.
+//!
+//! Opening may fail, but must neither panic nor allocate beyond the size
+//! of the log, and whatever is opened must be readable.
+//!
+//! Run with `cargo fuzz run log_replay` in this directory.
+#![no_main]
+use kvs::{KvStore, KvsEngine};
+use libfuzzer_sys::fuzz_target;
+use std::fs;
+use std::sync::OnceLock;
+use tempfile::TempDir;
+use tokio::runtime::Runtime;
+
+static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+
+fuzz_target!(|data: &[u8]| {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(temp_dir.path().join("1.log"), data).expect("unable to write the log");
+    let store = match KvStore::open(temp_dir.path()) {
+        Ok(store) => store,
+        Err(_) => return,
+    };
+    let rt = RUNTIME.get_or_init(|| Runtime::new().expect("failed to create tokio runtime"));
+    rt.block_on(async move {
+        let pairs = store.scan_bytes(.., None).await.expect("replayed log is unreadable");
+        for (key, value) in pairs {
+            assert_eq!(store.get_bytes(key).await.unwrap(), Some(value));
+        }
+    });
+});
@@ -0,0 +1,13 @@
+//! Feed arbitrary bytes to the server as the frames of a connection, in the
+//! codec picked by the first byte.
+//!
+//! Decoding may stop at an invalid frame, but must neither panic nor
+//! allocate beyond the maximum frame length.
+//!
+//! Run with `cargo fuzz run request_decoder` in this directory.
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    kvs::server::decode_requests(data);
+});
@@ -1,9 +1,13 @@
 use anyhow::{Context, Result};
-use kvs::KvsClient;
+use kvs::{Codec, DumpFormat, DumpReader, DumpWriter, KvsClient};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Write};
 use std::net::SocketAddr;
+use std::ops::Bound;
+use std::path::PathBuf;
 use std::process::exit;
+use std::time::Duration;
 use structopt::StructOpt;
-use tokio;
 
 const DEFAULT_ADDRESS: &str = "127.0.0.1:4000";
 
@@ -12,6 +16,15 @@ const DEFAULT_ADDRESS: &str = "127.0.0.1:4000";
 pub struct ClientArgs {
     #[structopt(subcommand)]
     pub command: Command,
+
+    #[structopt(
+        long,
+        global = true,
+        help = "How requests are encoded: json or bincode.",
+        default_value = "json",
+        parse(try_from_str)
+    )]
+    pub codec: Codec,
 }
 #[derive(Debug, StructOpt)]
 pub enum Command {
@@ -23,6 +36,9 @@ pub enum Command {
         #[structopt(name = "VALUE", help = "The string value of the key")]
         value: String,
 
+        #[structopt(long, help = "Expire the key after this many seconds")]
+        ttl: Option<u64>,
+
         #[structopt(
             long,
             help = "The server address to be connected.",
@@ -46,6 +62,162 @@ pub enum Command {
         addr: SocketAddr,
     },
 
+    #[structopt(name = "scan", about = "Get key/value pairs whose keys are in a range")]
+    Scan {
+        #[structopt(long, help = "The first key of the range")]
+        start: Option<String>,
+
+        #[structopt(long, help = "The key right after the range")]
+        end: Option<String>,
+
+        #[structopt(long, help = "Get at most this many pairs")]
+        limit: Option<usize>,
+
+        #[structopt(
+            long,
+            help = "The server address to be connected.",
+            default_value = DEFAULT_ADDRESS,
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+
+    #[structopt(
+        name = "prefix",
+        about = "Get key/value pairs whose keys start with a prefix"
+    )]
+    Prefix {
+        #[structopt(name = "PREFIX", help = "A string prefix")]
+        prefix: String,
+
+        #[structopt(long, help = "Get at most this many pairs")]
+        limit: Option<usize>,
+
+        #[structopt(
+            long,
+            help = "The server address to be connected.",
+            default_value = DEFAULT_ADDRESS,
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+
+    #[structopt(name = "ttl", about = "Get how many seconds a given key lives")]
+    Ttl {
+        #[structopt(name = "KEY", help = "A string key")]
+        key: String,
+
+        #[structopt(
+            long,
+            help = "The server address to be connected.",
+            default_value = DEFAULT_ADDRESS,
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+
+    #[structopt(name = "persist", about = "Clear the expiry of a given key")]
+    Persist {
+        #[structopt(name = "KEY", help = "A string key")]
+        key: String,
+
+        #[structopt(
+            long,
+            help = "The server address to be connected.",
+            default_value = DEFAULT_ADDRESS,
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+
+    #[structopt(
+        name = "cas",
+        about = "Swap the value of a key only if it's the expected one"
+    )]
+    Cas {
+        #[structopt(name = "KEY", help = "A string key")]
+        key: String,
+
+        #[structopt(long, help = "The expected value, the key must be absent if omitted")]
+        expected: Option<String>,
+
+        #[structopt(long, help = "The new value, the key is removed if omitted")]
+        new: Option<String>,
+
+        #[structopt(
+            long,
+            help = "The server address to be connected.",
+            default_value = DEFAULT_ADDRESS,
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+
+    #[structopt(
+        name = "backup",
+        about = "Make the server write a copy of its store to a directory"
+    )]
+    Backup {
+        #[structopt(
+            name = "DIR",
+            help = "A directory within the server's backup directory, it must not exist or be empty",
+            parse(from_os_str)
+        )]
+        dest: PathBuf,
+
+        #[structopt(
+            long,
+            help = "The server address to be connected.",
+            default_value = DEFAULT_ADDRESS,
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+
+    #[structopt(name = "export", about = "Dump every key/value pair of the server")]
+    Export {
+        #[structopt(
+            long,
+            help = "Format of the dump: json or binary",
+            default_value = "json",
+            parse(try_from_str)
+        )]
+        format: DumpFormat,
+
+        #[structopt(
+            long,
+            help = "Write the dump to this file instead of stdout",
+            parse(from_os_str)
+        )]
+        output: Option<PathBuf>,
+
+        #[structopt(
+            long,
+            help = "The server address to be connected.",
+            default_value = DEFAULT_ADDRESS,
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+
+    #[structopt(name = "import", about = "Set every key/value pair of a dump")]
+    Import {
+        #[structopt(
+            long,
+            help = "Read the dump from this file instead of stdin",
+            parse(from_os_str)
+        )]
+        input: Option<PathBuf>,
+
+        #[structopt(
+            long,
+            help = "The server address to be connected.",
+            default_value = DEFAULT_ADDRESS,
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+
     #[structopt(name = "rm", about = "Remove a given key")]
     Remove {
         #[structopt(name = "KEY", help = "A string key")]
@@ -75,24 +247,126 @@ fn main() -> Result<()> {
 }
 
 async fn run(opt: ClientArgs) -> Result<()> {
+    let codec = opt.codec;
     match opt.command {
-        Command::Set { key, value, addr } => {
+        Command::Set {
+            key,
+            value,
+            ttl,
+            addr,
+        } => {
             // println!("set {} {} {}", key, value, addr);
-            let mut client = KvsClient::connect(addr).await?;
-            client.set(key, value).await?;
+            let client = KvsClient::connect_with_codec(addr, codec).await?;
+            match ttl {
+                Some(secs) => {
+                    client
+                        .set_with_ttl(key, value, Duration::from_secs(secs))
+                        .await?
+                }
+                None => client.set(key, value).await?,
+            }
         }
         Command::Get { key, addr } => {
-            let mut client = KvsClient::connect(addr).await?;
-            if let Some(value) = client.get(key).await? {
-                println!("{}", value);
+            let client = KvsClient::connect_with_codec(addr, codec).await?;
+            // values are printed as they are, they needn't be UTF-8
+            if let Some(value) = client.get_bytes(key.into_bytes()).await? {
+                let mut stdout = io::stdout();
+                stdout.write_all(&value)?;
+                stdout.write_all(b"\n")?;
             } else {
                 println!("Key not found");
             }
         }
+        Command::Ttl { key, addr } => {
+            let client = KvsClient::connect_with_codec(addr, codec).await?;
+            match client.ttl(key).await? {
+                // round up, a key with less than a second left still lives
+                Some(ttl) => println!("{}", ttl.as_millis().div_ceil(1000)),
+                None => println!("No expiry"),
+            }
+        }
+        Command::Persist { key, addr } => {
+            let client = KvsClient::connect_with_codec(addr, codec).await?;
+            client.persist(key).await?;
+        }
+        Command::Cas {
+            key,
+            expected,
+            new,
+            addr,
+        } => {
+            let client = KvsClient::connect_with_codec(addr, codec).await?;
+            client.compare_and_swap(key, expected, new).await?;
+        }
+        Command::Backup { dest, addr } => {
+            let client = KvsClient::connect_with_codec(addr, codec).await?;
+            client.checkpoint(dest).await?;
+        }
+        Command::Export {
+            format,
+            output,
+            addr,
+        } => {
+            let client = KvsClient::connect_with_codec(addr, codec).await?;
+            match output {
+                Some(path) => export(&client, BufWriter::new(File::create(path)?), format).await?,
+                None => export(&client, io::stdout().lock(), format).await?,
+            }
+        }
+        Command::Import { input, addr } => {
+            let client = KvsClient::connect_with_codec(addr, codec).await?;
+            match input {
+                Some(path) => import(&client, BufReader::new(File::open(path)?)).await?,
+                None => import(&client, io::stdin().lock()).await?,
+            }
+        }
         Command::Remove { key, addr } => {
-            let mut client = KvsClient::connect(addr).await?;
+            let client = KvsClient::connect_with_codec(addr, codec).await?;
             client.remove(key).await?;
         }
+        Command::Scan {
+            start,
+            end,
+            limit,
+            addr,
+        } => {
+            let start = start.map_or(Bound::Unbounded, |key| Bound::Included(key.into_bytes()));
+            let end = end.map_or(Bound::Unbounded, |key| Bound::Excluded(key.into_bytes()));
+            let client = KvsClient::connect_with_codec(addr, codec).await?;
+            print_pairs(client.scan_bytes((start, end), limit).await?)?;
+        }
+        Command::Prefix {
+            prefix,
+            limit,
+            addr,
+        } => {
+            let client = KvsClient::connect_with_codec(addr, codec).await?;
+            print_pairs(client.scan_prefix_bytes(prefix.into_bytes(), limit).await?)?;
+        }
+    }
+    Ok(())
+}
+
+// dump pairs a page at a time, in the order of keys
+async fn export(client: &KvsClient, writer: impl Write, format: DumpFormat) -> Result<()> {
+    let mut writer = DumpWriter::new(writer, format)?;
+    kvs::export(client, &mut writer).await?;
+    writer.finish()?;
+    Ok(())
+}
+
+async fn import(client: &KvsClient, reader: impl io::BufRead) -> Result<()> {
+    kvs::import(client, DumpReader::new(reader)?).await?;
+    Ok(())
+}
+
+fn print_pairs(pairs: Vec<(Vec<u8>, Vec<u8>)>) -> Result<()> {
+    let mut stdout = io::stdout();
+    for (key, value) in pairs {
+        stdout.write_all(&key)?;
+        stdout.write_all(b"\t")?;
+        stdout.write_all(&value)?;
+        stdout.write_all(b"\n")?;
     }
     Ok(())
 }
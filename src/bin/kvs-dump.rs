@@ -0,0 +1,157 @@
+use anyhow::{Context, Result};
+use clap::arg_enum;
+use kvs::{DumpFormat, DumpReader, DumpWriter, KvStore, SledKvsEngine};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use structopt::StructOpt;
+
+arg_enum! {
+    #[allow(non_camel_case_types)]
+    #[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+    enum Engine {
+        kvs,
+        sled
+    }
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "kvs-dump",
+    about = "Export or import the data directory of a stopped kvs-server"
+)]
+struct DumpArgs {
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    #[structopt(
+        name = "export",
+        about = "Dump every key/value pair of a data directory"
+    )]
+    Export {
+        #[structopt(name = "DIR", help = "The data directory", parse(from_os_str))]
+        dir: PathBuf,
+
+        #[structopt(
+            long,
+            help = "Format of the dump: json or binary",
+            default_value = "json",
+            parse(try_from_str)
+        )]
+        format: DumpFormat,
+
+        #[structopt(
+            long,
+            help = "Write the dump to this file instead of stdout",
+            parse(from_os_str)
+        )]
+        output: Option<PathBuf>,
+
+        #[structopt(
+            long,
+            help = "The engine of the directory if it has no engine.log.",
+            possible_values = &Engine::variants(),
+            case_insensitive = false,
+        )]
+        engine: Option<Engine>,
+    },
+
+    #[structopt(
+        name = "import",
+        about = "Set every key/value pair of a dump in a data directory"
+    )]
+    Import {
+        #[structopt(name = "DIR", help = "The data directory", parse(from_os_str))]
+        dir: PathBuf,
+
+        #[structopt(
+            long,
+            help = "Read the dump from this file instead of stdin",
+            parse(from_os_str)
+        )]
+        input: Option<PathBuf>,
+
+        #[structopt(
+            long,
+            help = "The engine of the directory if it has no engine.log.",
+            possible_values = &Engine::variants(),
+            case_insensitive = false,
+        )]
+        engine: Option<Engine>,
+    },
+}
+
+fn main() -> Result<()> {
+    let opt = DumpArgs::from_args();
+    let rt = tokio::runtime::Runtime::new().context("failed to create tokio runtime")?;
+    rt.block_on(async move {
+        if let Err(err) = run(opt).await {
+            eprintln!("{}", err);
+            exit(1);
+        }
+    });
+    Ok(())
+}
+
+async fn run(opt: DumpArgs) -> Result<()> {
+    match opt.command {
+        Command::Export {
+            dir,
+            format,
+            output,
+            engine,
+        } => {
+            let writer: Box<dyn Write> = match output {
+                Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+                None => Box::new(io::stdout()),
+            };
+            let mut writer = DumpWriter::new(writer, format)?;
+            match determine_engine(&dir, engine)? {
+                Engine::kvs => kvs::export(&KvStore::open(&dir)?, &mut writer).await?,
+                Engine::sled => kvs::export(&SledKvsEngine::open(&dir)?, &mut writer).await?,
+            };
+            writer.finish()?;
+        }
+        Command::Import { dir, input, engine } => {
+            let reader: Box<dyn BufRead> = match input {
+                Some(path) => Box::new(BufReader::new(File::open(path)?)),
+                None => Box::new(BufReader::new(io::stdin())),
+            };
+            let reader = DumpReader::new(reader)?;
+            let engine = determine_engine(&dir, engine)?;
+            fs::create_dir_all(&dir)?;
+            // so kvs-server opens the directory with the same engine
+            serde_json::to_writer(File::create(dir.join("engine.log"))?, &engine)?;
+            match engine {
+                Engine::kvs => kvs::import(&KvStore::open(&dir)?, reader).await?,
+                Engine::sled => kvs::import(&SledKvsEngine::open(&dir)?, reader).await?,
+            };
+        }
+    }
+    Ok(())
+}
+
+// the engine recorded in engine.log wins, like kvs-server does it
+fn determine_engine(dir: &Path, engine: Option<Engine>) -> Result<Engine> {
+    let engine_log = dir.join("engine.log");
+    let previous = if engine_log.exists() {
+        Some(serde_json::from_reader(File::open(engine_log)?)?)
+    } else {
+        None
+    };
+    match (engine, previous) {
+        (Some(engine), Some(previous)) if engine != previous => Err(anyhow::anyhow!(
+            "Engine inconsistent, previous engine: {:?}, choosen engine: {:?}",
+            previous,
+            engine
+        )),
+        (_, Some(previous)) => Ok(previous),
+        (Some(engine), None) => Ok(engine),
+        (None, None) => Ok(Engine::kvs),
+    }
+}
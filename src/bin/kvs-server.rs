@@ -1,26 +1,35 @@
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
 use clap::arg_enum;
+use crc32fast::Hasher;
 use env_logger::Builder;
-use kvs::{KvStore, KvsEngine, KvsServer, SledKvsEngine};
+use kvs::{
+    Codec, EngineOptions, KvStore, KvStoreOptions, KvsEngine, KvsError, KvsServer, MemoryKvsEngine,
+    SledKvsEngine, SyncPolicy, WriteBatch,
+};
 use log::{error, info, LevelFilter};
 use serde::{Deserialize, Serialize};
 use std::fs;
 use std::net::SocketAddr;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
 use std::{env::current_dir, process::exit};
 use structopt::StructOpt;
-use tokio;
-use tokio::net::ToSocketAddrs;
 
 const DEFAULT_ADDRESS: &str = "127.0.0.1:4000";
+/// Pairs copied or checked at once by a migration
+const MIGRATION_PAGE_SIZE: usize = 1000;
+/// Marker of a migration which hasn't finished, in the data directory
+const MIGRATION_MARKER: &str = "migration.json";
 
 arg_enum! {
     #[allow(non_camel_case_types)]
     #[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
     enum Engine {
         kvs,
-        sled
+        sled,
+        memory
     }
 }
 
@@ -42,6 +51,80 @@ pub struct ServerArgs {
         case_insensitive = false,
     )]
     engine: Option<Engine>,
+
+    #[structopt(
+        long,
+        help = "When to fsync writes: always, never or an interval like 100ms.",
+        parse(try_from_str)
+    )]
+    sync: Option<SyncPolicy>,
+
+    #[structopt(
+        long,
+        help = "Start a new log file beyond this many bytes (kvs engine only)."
+    )]
+    max_file_size: Option<u64>,
+
+    #[structopt(
+        long,
+        help = "Compact once there are this many bytes of stale data (kvs engine only)."
+    )]
+    compaction_threshold: Option<u64>,
+
+    #[structopt(
+        long,
+        help = "Compact only if this fraction of log data is stale (kvs engine only)."
+    )]
+    compaction_ratio: Option<f64>,
+
+    #[structopt(
+        long,
+        help = "Buffer size in bytes of log file readers (kvs engine only)."
+    )]
+    read_buffer_size: Option<usize>,
+
+    #[structopt(
+        long,
+        help = "Max log files kept open per connection (kvs engine only)."
+    )]
+    max_open_files: Option<usize>,
+
+    #[structopt(
+        long,
+        help = "Copy the data of the stopped server to another engine, switch to it and exit.",
+        possible_values = &Engine::variants(),
+        case_insensitive = false,
+    )]
+    migrate_to: Option<Engine>,
+
+    #[structopt(
+        long,
+        help = "Codecs clients may pick, like json,bincode (all of them by default).",
+        use_delimiter = true,
+        parse(try_from_str)
+    )]
+    codecs: Option<Vec<Codec>>,
+
+    #[structopt(
+        long,
+        help = "Also serve Redis clients (RESP2) on this address.",
+        parse(try_from_str)
+    )]
+    resp_addr: Option<SocketAddr>,
+
+    #[structopt(
+        long,
+        help = "Let clients write checkpoints to directories within this one.",
+        parse(from_os_str)
+    )]
+    backup_dir: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        help = "Load the data from a snapshot in this directory and write it back on shutdown (memory engine only).",
+        parse(from_os_str)
+    )]
+    memory_snapshot_dir: Option<PathBuf>,
 }
 fn main() {
     Builder::new().filter_level(LevelFilter::Info).init();
@@ -52,47 +135,158 @@ fn main() {
     }
 }
 fn init(opt: ServerArgs) -> Result<()> {
+    if let Some(target) = opt.migrate_to {
+        return migrate(target);
+    }
     let engine = determine_engine(&opt)?;
-    serde_json::to_writer(
-        fs::File::create(current_dir()?.join("engine.log"))?,
-        &engine,
-    )?;
+    if engine != Engine::kvs {
+        if let Some(flag) = kvs_only_flag(&opt) {
+            return Err(anyhow!("{} is only supported by the kvs engine", flag));
+        }
+    }
+    if engine != Engine::memory && opt.memory_snapshot_dir.is_some() {
+        return Err(anyhow!(
+            "--memory-snapshot-dir is only supported by the memory engine"
+        ));
+    }
+    // the memory engine leaves the data directory alone
+    if engine != Engine::memory {
+        if let Some(migration) = read_migration(&current_dir()?)? {
+            return Err(anyhow!(
+                "a migration from {} to {} was interrupted, finish it with --migrate-to {}",
+                migration.source,
+                migration.target,
+                migration.target
+            ));
+        }
+        serde_json::to_writer(
+            fs::File::create(current_dir()?.join("engine.log"))?,
+            &engine,
+        )?;
+    }
     info!("kvs-server {}", env!("CARGO_PKG_VERSION"));
     info!("Storage engine: {}", engine);
     info!("Server address: {}", opt.addr);
+    if let Some(addr) = opt.resp_addr {
+        info!("RESP address: {}", addr);
+    }
+    let mut options = EngineOptions::default();
+    if let Some(sync_policy) = opt.sync {
+        options = options.sync_policy(sync_policy);
+    }
     match engine {
-        Engine::kvs => start(KvStore::open(current_dir()?)?, opt.addr),
-        Engine::sled => start(SledKvsEngine::open(current_dir()?)?, opt.addr),
+        Engine::kvs => start(
+            KvStore::open_with_options(current_dir()?, kv_store_options(&opt, options))?,
+            &opt,
+        ),
+        Engine::sled => start(
+            SledKvsEngine::open_with_options(current_dir()?, options)?,
+            &opt,
+        ),
+        Engine::memory => match &opt.memory_snapshot_dir {
+            Some(dir) => start(MemoryKvsEngine::open(current_dir()?.join(dir))?, &opt),
+            None => start(MemoryKvsEngine::new(), &opt),
+        },
     }
 }
 
-fn start(engine: impl KvsEngine + Sync, addr: impl ToSocketAddrs) -> Result<()> {
+// the first flag given which only the kvs engine knows
+fn kvs_only_flag(opt: &ServerArgs) -> Option<&'static str> {
+    [
+        ("--max-file-size", opt.max_file_size.is_some()),
+        ("--compaction-threshold", opt.compaction_threshold.is_some()),
+        ("--compaction-ratio", opt.compaction_ratio.is_some()),
+        ("--read-buffer-size", opt.read_buffer_size.is_some()),
+        ("--max-open-files", opt.max_open_files.is_some()),
+    ]
+    .iter()
+    .find(|(_, given)| *given)
+    .map(|(flag, _)| *flag)
+}
+
+fn kv_store_options(opt: &ServerArgs, options: EngineOptions) -> KvStoreOptions {
+    let mut kv_options = KvStoreOptions::from(options);
+    if let Some(size) = opt.max_file_size {
+        kv_options = kv_options.max_file_size(size);
+    }
+    if let Some(size) = opt.compaction_threshold {
+        kv_options = kv_options.compaction_threshold(size);
+    }
+    if let Some(ratio) = opt.compaction_ratio {
+        kv_options = kv_options.compaction_ratio(ratio);
+    }
+    if let Some(size) = opt.read_buffer_size {
+        kv_options = kv_options.read_buffer_size(size);
+    }
+    if let Some(count) = opt.max_open_files {
+        kv_options = kv_options.max_open_files(count);
+    }
+    kv_options
+}
+
+fn start(engine: impl KvsEngine, opt: &ServerArgs) -> Result<()> {
     let state = Arc::new(AtomicBool::new(true));
     let mut server = KvsServer::new(engine, state);
+    if let Some(codecs) = &opt.codecs {
+        server = server.codecs(codecs.clone());
+    }
+    if let Some(addr) = opt.resp_addr {
+        server = server.resp_listener(addr);
+    }
+    if let Some(dir) = &opt.backup_dir {
+        // the server runs in its data directory, a relative path is taken
+        // as relative to it
+        server = server.backup_dir(current_dir()?.join(dir));
+    }
     let rt = tokio::runtime::Runtime::new().context("failed to create tokio runtime")?;
-    let _ = rt.block_on(server.start(addr));
+    // the engine is dropped on shutdown along with the runtime's tasks, so
+    // the memory engine writes its snapshot
+    let _ = rt.block_on(async {
+        tokio::select! {
+            res = server.start(opt.addr) => res,
+            res = shutdown_signal() => {
+                info!("Shutting down");
+                res
+            }
+        }
+    });
+    Ok(())
+}
+
+// ctrl-c, or SIGTERM as sent by `kill` and service managers
+async fn shutdown_signal() -> Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            res = tokio::signal::ctrl_c() => res?,
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await?;
     Ok(())
 }
 
 fn determine_engine(opt: &ServerArgs) -> Result<Engine> {
+    if opt.engine == Some(Engine::memory) {
+        return Ok(Engine::memory);
+    }
     let previous_engine = previous_engine()?;
-    let engine = {
-        if opt.engine.is_none() && previous_engine.is_none() {
-            Engine::kvs
-        } else if opt.engine.is_some() && previous_engine.is_none() {
-            opt.engine.unwrap()
-        } else if opt.engine.is_none() && previous_engine.is_some() {
-            previous_engine.unwrap()
-        } else {
-            if opt.engine != previous_engine {
+    let engine = match (opt.engine, previous_engine) {
+        (None, None) => Engine::kvs,
+        (Some(engine), None) => engine,
+        (None, Some(previous)) => previous,
+        (Some(engine), Some(previous)) => {
+            if engine != previous {
                 error!(
                     "Engine inconsistent, previous engine: {:?}, choosen engine: {:?}",
-                    previous_engine.unwrap(),
-                    opt.engine.unwrap()
+                    previous, engine
                 );
                 exit(1);
             }
-            previous_engine.unwrap()
+            previous
         }
     };
     Ok(engine)
@@ -107,3 +301,242 @@ fn previous_engine() -> Result<Option<Engine>> {
     let engine: Engine = serde_json::from_reader(fs::File::open(engine_log)?)?;
     Ok(Some(engine))
 }
+
+// how far a migration has come, recorded in its marker so an interrupted
+// one goes on from there
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Phase {
+    // copying the data to `migrate-to-<target>` and checking it, a copy
+    // which is interrupted starts over
+    Copying,
+    // moving the old data to `<source>-backup`
+    Moving,
+    // moving the new data in place and switching engine.log to it
+    Installing,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Migration {
+    source: Engine,
+    target: Engine,
+    phase: Phase,
+}
+
+// the marker of a migration which hasn't finished
+fn read_migration(dir: &Path) -> Result<Option<Migration>> {
+    let path = dir.join(MIGRATION_MARKER);
+    if !path.exists() {
+        return Ok(None);
+    }
+    Ok(Some(serde_json::from_reader(fs::File::open(path)?)?))
+}
+
+// replace the marker at once, so it's never seen half written
+fn write_migration(dir: &Path, migration: &Migration) -> Result<()> {
+    let tmp = dir.join(format!("{}.tmp", MIGRATION_MARKER));
+    let file = fs::File::create(&tmp)?;
+    serde_json::to_writer(&file, migration)?;
+    file.sync_all()?;
+    fs::rename(tmp, dir.join(MIGRATION_MARKER))?;
+    Ok(())
+}
+
+// copy the data to `target` in a fresh directory and check it, then move the
+// old data aside to `<engine>-backup` and the new data in its place.
+//
+// Each phase is recorded in a marker before it starts, a migration which is
+// interrupted is finished by running it again and the server refuses to
+// start meanwhile.
+fn migrate(target: Engine) -> Result<()> {
+    let dir = current_dir()?;
+    let mut migration = match read_migration(&dir)? {
+        Some(migration) if migration.target != target => {
+            return Err(anyhow!(
+                "a migration to {} was interrupted, finish it first",
+                migration.target
+            ));
+        }
+        Some(migration) => {
+            info!(
+                "Resuming the migration from {} to {}",
+                migration.source, migration.target
+            );
+            migration
+        }
+        None => {
+            let source = previous_engine()?.unwrap_or(Engine::kvs);
+            if target == Engine::memory {
+                return Err(anyhow!("the memory engine keeps no data to migrate to"));
+            }
+            if source == target {
+                return Err(anyhow!("the data is already stored by {}", target));
+            }
+            let old_dir = dir.join(format!("{}-backup", source));
+            if old_dir.exists() {
+                return Err(anyhow!("{} exists, remove it first", old_dir.display()));
+            }
+            info!("Migrating from {} to {}", source, target);
+            Migration {
+                source,
+                target,
+                phase: Phase::Copying,
+            }
+        }
+    };
+    let new_dir = dir.join(format!("migrate-to-{}", target));
+    let old_dir = dir.join(format!("{}-backup", migration.source));
+
+    if migration.phase == Phase::Copying {
+        write_migration(&dir, &migration)?;
+        // left by an interrupted copy
+        if new_dir.exists() {
+            fs::remove_dir_all(&new_dir)?;
+        }
+        let rt = tokio::runtime::Runtime::new().context("failed to create tokio runtime")?;
+        let count = rt.block_on(async {
+            match target {
+                Engine::kvs => {
+                    copy_engine(SledKvsEngine::open(&dir)?, KvStore::open(&new_dir)?).await
+                }
+                Engine::sled => {
+                    copy_engine(KvStore::open(&dir)?, SledKvsEngine::open(&new_dir)?).await
+                }
+                Engine::memory => unreachable!(),
+            }
+        })?;
+        info!("Copied and checked {} keys", count);
+        migration.phase = Phase::Moving;
+        write_migration(&dir, &migration)?;
+    }
+
+    if migration.phase == Phase::Moving {
+        fs::create_dir_all(&old_dir)?;
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            if is_engine_file(migration.source, &entry.path()) {
+                fs::rename(entry.path(), old_dir.join(entry.file_name()))?;
+            }
+        }
+        migration.phase = Phase::Installing;
+        write_migration(&dir, &migration)?;
+    }
+
+    if new_dir.exists() {
+        for entry in fs::read_dir(&new_dir)? {
+            let entry = entry?;
+            fs::rename(entry.path(), dir.join(entry.file_name()))?;
+        }
+        fs::remove_dir(&new_dir)?;
+    }
+    serde_json::to_writer(fs::File::create(dir.join("engine.log"))?, &target)?;
+    fs::remove_file(dir.join(MIGRATION_MARKER))?;
+    info!("Old data is kept in {}", old_dir.display());
+    Ok(())
+}
+
+// files and directories the engine keeps in the data directory, so anything
+// else living there (a nested backup or snapshot directory) stays in place
+fn is_engine_file(engine: Engine, path: &Path) -> bool {
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or("");
+    if name == "engine.log" {
+        return true;
+    }
+    match engine {
+        Engine::kvs => {
+            let stem = [".log", ".hint", ".hint.tmp", ".compact"]
+                .iter()
+                .find_map(|ext| name.strip_suffix(ext));
+            path.is_file()
+                && stem.is_some_and(|stem| {
+                    !stem.is_empty() && stem.bytes().all(|b| b.is_ascii_digit())
+                })
+        }
+        Engine::sled => matches!(name, "conf" | "db" | "blobs") || name.starts_with("snap."),
+        Engine::memory => false,
+    }
+}
+
+// the pairs of an engine a page at a time, in the order of keys
+struct Pages<'a, E> {
+    engine: &'a E,
+    // `None` once the last page has been read
+    from: Option<Bound<Vec<u8>>>,
+}
+
+impl<'a, E: KvsEngine> Pages<'a, E> {
+    fn new(engine: &'a E) -> Pages<'a, E> {
+        Pages {
+            engine,
+            from: Some(Bound::Unbounded),
+        }
+    }
+
+    async fn next_page(&mut self) -> Result<Option<Vec<(Vec<u8>, Vec<u8>)>>> {
+        let from = match self.from.take() {
+            Some(from) => from,
+            None => return Ok(None),
+        };
+        let pairs = self
+            .engine
+            .scan_bytes((from, Bound::Unbounded), Some(MIGRATION_PAGE_SIZE))
+            .await?;
+        // a short page is the last one
+        if pairs.len() == MIGRATION_PAGE_SIZE {
+            self.from = pairs.last().map(|(key, _)| Bound::Excluded(key.clone()));
+        }
+        Ok(Some(pairs))
+    }
+}
+
+// copy every pair a page at a time, expiring keys keep their expiry,
+// and check both engines hold the same pairs which never expire
+async fn copy_engine(src: impl KvsEngine, dst: impl KvsEngine) -> Result<u64> {
+    let mut pages = Pages::new(&src);
+    while let Some(pairs) = pages.next_page().await? {
+        let mut batch = WriteBatch::new();
+        for (key, value) in pairs {
+            match src.ttl_bytes(key.clone()).await {
+                Ok(Some(ttl)) => dst.set_with_ttl_bytes(key, value, ttl).await?,
+                Ok(None) => batch.set(key, value),
+                // expired since it was scanned
+                Err(err) if matches!(err.downcast_ref(), Some(KvsError::KeyNotFound)) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        dst.write_batch(batch).await?;
+    }
+
+    let (count, checksum) = summarize(&src).await?;
+    if summarize(&dst).await? != (count, checksum) {
+        return Err(anyhow!("the copied data differs from the original one"));
+    }
+    Ok(count)
+}
+
+// number of pairs and a crc32 of all of them in the order of keys, leaving
+// out expiring keys which may expire between summarizing the two engines
+async fn summarize(engine: &impl KvsEngine) -> Result<(u64, u32)> {
+    let mut count = 0;
+    let mut hasher = Hasher::new();
+    let mut pages = Pages::new(engine);
+    while let Some(pairs) = pages.next_page().await? {
+        for (key, value) in &pairs {
+            match engine.ttl_bytes(key.clone()).await {
+                Ok(None) => {}
+                Ok(Some(_)) => continue,
+                Err(err) if matches!(err.downcast_ref(), Some(KvsError::KeyNotFound)) => continue,
+                Err(err) => return Err(err),
+            }
+            hasher.update(&(key.len() as u32).to_le_bytes());
+            hasher.update(key);
+            hasher.update(&(value.len() as u32).to_le_bytes());
+            hasher.update(value);
+            count += 1;
+        }
+    }
+    Ok((count, hasher.finalize()))
+}
@@ -1,91 +1,556 @@
 use crate::{
-    protocol::{Request, Response},
-    KvsError, Result,
+    codec::{framed_reader, framed_writer, reframe, request_codec, FramedReader, FramedWriter},
+    engine::{bytes_bound, into_string, into_string_pairs},
+    protocol::{
+        Request, Response, Tagged, FEATURES, MIN_PROTOCOL_VERSION, PIPELINING_VERSION,
+        PROTOCOL_VERSION,
+    },
+    Codec, KvsError, Result, ServerInfo, WriteBatch,
 };
 use futures::prelude::*;
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::ops::RangeBounds;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tokio::net::{
     tcp::{OwnedReadHalf, OwnedWriteHalf},
     TcpStream, ToSocketAddrs,
 };
-use tokio_serde::formats::SymmetricalJson;
-use tokio_util::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+/// Calls waiting for a response by the id of their request, `None` once the
+/// connection is gone
+type Waiters = Arc<Mutex<Option<HashMap<u64, oneshot::Sender<Response>>>>>;
+
 /// A k/v store client
+///
+/// Requests are pipelined, many calls can wait on one connection at once
+/// and the server answers each as soon as it's done. Servers speaking a
+/// protocol version before 3 answer one request at a time instead, and
+/// servers from before the handshake are spoken to without one.
+///
+/// A transaction runs on a connection of its own, see `transaction`.
 pub struct KvsClient {
-    // sender: BufWriter<TcpStream>,
-    // because the from_reader method needs input stream end
-    // so receiver is not a simple BufReader
-    // https://docs.serde.rs/serde_json/fn.from_reader.html
-    // receiver: Deserializer<IoRead<BufReader<TcpStream>>>,
-    reader: tokio_serde::SymmetricallyFramed<
-        FramedRead<OwnedReadHalf, LengthDelimitedCodec>,
-        Response,
-        SymmetricalJson<Response>,
-    >,
-    writer: tokio_serde::SymmetricallyFramed<
-        FramedWrite<OwnedWriteHalf, LengthDelimitedCodec>,
-        Request,
-        SymmetricalJson<Request>,
-    >,
+    connection: Connection,
+    addr: SocketAddr,
+    codec: Codec,
+    server: ServerInfo,
+}
+
+enum Connection {
+    /// requests are sent one at a time, each waits for its response
+    Serial(
+        tokio::sync::Mutex<(
+            FramedReader<OwnedReadHalf, Response>,
+            FramedWriter<OwnedWriteHalf, Request>,
+        )>,
+    ),
+    Pipelined(Pipeline),
+}
+
+/// A connection whose requests are tagged, so their responses can come
+/// back in any order
+struct Pipeline {
+    writer: tokio::sync::Mutex<FramedWriter<OwnedWriteHalf, Tagged<Request>>>,
+    waiters: Waiters,
+    // 0 is left for answers to requests whose id the server couldn't read
+    next_id: AtomicU64,
+    receiver: JoinHandle<()>,
+}
+
+impl Pipeline {
+    fn new(
+        reader: FramedReader<OwnedReadHalf, Response>,
+        writer: FramedWriter<OwnedWriteHalf, Request>,
+        codec: Codec,
+    ) -> Pipeline {
+        let waiters = Arc::new(Mutex::new(Some(HashMap::new())));
+        let receiver = tokio::spawn(receive(reframe(reader, codec), waiters.clone()));
+        Pipeline {
+            writer: tokio::sync::Mutex::new(reframe(writer, codec)),
+            waiters,
+            next_id: AtomicU64::new(1),
+            receiver,
+        }
+    }
+
+    async fn send_and_receive(&self, req: Request) -> Result<Response> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = oneshot::channel();
+        match self.waiters.lock().unwrap().as_mut() {
+            Some(waiters) => waiters.insert(id, sender),
+            None => return Err(cant_receive()),
+        };
+        let mut writer = self.writer.lock().await;
+        if let Err(err) = writer.send(Tagged { id, body: req }).await {
+            if let Some(waiters) = self.waiters.lock().unwrap().as_mut() {
+                waiters.remove(&id);
+            }
+            return Err(err.into());
+        }
+        drop(writer);
+        receiver.await.map_err(|_| cant_receive())
+    }
+}
+
+impl Drop for Pipeline {
+    fn drop(&mut self) {
+        self.receiver.abort();
+    }
 }
 
 impl KvsClient {
-    /// connect to a `KvsServer`
+    /// connect to a `KvsServer`, speaking JSON
     pub async fn connect<A>(addr: A) -> Result<KvsClient>
     where
         A: ToSocketAddrs,
     {
-        let stream = TcpStream::connect(addr).await?;
+        KvsClient::connect_with_codec(addr, Codec::Json).await
+    }
 
-        let (read_half, write_half) = stream.into_split();
+    /// connect to a `KvsServer`, speaking `codec`
+    ///
+    /// # Errors
+    ///
+    /// Returns `KvsError::OtherError` if the server doesn't accept `codec`,
+    /// and `KvsError::ProtocolMismatch` if it speaks no protocol version
+    /// this client speaks.
+    pub async fn connect_with_codec<A>(addr: A, codec: Codec) -> Result<KvsClient>
+    where
+        A: ToSocketAddrs,
+    {
+        let stream = TcpStream::connect(addr).await?;
+        let addr = stream.peer_addr()?;
+        let (mut reader, mut writer) = open(stream, codec).await?;
+        let server = match hello(&mut reader, &mut writer).await? {
+            Some(server) => server,
+            // a server from before the handshake, which dropped the connection
+            // on the hello it doesn't know, is connected to again without one
+            None => {
+                let (old_reader, old_writer) = open(TcpStream::connect(addr).await?, codec).await?;
+                reader = old_reader;
+                writer = old_writer;
+                ServerInfo {
+                    version: MIN_PROTOCOL_VERSION,
+                    ..ServerInfo::default()
+                }
+            }
+        };
 
-        let reader = tokio_serde::SymmetricallyFramed::new(
-            FramedRead::new(read_half, LengthDelimitedCodec::new()),
-            SymmetricalJson::<Response>::default(),
-        );
-        let writer = tokio_serde::SymmetricallyFramed::new(
-            FramedWrite::new(write_half, LengthDelimitedCodec::new()),
-            SymmetricalJson::<Request>::default(),
-        );
+        let connection = if server.version >= PIPELINING_VERSION {
+            Connection::Pipelined(Pipeline::new(reader, writer, codec))
+        } else {
+            Connection::Serial(tokio::sync::Mutex::new((reader, writer)))
+        };
+        Ok(KvsClient {
+            connection,
+            addr,
+            codec,
+            server,
+        })
+    }
 
-        Ok(KvsClient { reader, writer })
+    /// What the server told in the handshake, only the protocol version is
+    /// known of servers from before it
+    pub fn server_info(&self) -> &ServerInfo {
+        &self.server
     }
 
-    /// Set the string value of a given string key.
-    pub async fn set(&mut self, key: String, value: String) -> Result<()> {
+    /// Set the value of a given key.
+    pub async fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
         let resp = self.send_and_receive(Request::Set { key, value }).await?;
         match resp {
             Response::Ok(_) => Ok(()),
-            Response::Err(e) => Err(KvsError::OtherError(e).into()),
+            resp => Err(response_error(resp)),
         }
     }
 
-    /// Get the string value of a given string key.
-    pub async fn get(&mut self, key: String) -> Result<Option<String>> {
+    /// Set the value of a given key, which expires after `ttl`.
+    pub async fn set_with_ttl_bytes(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> Result<()> {
+        let resp = self
+            .send_and_receive(Request::SetWithTtl { key, value, ttl })
+            .await?;
+        match resp {
+            Response::Ok(_) => Ok(()),
+            resp => Err(response_error(resp)),
+        }
+    }
+
+    /// Get the value of a given key.
+    pub async fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
         let resp = self.send_and_receive(Request::Get { key }).await?;
         match resp {
             Response::Ok(v) => Ok(v),
-            Response::Err(e) => Err(KvsError::OtherError(e).into()),
+            resp => Err(response_error(resp)),
         }
     }
 
-    /// Remove a given string key.
-    pub async fn remove(&mut self, key: String) -> Result<()> {
+    /// Remove a given key.
+    ///
+    /// # Errors
+    ///
+    /// Returns `KvsError::KeyNotFound` if the given key does not exist.
+    pub async fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
         let resp = self.send_and_receive(Request::Rm { key }).await?;
         match resp {
             Response::Ok(_) => Ok(()),
-            Response::Err(e) => Err(KvsError::OtherError(e).into()),
+            resp => Err(response_error(resp)),
+        }
+    }
+
+    /// Get how long a given key lives, `None` if it never expires.
+    ///
+    /// # Errors
+    ///
+    /// Returns `KvsError::KeyNotFound` if the given key does not exist.
+    pub async fn ttl_bytes(&self, key: Vec<u8>) -> Result<Option<Duration>> {
+        let resp = self.send_and_receive(Request::Ttl { key }).await?;
+        match resp {
+            Response::Ttl(ttl) => Ok(ttl),
+            resp => Err(response_error(resp)),
+        }
+    }
+
+    /// Clear the expiry of a given key.
+    ///
+    /// # Errors
+    ///
+    /// Returns `KvsError::KeyNotFound` if the given key does not exist.
+    pub async fn persist_bytes(&self, key: Vec<u8>) -> Result<()> {
+        let resp = self.send_and_receive(Request::Persist { key }).await?;
+        match resp {
+            Response::Ok(_) => Ok(()),
+            resp => Err(response_error(resp)),
+        }
+    }
+
+    /// Set a given key to `new` only if its value is `expected`, atomically.
+    ///
+    /// # Errors
+    ///
+    /// Returns `KvsError::CasMismatch` with the current value if it's not `expected`.
+    pub async fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<()> {
+        let resp = self
+            .send_and_receive(Request::Cas { key, expected, new })
+            .await?;
+        match resp {
+            Response::Ok(_) => Ok(()),
+            Response::CasMismatch(current) => Err(KvsError::CasMismatch { current }.into()),
+            resp => Err(response_error(resp)),
+        }
+    }
+
+    /// Apply all writes of a `WriteBatch` on the server, or none of them.
+    pub async fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        let resp = self.send_and_receive(Request::Batch { batch }).await?;
+        match resp {
+            Response::Ok(_) => Ok(()),
+            resp => Err(response_error(resp)),
         }
     }
 
-    /// send a request and receive a response
-    pub async fn send_and_receive(&mut self, req: Request) -> Result<Response> {
-        self.writer.send(req).await?;
-        match self.reader.try_next().await? {
-            Some(resp) => Ok(resp),
-            _ => Err(KvsError::OtherError("can't receive".to_string()).into()),
+    /// Start a transaction on the server, on a new connection which belongs
+    /// to the returned `RemoteTransaction`, so calls of this client and other
+    /// transactions aren't part of it.
+    pub async fn transaction(&self) -> Result<RemoteTransaction> {
+        let client = KvsClient::connect_with_codec(self.addr, self.codec).await?;
+        let resp = client.send_and_receive(Request::Begin).await?;
+        expect_ok(resp)?;
+        Ok(RemoteTransaction { client })
+    }
+
+    /// Get key/value pairs whose keys are in `range`, in the order of keys.
+    pub async fn scan_bytes(
+        &self,
+        range: impl RangeBounds<Vec<u8>>,
+        limit: Option<usize>,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let req = Request::Scan {
+            start: range.start_bound().cloned(),
+            end: range.end_bound().cloned(),
+            limit,
+        };
+        match self.send_and_receive(req).await? {
+            Response::Pairs(pairs) => Ok(pairs),
+            resp => Err(response_error(resp)),
+        }
+    }
+
+    /// Get key/value pairs whose keys start with `prefix`, in the order of keys.
+    pub async fn scan_prefix_bytes(
+        &self,
+        prefix: Vec<u8>,
+        limit: Option<usize>,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        match self
+            .send_and_receive(Request::ScanPrefix { prefix, limit })
+            .await?
+        {
+            Response::Pairs(pairs) => Ok(pairs),
+            resp => Err(response_error(resp)),
+        }
+    }
+
+    /// Make the server write a copy of the store to `dest`, a relative path
+    /// within the server's backup directory which must not exist or be empty.
+    ///
+    /// It fails with `KvsError::Unauthorized` if the server has no backup directory.
+    pub async fn checkpoint(&self, dest: impl Into<PathBuf>) -> Result<()> {
+        let dest = dest.into();
+        match self.send_and_receive(Request::Checkpoint { dest }).await? {
+            Response::Ok(_) => Ok(()),
+            resp => Err(response_error(resp)),
+        }
+    }
+
+    /// Set the string value of a given string key.
+    pub async fn set(&self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes()).await
+    }
+
+    /// Set the string value of a given string key, which expires after `ttl`.
+    pub async fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.set_with_ttl_bytes(key.into_bytes(), value.into_bytes(), ttl)
+            .await
+    }
+
+    /// Get the string value of a given string key.
+    pub async fn get(&self, key: String) -> Result<Option<String>> {
+        match self.get_bytes(key.into_bytes()).await? {
+            Some(value) => Ok(Some(into_string(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Remove a given string key.
+    pub async fn remove(&self, key: String) -> Result<()> {
+        self.remove_bytes(key.into_bytes()).await
+    }
+
+    /// Get how long a given string key lives, `None` if it never expires.
+    pub async fn ttl(&self, key: String) -> Result<Option<Duration>> {
+        self.ttl_bytes(key.into_bytes()).await
+    }
+
+    /// Clear the expiry of a given string key.
+    pub async fn persist(&self, key: String) -> Result<()> {
+        self.persist_bytes(key.into_bytes()).await
+    }
+
+    /// Set a given string key to `new` only if its value is `expected`, atomically.
+    pub async fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<()> {
+        self.compare_and_swap_bytes(
+            key.into_bytes(),
+            expected.map(String::into_bytes),
+            new.map(String::into_bytes),
+        )
+        .await
+    }
+
+    /// Get string key/value pairs whose keys are in `range`, in the order of keys.
+    pub async fn scan(
+        &self,
+        range: impl RangeBounds<String>,
+        limit: Option<usize>,
+    ) -> Result<Vec<(String, String)>> {
+        let range = (
+            bytes_bound(range.start_bound()),
+            bytes_bound(range.end_bound()),
+        );
+        into_string_pairs(self.scan_bytes(range, limit).await?)
+    }
+
+    /// Get string key/value pairs whose keys start with `prefix`, in the order of keys.
+    pub async fn scan_prefix(
+        &self,
+        prefix: String,
+        limit: Option<usize>,
+    ) -> Result<Vec<(String, String)>> {
+        into_string_pairs(self.scan_prefix_bytes(prefix.into_bytes(), limit).await?)
+    }
+
+    /// send a request and receive a response, other calls may send theirs
+    /// meanwhile if the connection is pipelined
+    pub async fn send_and_receive(&self, req: Request) -> Result<Response> {
+        match &self.connection {
+            Connection::Serial(connection) => {
+                let mut connection = connection.lock().await;
+                let (reader, writer) = &mut *connection;
+                writer.send(req).await?;
+                reader.try_next().await?.ok_or_else(cant_receive)
+            }
+            Connection::Pipelined(pipeline) => pipeline.send_and_receive(req).await,
         }
     }
 
     /// close a client()
     pub fn close(self) {}
 }
+
+/// A transaction on the server, begun by `KvsClient::transaction`.
+///
+/// It owns the connection it runs on, which is closed once it's committed,
+/// aborted or dropped. Dropping it discards the transaction.
+pub struct RemoteTransaction {
+    client: KvsClient,
+}
+
+impl RemoteTransaction {
+    /// Get the value of a given key in the transaction.
+    pub async fn get(&mut self, key: impl Into<Vec<u8>>) -> Result<Option<Vec<u8>>> {
+        let resp = self
+            .client
+            .send_and_receive(Request::TxnGet { key: key.into() })
+            .await?;
+        expect_ok(resp)
+    }
+
+    /// Set the value of a given key when the transaction commits.
+    pub async fn set(&mut self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> Result<()> {
+        let req = Request::TxnSet {
+            key: key.into(),
+            value: value.into(),
+        };
+        expect_ok(self.client.send_and_receive(req).await?).map(|_| ())
+    }
+
+    /// Remove a given key when the transaction commits.
+    pub async fn remove(&mut self, key: impl Into<Vec<u8>>) -> Result<()> {
+        let resp = self
+            .client
+            .send_and_receive(Request::TxnRm { key: key.into() })
+            .await?;
+        expect_ok(resp).map(|_| ())
+    }
+
+    /// Commit the transaction.
+    ///
+    /// # Errors
+    ///
+    /// Returns `KvsError::Conflict` if a key read has changed, nothing is written then.
+    pub async fn commit(self) -> Result<()> {
+        match self.client.send_and_receive(Request::Commit).await? {
+            Response::Conflict => Err(KvsError::Conflict.into()),
+            resp => expect_ok(resp).map(|_| ()),
+        }
+    }
+
+    /// Discard the transaction.
+    pub async fn abort(self) -> Result<()> {
+        let resp = self.client.send_and_receive(Request::Abort).await?;
+        expect_ok(resp).map(|_| ())
+    }
+}
+
+// the frames of a connection, once the server has accepted `codec`
+async fn open(
+    mut stream: TcpStream,
+    codec: Codec,
+) -> Result<(
+    FramedReader<OwnedReadHalf, Response>,
+    FramedWriter<OwnedWriteHalf, Request>,
+)> {
+    request_codec(&mut stream, codec).await?;
+    let (read_half, write_half) = stream.into_split();
+    Ok((
+        framed_reader(read_half, codec),
+        framed_writer(write_half, codec),
+    ))
+}
+
+// tell the server which protocol versions and features are wanted, before
+// the frames may be tagged, `None` if it drops the connection instead
+async fn hello(
+    reader: &mut FramedReader<OwnedReadHalf, Response>,
+    writer: &mut FramedWriter<OwnedWriteHalf, Request>,
+) -> Result<Option<ServerInfo>> {
+    let req = Request::Hello {
+        version: PROTOCOL_VERSION,
+        features: FEATURES.iter().map(|&feature| feature.to_owned()).collect(),
+    };
+    writer.send(req).await?;
+    let resp = match reader.try_next().await {
+        Ok(Some(resp)) => resp,
+        Ok(None) => return Ok(None),
+        Err(err) if is_dropped(&err) => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    match resp {
+        Response::Hello(server) => Ok(Some(server)),
+        Response::Err(e) => Err(KvsError::ProtocolMismatch(e).into()),
+        resp => Err(response_error(resp)),
+    }
+}
+
+// hand each response to the call waiting for it, until the connection is gone
+// or a frame can't be read
+async fn receive(mut reader: FramedReader<OwnedReadHalf, Tagged<Response>>, waiters: Waiters) {
+    while let Ok(Some(Tagged { id, body })) = reader.try_next().await {
+        // the server couldn't read even the id of a request, there's no
+        // call to hand it to
+        if id == 0 {
+            continue;
+        }
+        let waiter = waiters
+            .lock()
+            .unwrap()
+            .as_mut()
+            .and_then(|waiters| waiters.remove(&id));
+        if let Some(waiter) = waiter {
+            // the call may have been dropped
+            let _ = waiter.send(body);
+        }
+    }
+    // calls still waiting fail as their senders are dropped
+    waiters.lock().unwrap().take();
+}
+
+fn is_dropped(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::UnexpectedEof
+    )
+}
+
+fn cant_receive() -> anyhow::Error {
+    KvsError::OtherError("can't receive".to_string()).into()
+}
+
+fn expect_ok(resp: Response) -> Result<Option<Vec<u8>>> {
+    match resp {
+        Response::Ok(value) => Ok(value),
+        resp => Err(response_error(resp)),
+    }
+}
+
+// the error of a response which isn't the one expected
+fn response_error(resp: Response) -> anyhow::Error {
+    match resp {
+        Response::Error { code, message } => code.into_error(message).into(),
+        // sent by servers before protocol version 2
+        Response::Err(e) => KvsError::OtherError(e).into(),
+        _ => KvsError::OtherError("unexpected response".to_string()).into(),
+    }
+}
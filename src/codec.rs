@@ -0,0 +1,224 @@
+use crate::{KvsError, Result};
+use bytes::{Bytes, BytesMut};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::fmt;
+use std::io;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::str::FromStr;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_serde::SymmetricallyFramed;
+use tokio_util::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
+
+/// First byte a client sends to pick a codec, followed by the codec's id.
+///
+/// A connection without it speaks JSON, its first byte is the top byte of a
+/// frame length, which is always 0 as frames are at most 8MB.
+const HANDSHAKE: u8 = 0xff;
+/// Sent back instead of the codec's id if the server doesn't accept it
+const REJECTED: u8 = 0;
+
+/// How requests and responses are encoded on a connection.
+///
+/// A client picks one when it connects, JSON needs no handshake so clients
+/// and servers which only speak JSON keep working.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Codec {
+    /// JSON, readable and the default
+    #[default]
+    Json,
+    /// bincode, compact and fast, keys and values aren't inflated
+    Bincode,
+}
+
+impl Codec {
+    fn id(self) -> u8 {
+        match self {
+            Codec::Json => 1,
+            Codec::Bincode => 2,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Codec> {
+        match id {
+            1 => Some(Codec::Json),
+            2 => Some(Codec::Bincode),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Codec::Json => write!(f, "json"),
+            Codec::Bincode => write!(f, "bincode"),
+        }
+    }
+}
+
+impl FromStr for Codec {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> std::result::Result<Codec, KvsError> {
+        match s {
+            "json" => Ok(Codec::Json),
+            "bincode" => Ok(Codec::Bincode),
+            _ => Err(KvsError::OtherError(format!(
+                "invalid codec {}, expected json or bincode",
+                s
+            ))),
+        }
+    }
+}
+
+/// Encodes and decodes frames of `T` with the codec picked at runtime
+pub(crate) struct Format<T> {
+    codec: Codec,
+    item: PhantomData<fn() -> T>,
+}
+
+impl<T> Format<T> {
+    fn new(codec: Codec) -> Format<T> {
+        Format {
+            codec,
+            item: PhantomData,
+        }
+    }
+}
+
+impl<T: Serialize> tokio_serde::Serializer<T> for Format<T> {
+    type Error = io::Error;
+
+    fn serialize(self: Pin<&mut Self>, item: &T) -> io::Result<Bytes> {
+        let buf = match self.codec {
+            Codec::Json => serde_json::to_vec(item).map_err(invalid_data)?,
+            Codec::Bincode => bincode::serialize(item).map_err(invalid_data)?,
+        };
+        Ok(Bytes::from(buf))
+    }
+}
+
+impl<T: DeserializeOwned> tokio_serde::Deserializer<T> for Format<T> {
+    type Error = io::Error;
+
+    fn deserialize(self: Pin<&mut Self>, src: &BytesMut) -> io::Result<T> {
+        match self.codec {
+            Codec::Json => serde_json::from_slice(src).map_err(|err| decode_error(err, src)),
+            Codec::Bincode => bincode::deserialize(src).map_err(|err| decode_error(err, src)),
+        }
+    }
+}
+
+/// A frame holding no valid value, the frames after it can still be read
+#[derive(Debug, Error)]
+#[error("{message}")]
+struct DecodeError {
+    message: String,
+    // kept to read what's valid of it, such as the id of a tagged frame
+    frame: Bytes,
+}
+
+fn decode_error(err: impl fmt::Display, frame: &BytesMut) -> io::Error {
+    invalid_data(DecodeError {
+        message: err.to_string(),
+        frame: Bytes::copy_from_slice(frame),
+    })
+}
+
+/// Whether reading a frame failed only as it holds no valid value
+pub(crate) fn is_decode_error(err: &io::Error) -> bool {
+    err.get_ref().is_some_and(|err| err.is::<DecodeError>())
+}
+
+/// The id of a tagged frame whose body isn't valid, `None` if the id can't
+/// be read either
+pub(crate) fn undecodable_id(err: &io::Error, codec: Codec) -> Option<u64> {
+    // the id of a `Tagged`, which comes before its body
+    #[derive(Deserialize)]
+    struct Tag {
+        id: u64,
+    }
+    let frame = &err.get_ref()?.downcast_ref::<DecodeError>()?.frame;
+    let tag: Tag = match codec {
+        Codec::Json => serde_json::from_slice(frame).ok()?,
+        Codec::Bincode => bincode::deserialize(frame).ok()?,
+    };
+    Some(tag.id)
+}
+
+fn invalid_data(err: impl std::error::Error + Send + Sync + 'static) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+/// Values of `T` read from length delimited frames
+pub(crate) type FramedReader<R, T> =
+    SymmetricallyFramed<FramedRead<R, LengthDelimitedCodec>, T, Format<T>>;
+/// Values of `T` written as length delimited frames
+pub(crate) type FramedWriter<W, T> =
+    SymmetricallyFramed<FramedWrite<W, LengthDelimitedCodec>, T, Format<T>>;
+
+pub(crate) fn framed_reader<R: AsyncRead, T>(read: R, codec: Codec) -> FramedReader<R, T> {
+    SymmetricallyFramed::new(
+        FramedRead::new(read, LengthDelimitedCodec::new()),
+        Format::new(codec),
+    )
+}
+
+pub(crate) fn framed_writer<W: AsyncWrite, T>(write: W, codec: Codec) -> FramedWriter<W, T> {
+    SymmetricallyFramed::new(
+        FramedWrite::new(write, LengthDelimitedCodec::new()),
+        Format::new(codec),
+    )
+}
+
+/// The same frames holding values of another type from now on, nothing
+/// buffered is lost
+pub(crate) fn reframe<S, T, U>(
+    framed: SymmetricallyFramed<S, T, Format<T>>,
+    codec: Codec,
+) -> SymmetricallyFramed<S, U, Format<U>> {
+    SymmetricallyFramed::new(framed.into_inner(), Format::new(codec))
+}
+
+/// Ask the server for `codec`, JSON is spoken without asking
+pub(crate) async fn request_codec(stream: &mut TcpStream, codec: Codec) -> Result<()> {
+    if codec == Codec::Json {
+        return Ok(());
+    }
+    stream.write_all(&[HANDSHAKE, codec.id()]).await?;
+    let mut reply = [0; 1];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != codec.id() {
+        return Err(
+            KvsError::OtherError(format!("the server doesn't accept the {} codec", codec)).into(),
+        );
+    }
+    Ok(())
+}
+
+/// The codec a client asks for, or JSON if it doesn't ask, as long as it's
+/// one of `accepted`
+pub(crate) async fn accept_codec(stream: &mut TcpStream, accepted: &[Codec]) -> Result<Codec> {
+    let mut first = [0; 1];
+    if stream.peek(&mut first).await? == 0 || first[0] != HANDSHAKE {
+        if !accepted.contains(&Codec::Json) {
+            return Err(KvsError::OtherError("the json codec isn't accepted".to_owned()).into());
+        }
+        return Ok(Codec::Json);
+    }
+    let mut hello = [0; 2];
+    stream.read_exact(&mut hello).await?;
+    match Codec::from_id(hello[1]) {
+        Some(codec) if accepted.contains(&codec) => {
+            stream.write_all(&[codec.id()]).await?;
+            Ok(codec)
+        }
+        _ => {
+            stream.write_all(&[REJECTED]).await?;
+            Err(KvsError::OtherError(format!("codec {} asked for isn't accepted", hello[1])).into())
+        }
+    }
+}
@@ -0,0 +1,217 @@
+//! Checks any `KvsEngine` behaves like the built-in engines.
+//!
+//! Every check panics on a misbehaviour, like `assert!` does, and returns an
+//! error if the engine fails an operation. Checks which reopen the engine
+//! take a function opening it on a directory, and a directory of their own,
+//! which must be empty.
+//!
+//! Example:
+//!
+//! ```rust
+//! # use anyhow::{Result, Context};
+//! use kvs::{conformance, MemoryKvsEngine};
+//! use tempfile::TempDir;
+//! # fn main() -> Result<()> {
+//! let rt = tokio::runtime::Runtime::new().context("failed to create tokio runtime")?;
+//! rt.block_on(async move {
+//!     let temp_dir = TempDir::new()?;
+//!     conformance::run_all(|dir| MemoryKvsEngine::open(dir), temp_dir.path()).await
+//! })
+//! # }
+//! ```
+use crate::{KvsEngine, KvsError, Result, WriteBatch};
+use std::path::Path;
+use std::time::Duration;
+
+/// Tasks run at once by `concurrent_access`
+const TASKS: usize = 8;
+/// Keys written by each task of `concurrent_access`
+const KEYS_PER_TASK: usize = 100;
+/// Keys overwritten by each round of `large_volume`
+const VOLUME_KEYS: usize = 1000;
+/// Rounds of `large_volume`, enough stale data to trigger a `KvStore` compaction
+const VOLUME_ROUNDS: usize = 50;
+/// TTL of the keys `expiry_sweep` lets expire
+const SHORT_TTL: Duration = Duration::from_millis(50);
+
+/// Run every check, each in a subdirectory of `dir`
+pub async fn run_all<E, F>(open: F, dir: &Path) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    get_set_remove(&open(&dir.join("get_set_remove"))?).await?;
+    concurrent_access(&open(&dir.join("concurrent_access"))?).await?;
+    persistence(&open, &dir.join("persistence")).await?;
+    large_volume(&open, &dir.join("large_volume")).await?;
+    expiry_sweep(&open, &dir.join("expiry_sweep")).await?;
+    Ok(())
+}
+
+/// Values are read back as set, overwritten and removed, and removing a
+/// missing key fails with `KvsError::KeyNotFound`
+pub async fn get_set_remove<E: KvsEngine>(engine: &E) -> Result<()> {
+    assert_eq!(engine.get("key1".to_owned()).await?, None);
+    engine.set("key1".to_owned(), "value1".to_owned()).await?;
+    assert_eq!(
+        engine.get("key1".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+    engine.set("key1".to_owned(), "value2".to_owned()).await?;
+    assert_eq!(
+        engine.get("key1".to_owned()).await?,
+        Some("value2".to_owned())
+    );
+
+    engine.set_bytes(vec![0xff, 0], Vec::new()).await?;
+    assert_eq!(engine.get_bytes(vec![0xff, 0]).await?, Some(Vec::new()));
+
+    engine.remove("key1".to_owned()).await?;
+    assert_eq!(engine.get("key1".to_owned()).await?, None);
+    for key in ["key1", "key2"] {
+        let err = engine.remove(key.to_owned()).await.unwrap_err();
+        assert!(
+            matches!(err.downcast_ref::<KvsError>(), Some(KvsError::KeyNotFound)),
+            "removing missing {} failed with {}",
+            key,
+            err
+        );
+    }
+    Ok(())
+}
+
+/// Tasks writing and reading their own keys at once see their own writes,
+/// and all of them are kept
+pub async fn concurrent_access<E: KvsEngine>(engine: &E) -> Result<()> {
+    let mut tasks = Vec::new();
+    for task in 0..TASKS {
+        let engine = engine.clone();
+        tasks.push(tokio::spawn(async move {
+            for key_id in 0..KEYS_PER_TASK {
+                let key = format!("task{}_key{}", task, key_id);
+                engine.set(key.clone(), format!("value{}", key_id)).await?;
+                assert_eq!(engine.get(key).await?, Some(format!("value{}", key_id)));
+            }
+            Ok::<_, anyhow::Error>(())
+        }));
+    }
+    for task in tasks {
+        task.await??;
+    }
+    for task in 0..TASKS {
+        let pairs = engine.scan_prefix(format!("task{}_", task), None).await?;
+        assert_eq!(pairs.len(), KEYS_PER_TASK);
+    }
+    Ok(())
+}
+
+/// Writes outlive the engine, removes too
+pub async fn persistence<E, F>(open: F, dir: &Path) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let engine = open(dir)?;
+    engine.set("key1".to_owned(), "value1".to_owned()).await?;
+    engine.set("key2".to_owned(), "value2".to_owned()).await?;
+    drop(engine);
+
+    let engine = open(dir)?;
+    assert_eq!(
+        engine.get("key1".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+    engine.remove("key1".to_owned()).await?;
+    drop(engine);
+
+    let engine = open(dir)?;
+    assert_eq!(engine.get("key1".to_owned()).await?, None);
+    assert_eq!(
+        engine.get("key2".to_owned()).await?,
+        Some("value2".to_owned())
+    );
+    Ok(())
+}
+
+/// Keys overwritten many times keep their last value, before and after
+/// reopening, however the engine reclaims the overwritten data
+pub async fn large_volume<E, F>(open: F, dir: &Path) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let engine = open(dir)?;
+    // batches, so an engine syncing every write stays fast
+    for round in 0..VOLUME_ROUNDS {
+        let mut batch = WriteBatch::new();
+        for key_id in 0..VOLUME_KEYS {
+            batch.set(format!("key{}", key_id), format!("{:064}", round));
+        }
+        engine.write_batch(batch).await?;
+    }
+    let last = format!("{:064}", VOLUME_ROUNDS - 1);
+    for key_id in 0..VOLUME_KEYS {
+        assert_eq!(
+            engine.get(format!("key{}", key_id)).await?,
+            Some(last.clone())
+        );
+    }
+    drop(engine);
+
+    let engine = open(dir)?;
+    let pairs = engine.scan(.., None).await?;
+    assert_eq!(pairs.len(), VOLUME_KEYS);
+    assert!(pairs.iter().all(|(_, value)| *value == last));
+    Ok(())
+}
+
+/// `remove_expired` removes the keys which have expired and counts them,
+/// but neither keys which have lost or changed their TTL since nor keys
+/// which have not expired yet, before and after reopening
+pub async fn expiry_sweep<E, F>(open: F, dir: &Path) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let long_ttl = Duration::from_secs(3600);
+    let engine = open(dir)?;
+    for key in ["expired", "overwritten", "persisted", "extended", "removed"] {
+        engine
+            .set_with_ttl(key.to_owned(), "value".to_owned(), SHORT_TTL)
+            .await?;
+    }
+    engine
+        .set_with_ttl("reopened".to_owned(), "value".to_owned(), long_ttl)
+        .await?;
+    engine
+        .set("overwritten".to_owned(), "value".to_owned())
+        .await?;
+    engine.persist("persisted".to_owned()).await?;
+    engine
+        .set_with_ttl("extended".to_owned(), "value".to_owned(), long_ttl)
+        .await?;
+    engine.remove("removed".to_owned()).await?;
+    tokio::time::sleep(SHORT_TTL * 2).await;
+    assert_eq!(engine.remove_expired().await?, 1);
+    assert_eq!(engine.remove_expired().await?, 0);
+    let keys: Vec<String> = engine
+        .scan(.., None)
+        .await?
+        .into_iter()
+        .map(|(key, _)| key)
+        .collect();
+    assert_eq!(keys, ["extended", "overwritten", "persisted", "reopened"]);
+
+    // it expires once reopened, an engine may drop expired keys on open
+    engine
+        .set_with_ttl("reopened".to_owned(), "value".to_owned(), SHORT_TTL * 4)
+        .await?;
+    drop(engine);
+    // the engine may release its files in the background
+    tokio::time::sleep(SHORT_TTL).await;
+    let engine = open(dir)?;
+    tokio::time::sleep(SHORT_TTL * 4).await;
+    assert_eq!(engine.remove_expired().await?, 1);
+    assert_eq!(engine.scan(.., None).await?.len(), 3);
+    Ok(())
+}
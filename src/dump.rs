@@ -0,0 +1,474 @@
+//! Portable dumps of every key/value pair of a store.
+//!
+//! A JSON dump holds one object per line, keys and values are strings if
+//! they are UTF-8 and arrays of bytes otherwise. Keys which expire have
+//! when they do in milliseconds since the unix epoch:
+//!
+//! ```text
+//! {"key":"key1","value":"value1"}
+//! {"key":[255,0],"value":"value2","expires_at":1700000000000}
+//! ```
+//!
+//! A binary dump is a header followed by the pairs and a trailer holding
+//! how many pairs there are, so a truncated dump is detected:
+//!
+//! ```text
+//! | magic (2) | version (1) | pair | pair | ... | 0xffffffff (4) | count (8) |
+//! pair: | key_len (4) | value_len (4) | expires_at (8) | key | value |
+//! ```
+//!
+//! `expires_at` is 0 for keys which never expire. Version 1 dumps have no
+//! `expires_at`, they are still read.
+//!
+//! Keys which have expired by the time a dump is imported are skipped.
+use crate::engine::now_millis;
+use crate::{KvsClient, KvsEngine, KvsError, Result, WriteBatch};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::convert::TryInto;
+use std::io::{self, BufRead, Read, Write};
+use std::ops::Bound;
+use std::str::FromStr;
+use std::time::Duration;
+
+const MAGIC: [u8; 2] = *b"KD";
+const VERSION: u8 = 2;
+// the version without expiries
+const VERSION_1: u8 = 1;
+// key length marking the trailer of a binary dump
+const END: u32 = u32::MAX;
+/// Pairs read by a scan of `export` at once
+const PAGE_SIZE: usize = 1000;
+/// Pairs written by a batch of `import` at once
+const BATCH_SIZE: usize = 1000;
+
+/// Format of a dump
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DumpFormat {
+    /// JSON Lines, readable and diffable
+    Json,
+    /// length prefixed binary pairs, compact
+    Binary,
+}
+
+impl FromStr for DumpFormat {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> std::result::Result<DumpFormat, KvsError> {
+        match s {
+            "json" => Ok(DumpFormat::Json),
+            "binary" => Ok(DumpFormat::Binary),
+            _ => Err(KvsError::OtherError(format!(
+                "invalid dump format {}, expected json or binary",
+                s
+            ))),
+        }
+    }
+}
+
+// bytes of a JSON dump, a string if they are UTF-8
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum JsonBytes {
+    Text(String),
+    Raw(Vec<u8>),
+}
+
+impl From<&[u8]> for JsonBytes {
+    fn from(bytes: &[u8]) -> JsonBytes {
+        match std::str::from_utf8(bytes) {
+            Ok(text) => JsonBytes::Text(text.to_owned()),
+            Err(_) => JsonBytes::Raw(bytes.to_vec()),
+        }
+    }
+}
+
+impl From<JsonBytes> for Vec<u8> {
+    fn from(bytes: JsonBytes) -> Vec<u8> {
+        match bytes {
+            JsonBytes::Text(text) => text.into_bytes(),
+            JsonBytes::Raw(raw) => raw,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct JsonPair {
+    key: JsonBytes,
+    value: JsonBytes,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at: Option<u64>,
+}
+
+/// A key/value pair of a dump
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DumpEntry {
+    /// The key
+    pub key: Vec<u8>,
+    /// The value
+    pub value: Vec<u8>,
+    /// When the key expires in milliseconds since the unix epoch, `None` if it never does
+    pub expires_at: Option<u64>,
+}
+
+/// A store dumps are exported from and imported to, which is any
+/// `KvsEngine` or a server reached by a `KvsClient`.
+#[async_trait]
+pub trait DumpStore: Sync {
+    /// Get up to `limit` pairs whose keys come after `from`, in the order of keys
+    async fn dump_page(
+        &self,
+        from: Bound<Vec<u8>>,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>>;
+
+    /// Get how long a key lives, `None` if it never expires.
+    ///
+    /// # Errors
+    ///
+    /// Returns `KvsError::KeyNotFound` if the key is gone.
+    async fn dump_ttl(&self, key: Vec<u8>) -> Result<Option<Duration>>;
+
+    /// Set keys which never expire, all at once
+    async fn load_batch(&self, batch: WriteBatch) -> Result<()>;
+
+    /// Set a key which expires after `ttl`
+    async fn load_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()>;
+}
+
+#[async_trait]
+impl<E: KvsEngine> DumpStore for E {
+    async fn dump_page(
+        &self,
+        from: Bound<Vec<u8>>,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.scan_bytes((from, Bound::Unbounded), Some(limit)).await
+    }
+
+    async fn dump_ttl(&self, key: Vec<u8>) -> Result<Option<Duration>> {
+        self.ttl_bytes(key).await
+    }
+
+    async fn load_batch(&self, batch: WriteBatch) -> Result<()> {
+        self.write_batch(batch).await
+    }
+
+    async fn load_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        self.set_with_ttl_bytes(key, value, ttl).await
+    }
+}
+
+#[async_trait]
+impl DumpStore for KvsClient {
+    async fn dump_page(
+        &self,
+        from: Bound<Vec<u8>>,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.scan_bytes((from, Bound::Unbounded), Some(limit)).await
+    }
+
+    async fn dump_ttl(&self, key: Vec<u8>) -> Result<Option<Duration>> {
+        self.ttl_bytes(key).await
+    }
+
+    async fn load_batch(&self, batch: WriteBatch) -> Result<()> {
+        self.write_batch(batch).await
+    }
+
+    async fn load_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        self.set_with_ttl_bytes(key, value, ttl).await
+    }
+}
+
+/// Writes key/value pairs as a dump.
+///
+/// Example:
+///
+/// ```rust
+/// # use anyhow::Result;
+/// use kvs::{DumpEntry, DumpFormat, DumpReader, DumpWriter};
+/// # fn main() -> Result<()> {
+/// let entry = DumpEntry {
+///     key: b"key".to_vec(),
+///     value: b"value".to_vec(),
+///     expires_at: None,
+/// };
+/// let mut writer = DumpWriter::new(Vec::new(), DumpFormat::Binary)?;
+/// writer.write(&entry)?;
+/// let dump = writer.finish()?;
+/// let entries = DumpReader::new(dump.as_slice())?.collect::<Result<Vec<_>>>()?;
+/// assert_eq!(entries, vec![entry]);
+/// # Ok(())
+/// # }
+/// ```
+pub struct DumpWriter<W: Write> {
+    writer: W,
+    format: DumpFormat,
+    count: u64,
+}
+
+impl<W: Write> DumpWriter<W> {
+    /// Start a dump in `format`
+    pub fn new(mut writer: W, format: DumpFormat) -> Result<DumpWriter<W>> {
+        if format == DumpFormat::Binary {
+            writer.write_all(&MAGIC)?;
+            writer.write_all(&[VERSION])?;
+        }
+        Ok(DumpWriter {
+            writer,
+            format,
+            count: 0,
+        })
+    }
+
+    /// Write a key/value pair
+    pub fn write(&mut self, entry: &DumpEntry) -> Result<()> {
+        match self.format {
+            DumpFormat::Json => {
+                let pair = JsonPair {
+                    key: entry.key.as_slice().into(),
+                    value: entry.value.as_slice().into(),
+                    expires_at: entry.expires_at,
+                };
+                serde_json::to_writer(&mut self.writer, &pair).map_err(KvsError::Serde)?;
+                self.writer.write_all(b"\n")?;
+            }
+            DumpFormat::Binary => {
+                let expires_at = entry.expires_at.unwrap_or(0);
+                self.writer
+                    .write_all(&(entry.key.len() as u32).to_le_bytes())?;
+                self.writer
+                    .write_all(&(entry.value.len() as u32).to_le_bytes())?;
+                self.writer.write_all(&expires_at.to_le_bytes())?;
+                self.writer.write_all(&entry.key)?;
+                self.writer.write_all(&entry.value)?;
+            }
+        }
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Number of pairs written so far
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// End the dump and flush it, return the underlying writer
+    pub fn finish(mut self) -> Result<W> {
+        if self.format == DumpFormat::Binary {
+            self.writer.write_all(&END.to_le_bytes())?;
+            self.writer.write_all(&self.count.to_le_bytes())?;
+        }
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+/// Reads the key/value pairs of a dump, whichever format it's in
+pub struct DumpReader<R: BufRead> {
+    reader: R,
+    format: DumpFormat,
+    // version of a binary dump
+    version: u8,
+    count: u64,
+    done: bool,
+}
+
+impl<R: BufRead> DumpReader<R> {
+    /// Start reading a dump, its format is told by its first bytes
+    pub fn new(mut reader: R) -> Result<DumpReader<R>> {
+        let (format, version) = if reader.fill_buf()?.first() == Some(&MAGIC[0]) {
+            let mut header = [0_u8; 3];
+            reader.read_exact(&mut header)?;
+            if header[0..2] != MAGIC || !matches!(header[2], VERSION_1 | VERSION) {
+                return Err(KvsError::BrokenDump(0).into());
+            }
+            (DumpFormat::Binary, header[2])
+        } else {
+            (DumpFormat::Json, VERSION)
+        };
+        Ok(DumpReader {
+            reader,
+            format,
+            version,
+            count: 0,
+            done: false,
+        })
+    }
+
+    /// Format of the dump
+    pub fn format(&self) -> DumpFormat {
+        self.format
+    }
+
+    fn read_json(&mut self) -> Result<Option<DumpEntry>> {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Ok(None);
+            }
+            if !line.trim().is_empty() {
+                break;
+            }
+        }
+        let pair: JsonPair =
+            serde_json::from_str(&line).map_err(|_| KvsError::BrokenDump(self.count))?;
+        Ok(Some(DumpEntry {
+            key: pair.key.into(),
+            value: pair.value.into(),
+            expires_at: pair.expires_at,
+        }))
+    }
+
+    fn read_binary(&mut self) -> Result<Option<DumpEntry>> {
+        let count = self.count;
+        // a dump ending before its trailer is truncated
+        let broken = |err: io::Error| -> anyhow::Error {
+            if err.kind() == io::ErrorKind::UnexpectedEof {
+                KvsError::BrokenDump(count).into()
+            } else {
+                err.into()
+            }
+        };
+        let mut lens = [0_u8; 4];
+        self.reader.read_exact(&mut lens).map_err(broken)?;
+        let key_len = u32::from_le_bytes(lens);
+        if key_len == END {
+            let mut total = [0_u8; 8];
+            self.reader.read_exact(&mut total).map_err(broken)?;
+            if u64::from_le_bytes(total) != count {
+                return Err(KvsError::BrokenDump(count).into());
+            }
+            return Ok(None);
+        }
+        self.reader.read_exact(&mut lens).map_err(broken)?;
+        let value_len = u32::from_le_bytes(lens);
+        let mut expires_at = None;
+        if self.version > VERSION_1 {
+            let mut millis = [0_u8; 8];
+            self.reader.read_exact(&mut millis).map_err(broken)?;
+            expires_at = Some(u64::from_le_bytes(millis)).filter(|&millis| millis != 0);
+        }
+
+        // read through `take` so a corrupted length can't make us allocate
+        // more than the dump actually holds
+        let len = key_len as u64 + value_len as u64;
+        let mut pair = Vec::new();
+        (&mut self.reader).take(len).read_to_end(&mut pair)?;
+        if (pair.len() as u64) < len {
+            return Err(KvsError::BrokenDump(count).into());
+        }
+        let value = pair.split_off(key_len.try_into().unwrap());
+        Ok(Some(DumpEntry {
+            key: pair,
+            value,
+            expires_at,
+        }))
+    }
+}
+
+impl<R: BufRead> Iterator for DumpReader<R> {
+    type Item = Result<DumpEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let pair = match self.format {
+            DumpFormat::Json => self.read_json(),
+            DumpFormat::Binary => self.read_binary(),
+        };
+        match pair {
+            Ok(Some(pair)) => {
+                self.count += 1;
+                Some(Ok(pair))
+            }
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(err) => {
+                self.done = true;
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+/// Write every key/value pair of `store` to `writer` with its expiry,
+/// return how many there are.
+///
+/// Pairs are scanned a page at a time, a write done meanwhile may or may not
+/// be dumped.
+pub async fn export<S, W>(store: &S, writer: &mut DumpWriter<W>) -> Result<u64>
+where
+    S: DumpStore + ?Sized,
+    W: Write,
+{
+    let start = writer.count();
+    let mut from = Bound::Unbounded;
+    loop {
+        let pairs = store.dump_page(from, PAGE_SIZE).await?;
+        // a short page is the last one
+        let full = pairs.len() == PAGE_SIZE;
+        for (key, value) in &pairs {
+            let ttl = match store.dump_ttl(key.clone()).await {
+                Ok(ttl) => ttl,
+                // removed or expired since the page was scanned
+                Err(err) if is_key_not_found(&err) => continue,
+                Err(err) => return Err(err),
+            };
+            writer.write(&DumpEntry {
+                key: key.clone(),
+                value: value.clone(),
+                expires_at: ttl.map(|ttl| now_millis().saturating_add(ttl.as_millis() as u64)),
+            })?;
+        }
+        match pairs.into_iter().last() {
+            Some((key, _)) if full => from = Bound::Excluded(key),
+            _ => break,
+        }
+    }
+    Ok(writer.count() - start)
+}
+
+/// Set every key/value pair of a dump in `store` with its expiry, return
+/// how many there are.
+///
+/// Pairs which never expire are written in batches, a dump broken halfway
+/// is partly imported. Pairs which have expired already are skipped but
+/// counted.
+pub async fn import<S, R>(store: &S, reader: DumpReader<R>) -> Result<u64>
+where
+    S: DumpStore + ?Sized,
+    R: BufRead,
+{
+    let mut count = 0;
+    let mut batch = WriteBatch::new();
+    for entry in reader {
+        let entry = entry?;
+        count += 1;
+        match entry.expires_at {
+            None => batch.set(entry.key, entry.value),
+            Some(expires_at) => {
+                let now = now_millis();
+                if expires_at > now {
+                    let ttl = Duration::from_millis(expires_at - now);
+                    store.load_with_ttl(entry.key, entry.value, ttl).await?;
+                }
+            }
+        }
+        if batch.len() >= BATCH_SIZE {
+            store.load_batch(std::mem::take(&mut batch)).await?;
+        }
+    }
+    store.load_batch(batch).await?;
+    Ok(count)
+}
+
+fn is_key_not_found(err: &anyhow::Error) -> bool {
+    matches!(err.downcast_ref::<KvsError>(), Some(KvsError::KeyNotFound))
+}
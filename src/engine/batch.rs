@@ -0,0 +1,69 @@
+use serde::{Deserialize, Serialize};
+
+/// A group of writes applied all or nothing by `KvsEngine::write_batch`.
+///
+/// Writes are applied in the order they are added, removing a key which
+/// does not exist is not an error.
+///
+/// Example:
+///
+/// ```rust
+/// use kvs::WriteBatch;
+/// let mut batch = WriteBatch::new();
+/// batch.set("key1", "value1");
+/// batch.set(b"key2".to_vec(), vec![0xff]);
+/// batch.remove("key3");
+/// assert_eq!(batch.len(), 3);
+/// ```
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) enum BatchOp {
+    Set {
+        #[serde(with = "crate::wire::bytes")]
+        key: Vec<u8>,
+        #[serde(with = "crate::wire::bytes")]
+        value: Vec<u8>,
+    },
+    Remove {
+        #[serde(with = "crate::wire::bytes")]
+        key: Vec<u8>,
+    },
+}
+
+impl WriteBatch {
+    /// An empty batch
+    pub fn new() -> WriteBatch {
+        WriteBatch::default()
+    }
+
+    /// Set the value of a given key
+    pub fn set(&mut self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) {
+        self.ops.push(BatchOp::Set {
+            key: key.into(),
+            value: value.into(),
+        });
+    }
+
+    /// Remove a given key
+    pub fn remove(&mut self, key: impl Into<Vec<u8>>) {
+        self.ops.push(BatchOp::Remove { key: key.into() });
+    }
+
+    /// Number of writes in the batch
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// Whether there is no write in the batch
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub(crate) fn into_ops(self) -> Vec<BatchOp> {
+        self.ops
+    }
+}
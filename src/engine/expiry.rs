@@ -0,0 +1,37 @@
+use std::collections::BTreeSet;
+use std::mem;
+
+/// Keys with a TTL ordered by when they expire, so a sweep only visits the
+/// keys which are due instead of every key.
+///
+/// It's kept in step with the index by its writer. An entry may still be
+/// stale once the key is dropped some other way, so the keys it yields have
+/// to be checked against the index.
+#[derive(Default)]
+pub(super) struct Expiries {
+    keys: BTreeSet<(u64, Vec<u8>)>,
+}
+
+impl Expiries {
+    /// Note that the expiry of `key` changes from `old` to `new`
+    pub(super) fn update(&mut self, key: &[u8], old: Option<u64>, new: Option<u64>) {
+        if old == new {
+            return;
+        }
+        if let Some(old) = old {
+            self.keys.remove(&(old, key.to_vec()));
+        }
+        if let Some(new) = new {
+            self.keys.insert((new, key.to_vec()));
+        }
+    }
+
+    /// Take the keys which expire at `now` or before
+    pub(super) fn take_due(&mut self, now: u64) -> Vec<Vec<u8>> {
+        let later = self.keys.split_off(&(now.saturating_add(1), Vec::new()));
+        mem::replace(&mut self.keys, later)
+            .into_iter()
+            .map(|(_, key)| key)
+            .collect()
+    }
+}
@@ -0,0 +1,247 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+#[cfg(feature = "fault-injection")]
+use std::sync::{Arc, Mutex};
+
+/// Makes the log files of a `KvStore` fail as if the process crashed, to
+/// test recovery.
+///
+/// Once crashed, the write crossing the limit has been written partly and
+/// every later write or sync of a log file fails, until it's recovered.
+/// Reads are never failed.
+///
+/// Only built with the `fault-injection` feature.
+///
+/// Example:
+///
+/// ```rust
+/// # use anyhow::{Result, Context};
+/// use kvs::{FaultInjector, KvStore, KvStoreOptions, KvsEngine};
+/// use tempfile::TempDir;
+/// # fn main() -> Result<()> {
+/// let rt = tokio::runtime::Runtime::new().context("failed to create tokio runtime")?;
+/// rt.block_on(async move {
+///     let temp_dir = TempDir::new()?;
+///     let faults = FaultInjector::new();
+///     let options = KvStoreOptions::new();
+///     let store = KvStore::open_with_faults(temp_dir.path(), options, faults.clone())?;
+///     faults.crash_after(10);
+///     assert!(store.set("key".to_owned(), "value".to_owned()).await.is_err());
+///     assert!(faults.crashed());
+///
+///     drop(store);
+///     faults.recover();
+///     let store = KvStore::open_with_faults(temp_dir.path(), options, faults)?;
+///     assert_eq!(store.get("key".to_owned()).await?, None);
+///     Ok(())
+/// })
+/// # }
+/// ```
+#[cfg(feature = "fault-injection")]
+#[derive(Debug, Clone, Default)]
+pub struct FaultInjector {
+    state: Arc<Mutex<FaultState>>,
+}
+
+#[cfg(feature = "fault-injection")]
+#[derive(Debug, Default)]
+struct FaultState {
+    // bytes which may still be written, unlimited if `None`
+    budget: Option<u64>,
+    // the write crossing the budget fails without crashing
+    transient: bool,
+    crashed: bool,
+    syncs: u64,
+}
+
+#[cfg(feature = "fault-injection")]
+impl FaultInjector {
+    /// A `FaultInjector` which doesn't fail anything yet
+    pub fn new() -> FaultInjector {
+        FaultInjector::default()
+    }
+
+    /// Crash once `bytes` more bytes have been written to log files
+    pub fn crash_after(&self, bytes: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.budget = Some(bytes);
+        state.transient = false;
+    }
+
+    /// Fail the write crossing `bytes` more bytes once, after writing part
+    /// of it like a full disk would, later writes succeed again
+    pub fn fail_after(&self, bytes: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.budget = Some(bytes);
+        state.transient = true;
+    }
+
+    /// Whether it has crashed
+    pub fn crashed(&self) -> bool {
+        self.state.lock().unwrap().crashed
+    }
+
+    /// How many times log files have been synced
+    pub fn syncs(&self) -> u64 {
+        self.state.lock().unwrap().syncs
+    }
+
+    /// Let log files be written again, without limit
+    pub fn recover(&self) {
+        *self.state.lock().unwrap() = FaultState::default();
+    }
+
+    // how much of `len` bytes may be written before crashing
+    fn admit(&self, len: usize) -> io::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        if state.crashed {
+            return Err(crash());
+        }
+        match state.budget {
+            Some(budget) if budget < len as u64 => {
+                if state.transient {
+                    state.budget = None;
+                    state.transient = false;
+                } else {
+                    state.budget = Some(0);
+                    state.crashed = true;
+                }
+                Ok(budget as usize)
+            }
+            Some(budget) => {
+                state.budget = Some(budget - len as u64);
+                Ok(len)
+            }
+            None => Ok(len),
+        }
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.crashed {
+            return Err(crash());
+        }
+        state.syncs += 1;
+        Ok(())
+    }
+}
+
+fn crash() -> io::Error {
+    io::Error::other("injected crash")
+}
+
+/// The `FaultInjector` log files go through if there is one, there never is
+/// without the `fault-injection` feature
+#[derive(Debug, Clone, Default)]
+pub(super) struct Faults {
+    #[cfg(feature = "fault-injection")]
+    injector: Option<FaultInjector>,
+}
+
+impl Faults {
+    #[cfg(feature = "fault-injection")]
+    pub(super) fn new(injector: FaultInjector) -> Faults {
+        Faults {
+            injector: Some(injector),
+        }
+    }
+
+    // how much of `len` bytes may be written before crashing
+    fn admit(&self, len: usize) -> io::Result<usize> {
+        #[cfg(feature = "fault-injection")]
+        if let Some(injector) = &self.injector {
+            return injector.admit(len);
+        }
+        Ok(len)
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        #[cfg(feature = "fault-injection")]
+        if let Some(injector) = &self.injector {
+            return injector.sync();
+        }
+        Ok(())
+    }
+}
+
+/// A log file, every write and sync goes through its `Faults`
+pub(super) struct LogFile {
+    file: File,
+    faults: Faults,
+}
+
+impl LogFile {
+    pub(super) fn open(path: &Path, faults: &Faults) -> io::Result<LogFile> {
+        LogFile::with_options(OpenOptions::new().read(true), path, faults)
+    }
+
+    pub(super) fn append(path: &Path, faults: &Faults) -> io::Result<LogFile> {
+        LogFile::with_options(OpenOptions::new().create(true).append(true), path, faults)
+    }
+
+    pub(super) fn create(path: &Path, faults: &Faults) -> io::Result<LogFile> {
+        let mut options = OpenOptions::new();
+        options.create(true).write(true).truncate(true);
+        LogFile::with_options(&options, path, faults)
+    }
+
+    fn with_options(options: &OpenOptions, path: &Path, faults: &Faults) -> io::Result<LogFile> {
+        Ok(LogFile {
+            file: options.open(path)?,
+            faults: faults.clone(),
+        })
+    }
+
+    /// Another handle to the same file, which can be synced on its own
+    pub(super) fn try_clone(&self) -> io::Result<LogFile> {
+        Ok(LogFile {
+            file: self.file.try_clone()?,
+            faults: self.faults.clone(),
+        })
+    }
+
+    pub(super) fn len(&self) -> io::Result<u64> {
+        Ok(self.file.metadata()?.len())
+    }
+
+    /// Cut the file to `len` bytes, which fails once crashed like a write
+    pub(super) fn set_len(&self, len: u64) -> io::Result<()> {
+        self.faults.admit(0)?;
+        self.file.set_len(len)
+    }
+
+    pub(super) fn sync_data(&self) -> io::Result<()> {
+        self.faults.sync()?;
+        self.file.sync_data()
+    }
+}
+
+impl Read for LogFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl Write for LogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.faults.admit(buf.len())?;
+        // a torn write, the rest is lost in the crash
+        if len < buf.len() {
+            self.file.write_all(&buf[..len])?;
+            return Err(crash());
+        }
+        self.file.write_all(buf)?;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Seek for LogFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.file.seek(pos)
+    }
+}
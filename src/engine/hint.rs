@@ -0,0 +1,132 @@
+//! Hint files for fast `KvStore` startup.
+//!
+//! Compaction writes a `N.hint` next to every compacted `N.log`, holding
+//! where each record of the log lives, so the keydir can be rebuilt without
+//! reading any value:
+//!
+//! ```text
+//! | magic (2) | version (1) | file_id (8) | entry | entry | ... | count (8) | crc32 (4) |
+//! entry: | crc32 (4) | offset (8) | len (8) | expires_at (8) | seq (8) | key_len (4) | key |
+//! ```
+//!
+//! The trailer holds the number of entries and a crc32 of everything before
+//! it, so a hint cut short between two entries isn't taken for a whole one.
+//! Hints are only a cache of the log, a missing or damaged hint file makes
+//! the log be replayed instead.
+use crc32fast::Hasher;
+use std::convert::TryInto;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+const MAGIC: [u8; 2] = *b"KH";
+const VERSION: u8 = 4;
+const HEADER_SIZE: usize = 11;
+const ENTRY_HEADER_SIZE: usize = 40;
+const TRAILER_SIZE: usize = 12;
+
+/// Location of the record of a key in the log the hint belongs to
+pub(super) struct HintEntry {
+    pub(super) key: Vec<u8>,
+    pub(super) offset: u64,
+    pub(super) len: u64,
+    pub(super) expires_at: Option<u64>,
+    pub(super) seq: u64,
+}
+
+pub(super) fn hint_path(dir: &Path, file_id: u64) -> PathBuf {
+    dir.join(format!("{}.hint", file_id))
+}
+
+/// Write the hint file of `file_id.log`.
+///
+/// It's written and synced to a temporary file first, so a crash never
+/// leaves a partial hint behind.
+pub(super) fn write_hint(dir: &Path, file_id: u64, entries: &[HintEntry]) -> std::io::Result<()> {
+    let mut buf =
+        Vec::with_capacity(HEADER_SIZE + entries.len() * (ENTRY_HEADER_SIZE + 16) + TRAILER_SIZE);
+    buf.extend_from_slice(&MAGIC);
+    buf.push(VERSION);
+    buf.extend_from_slice(&file_id.to_le_bytes());
+    for entry in entries {
+        let start = buf.len();
+        // placeholder of crc
+        buf.extend_from_slice(&[0; 4]);
+        buf.extend_from_slice(&entry.offset.to_le_bytes());
+        buf.extend_from_slice(&entry.len.to_le_bytes());
+        buf.extend_from_slice(&entry.expires_at.unwrap_or(0).to_le_bytes());
+        buf.extend_from_slice(&entry.seq.to_le_bytes());
+        buf.extend_from_slice(&(entry.key.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&entry.key);
+        let mut hasher = Hasher::new();
+        hasher.update(&buf[start + 4..]);
+        buf[start..start + 4].copy_from_slice(&hasher.finalize().to_le_bytes());
+    }
+    buf.extend_from_slice(&(entries.len() as u64).to_le_bytes());
+    let mut hasher = Hasher::new();
+    hasher.update(&buf);
+    buf.extend_from_slice(&hasher.finalize().to_le_bytes());
+
+    let tmp_path = dir.join(format!("{}.hint.tmp", file_id));
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(&buf)?;
+    file.sync_all()?;
+    fs::rename(tmp_path, hint_path(dir, file_id))
+}
+
+/// Read the hint file of `file_id.log` whose size is `log_len`.
+///
+/// Returns `None` if there is no hint file or it can't be trusted.
+pub(super) fn read_hint(dir: &Path, file_id: u64, log_len: u64) -> Option<Vec<HintEntry>> {
+    let buf = fs::read(hint_path(dir, file_id)).ok()?;
+    if buf.len() < HEADER_SIZE + TRAILER_SIZE
+        || buf[0..2] != MAGIC
+        || buf[2] != VERSION
+        || u64::from_le_bytes(buf[3..11].try_into().unwrap()) != file_id
+    {
+        return None;
+    }
+    let (buf, trailer) = buf.split_at(buf.len() - TRAILER_SIZE);
+    let count = u64::from_le_bytes(trailer[0..8].try_into().unwrap());
+    let mut hasher = Hasher::new();
+    hasher.update(buf);
+    hasher.update(&trailer[0..8]);
+    if hasher.finalize() != u32::from_le_bytes(trailer[8..12].try_into().unwrap()) {
+        return None;
+    }
+
+    let mut entries = Vec::new();
+    let mut rest = &buf[HEADER_SIZE..];
+    while !rest.is_empty() {
+        if rest.len() < ENTRY_HEADER_SIZE {
+            return None;
+        }
+        let crc = u32::from_le_bytes(rest[0..4].try_into().unwrap());
+        let offset = u64::from_le_bytes(rest[4..12].try_into().unwrap());
+        let len = u64::from_le_bytes(rest[12..20].try_into().unwrap());
+        let expires_at = u64::from_le_bytes(rest[20..28].try_into().unwrap());
+        let seq = u64::from_le_bytes(rest[28..36].try_into().unwrap());
+        let key_len = u32::from_le_bytes(rest[36..40].try_into().unwrap()) as usize;
+        if rest.len() < ENTRY_HEADER_SIZE + key_len {
+            return None;
+        }
+        let mut hasher = Hasher::new();
+        hasher.update(&rest[4..ENTRY_HEADER_SIZE + key_len]);
+        if hasher.finalize() != crc || offset.checked_add(len)? > log_len {
+            return None;
+        }
+        let key = rest[ENTRY_HEADER_SIZE..ENTRY_HEADER_SIZE + key_len].to_vec();
+        entries.push(HintEntry {
+            key,
+            offset,
+            len,
+            expires_at: Some(expires_at).filter(|&t| t != 0),
+            seq,
+        });
+        rest = &rest[ENTRY_HEADER_SIZE + key_len..];
+    }
+    if entries.len() as u64 != count {
+        return None;
+    }
+    Some(entries)
+}
@@ -1,75 +1,360 @@
-use super::KvsEngine;
+use super::batch::{BatchOp, WriteBatch};
+use super::expiry::Expiries;
+#[cfg(feature = "fault-injection")]
+use super::file::FaultInjector;
+use super::file::{Faults, LogFile};
+use super::hint::{self, HintEntry};
+use super::record::{self, Command, LegacyCommand, Record, RecordError};
+use super::{bytes_bound, create_checkpoint_dir, into_string, into_string_pairs};
+use super::{EngineOptions, KvsEngine, ReadSet, ReadVersion, SyncPolicy};
 use crate::{KvsError, Result};
 use async_trait::async_trait;
-use crossbeam_skiplist::SkipMap;
+use crossbeam::channel::{self, Receiver, Sender};
+use crossbeam_skiplist::{map, SkipMap};
 use fs::OpenOptions;
 use io::BufWriter;
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use log::{error, warn};
+use std::cmp::Reverse;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::fs::{self, File};
-use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::io::{self, BufRead, Read, Seek, SeekFrom, Write};
+use std::ops::{Bound, RangeBounds};
 use std::path::Path;
-use std::sync::{atomic, Arc, Mutex};
+use std::sync::{atomic, Arc, Mutex, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
 use std::{cell::RefCell, ffi::OsStr};
-use std::{io::BufReader, path::PathBuf, u64, usize};
+use std::{io::BufReader, path::PathBuf};
 use tokio::task::block_in_place;
 
-/// At most 2 MB inactive data
-const MAX_INACTIVE_DATA_SIZE: u64 = 1024 * 2048;
-/// max size of a single log's size
-const MAX_FILE_SIZE: u64 = 1024 * 2048;
+/// At most 2 MB inactive data by default
+const DEFAULT_COMPACTION_THRESHOLD: u64 = 1024 * 2048;
+/// max size of a single log's size by default
+const DEFAULT_MAX_FILE_SIZE: u64 = 1024 * 2048;
+const DEFAULT_READ_BUFFER_SIZE: usize = 8 * 1024;
+const DEFAULT_MAX_OPEN_FILES: usize = 64;
 
-#[allow(unsafe_code)]
+/// Options of a `KvStore`.
+///
+/// Example:
+///
+/// ```rust
+/// use kvs::{EngineOptions, KvStoreOptions, SyncPolicy};
+/// let options = KvStoreOptions::new()
+///     .max_file_size(64 * 1024 * 1024)
+///     .compaction_threshold(512 * 1024 * 1024)
+///     .compaction_ratio(0.5)
+///     .engine_options(EngineOptions::default().sync_policy(SyncPolicy::Always));
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct KvStoreOptions {
+    max_file_size: u64,
+    compaction_threshold: u64,
+    compaction_ratio: f64,
+    read_buffer_size: usize,
+    max_open_files: usize,
+    engine: EngineOptions,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> KvStoreOptions {
+        KvStoreOptions {
+            max_file_size: DEFAULT_MAX_FILE_SIZE,
+            compaction_threshold: DEFAULT_COMPACTION_THRESHOLD,
+            compaction_ratio: 0.0,
+            read_buffer_size: DEFAULT_READ_BUFFER_SIZE,
+            max_open_files: DEFAULT_MAX_OPEN_FILES,
+            engine: EngineOptions::default(),
+        }
+    }
+}
+
+impl KvStoreOptions {
+    /// Default options
+    pub fn new() -> KvStoreOptions {
+        KvStoreOptions::default()
+    }
+
+    /// Start a new log file once the active one grows beyond `size` bytes
+    pub fn max_file_size(mut self, size: u64) -> KvStoreOptions {
+        self.max_file_size = size;
+        self
+    }
+
+    /// Compact once there are at least `size` bytes of inactive data
+    pub fn compaction_threshold(mut self, size: u64) -> KvStoreOptions {
+        self.compaction_threshold = size;
+        self
+    }
+
+    /// Compact only if at least `ratio` of all log data is inactive,
+    /// in addition to the compaction threshold
+    pub fn compaction_ratio(mut self, ratio: f64) -> KvStoreOptions {
+        self.compaction_ratio = ratio;
+        self
+    }
+
+    /// Buffer size of every log file reader
+    pub fn read_buffer_size(mut self, size: usize) -> KvStoreOptions {
+        self.read_buffer_size = size;
+        self
+    }
+
+    /// Keep at most `count` log files open for reading per `KvStore` handle
+    pub fn max_open_files(mut self, count: usize) -> KvStoreOptions {
+        self.max_open_files = count;
+        self
+    }
+
+    /// Set the options shared by all engines, like the `SyncPolicy`
+    pub fn engine_options(mut self, options: EngineOptions) -> KvStoreOptions {
+        self.engine = options;
+        self
+    }
+
+    // writes are not synced by default
+    fn sync_policy(&self) -> SyncPolicy {
+        self.engine.sync_policy.unwrap_or(SyncPolicy::Never)
+    }
+
+    fn validate(&self) -> Result<()> {
+        let err = |msg: &str| Err(KvsError::OtherError(msg.to_owned()).into());
+        if self.max_file_size == 0 {
+            return err("max file size must be positive");
+        }
+        if !(0.0..=1.0).contains(&self.compaction_ratio) {
+            return err("compaction ratio must be between 0 and 1");
+        }
+        if self.read_buffer_size == 0 {
+            return err("read buffer size must be positive");
+        }
+        if self.max_open_files == 0 {
+            return err("max open files must be positive");
+        }
+        Ok(())
+    }
+}
 
+impl From<EngineOptions> for KvStoreOptions {
+    fn from(options: EngineOptions) -> KvStoreOptions {
+        KvStoreOptions::default().engine_options(options)
+    }
+}
+
+#[allow(unsafe_code)]
 unsafe impl Send for LogReader {}
 unsafe impl Sync for LogReader {}
 
 struct LogReader {
-    index: Arc<SkipMap<String, IndexEntry>>,
+    index: Arc<SkipMap<Vec<u8>, IndexEntry>>,
+    history: Arc<History>,
+    // held shared while reading the index and the history and exclusively
+    // while they are changed, so a write or a batch is seen whole or not at all
+    publish: Arc<RwLock<()>>,
     inactive_file_id_top: Arc<atomic::AtomicU64>,
     log_dir: PathBuf,
     // need interior mutability of refcell
-    readers: RefCell<HashMap<u64, CursorBufferReader<File>>>,
+    readers: RefCell<HashMap<u64, CursorBufferReader<LogFile>>>,
+    read_buffer_size: usize,
+    max_open_files: usize,
+    faults: Faults,
 }
 
 impl Clone for LogReader {
     fn clone(&self) -> Self {
         LogReader {
             index: Arc::clone(&self.index),
+            history: Arc::clone(&self.history),
+            publish: Arc::clone(&self.publish),
             inactive_file_id_top: Arc::clone(&self.inactive_file_id_top),
             log_dir: self.log_dir.clone(),
             readers: RefCell::new(HashMap::new()),
+            read_buffer_size: self.read_buffer_size,
+            max_open_files: self.max_open_files,
+            faults: self.faults.clone(),
         }
     }
 }
 
 impl LogReader {
-    fn get(&self, key: String) -> Result<Option<String>> {
-        if let Some(index) = self.index.get(&key) {
-            let cmd = self.read_command(index.value())?;
-            if let Command::Set { value, .. } = cmd {
-                Ok(Some(value))
-            } else {
-                Err(KvsError::BrokenCommand.into())
-            }
-        } else {
+    fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        match self.live_entry(&key) {
+            Some(entry) => Ok(Some(self.read_value(&entry)?)),
             // not an error, beaause we need to exit normally with code 0
-            Ok(None)
+            None => Ok(None),
+        }
+    }
+
+    // the index entry of a key unless it's absent or expired
+    fn live_entry(&self, key: &[u8]) -> Option<IndexEntry> {
+        let entry = {
+            let _publish = self.publish.read().unwrap();
+            *self.index.get(key)?.value()
+        };
+        if entry.is_expired(record::now_millis()) {
+            None
+        } else {
+            Some(entry)
+        }
+    }
+
+    // the index entry a snapshot at `seq` sees for a key unless it's absent or expired
+    fn entry_at(&self, key: &[u8], seq: u64) -> Option<IndexEntry> {
+        let _publish = self.publish.read().unwrap();
+        self.find_entry_at(key, seq)
+    }
+
+    // `entry_at` with the index and the history held already
+    fn find_entry_at(&self, key: &[u8], seq: u64) -> Option<IndexEntry> {
+        let entry = match self.index.get(key) {
+            Some(entry) if entry.value().seq <= seq => Some(*entry.value()),
+            // the newest version not after `seq` comes first in the history,
+            // it's gone at `seq` if it was superseded by then
+            _ => self
+                .history
+                .range((key.to_vec(), Reverse(seq))..)
+                .next()
+                .filter(|version| version.key().0 == key && version.value().superseded_at > seq)
+                .map(|version| version.value().entry),
+        };
+        entry.filter(|entry| !entry.is_expired(record::now_millis()))
+    }
+
+    fn get_at(&self, key: &[u8], seq: u64) -> Result<Option<Vec<u8>>> {
+        match self.entry_at(key, seq) {
+            Some(entry) => Ok(Some(self.read_value(&entry)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn scan_at<R: RangeBounds<Vec<u8>>>(
+        &self,
+        seq: u64,
+        range: R,
+        limit: Option<usize>,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let bounds = (range.start_bound().cloned(), range.end_bound().cloned());
+        // all versions of a key come before any version of a greater key
+        let history_start = match &bounds.0 {
+            Bound::Included(key) => Bound::Included((key.clone(), Reverse(u64::MAX))),
+            Bound::Excluded(key) => Bound::Excluded((key.clone(), Reverse(0))),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        let entries = {
+            let _publish = self.publish.read().unwrap();
+            let keys = self.index.range(bounds).map(|entry| entry.key().clone());
+            let versions = self
+                .history
+                .range((history_start, Bound::Unbounded))
+                .map(|version| version.key().0.clone())
+                .take_while(|key| range.contains(key));
+            self.entries_at(seq, merge_keys(keys, versions), limit)
+        };
+        self.read_entries(entries)
+    }
+
+    fn scan_prefix_at(
+        &self,
+        seq: u64,
+        prefix: &[u8],
+        limit: Option<usize>,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let entries = {
+            let _publish = self.publish.read().unwrap();
+            let keys = self
+                .index
+                .range::<[u8], _>((Bound::Included(prefix), Bound::Unbounded))
+                .take_while(|entry| entry.key().starts_with(prefix))
+                .map(|entry| entry.key().clone());
+            let versions = self
+                .history
+                .range((prefix.to_vec(), Reverse(u64::MAX))..)
+                .map(|version| version.key().0.clone())
+                .take_while(|key| key.starts_with(prefix));
+            self.entries_at(seq, merge_keys(keys, versions), limit)
+        };
+        self.read_entries(entries)
+    }
+
+    // the index entries a snapshot at `seq` sees for `keys`, skipping absent
+    // ones, keys are only walked until `limit` entries are found
+    fn entries_at(
+        &self,
+        seq: u64,
+        keys: impl Iterator<Item = Vec<u8>>,
+        limit: Option<usize>,
+    ) -> Vec<(Vec<u8>, IndexEntry)> {
+        keys.filter_map(|key| {
+            let entry = self.find_entry_at(&key, seq)?;
+            Some((key, entry))
+        })
+        .take(limit.unwrap_or(usize::MAX))
+        .collect()
+    }
+
+    fn read_value(&self, index: &IndexEntry) -> Result<Vec<u8>> {
+        if let Command::Set { value, .. } = self.read_command(index)? {
+            Ok(value)
+        } else {
+            Err(KvsError::BrokenCommand.into())
         }
     }
 
     fn read_command(&self, index: &IndexEntry) -> Result<Command> {
         let mut readers = self.readers.borrow_mut();
-        if !readers.contains_key(&index.file_id) {
-            let rerader = CursorBufferReader::new(File::open(
-                self.log_dir.join(format!("{}.log", index.file_id)),
-            )?)?;
-            readers.insert(index.file_id, rerader);
+        if readers.len() >= self.max_open_files && !readers.contains_key(&index.file_id) {
+            // the oldest log is the next to be compacted away
+            let oldest = readers.keys().min().cloned();
+            if let Some(oldest) = oldest {
+                readers.remove(&oldest);
+            }
         }
-        let reader = readers.get_mut(&index.file_id).unwrap();
+        let reader = match readers.entry(index.file_id) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(CursorBufferReader::with_capacity(
+                self.read_buffer_size,
+                LogFile::open(&log_path(&self.log_dir, index.file_id), &self.faults)?,
+            )?),
+        };
         reader.seek(SeekFrom::Start(index.offset))?;
-        let cmd_reader = reader.take(index.len);
-        Ok(serde_json::from_reader(cmd_reader)?)
+        let mut buf = vec![0; index.len as usize];
+        reader.read_exact(&mut buf)?;
+        Command::decode(&buf).map_err(|err| record_error(err, index.file_id, index.offset))
+    }
+
+    fn scan<R: RangeBounds<Vec<u8>>>(
+        &self,
+        range: R,
+        limit: Option<usize>,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let entries = {
+            let _publish = self.publish.read().unwrap();
+            live_entries(self.index.range(range), limit)
+        };
+        self.read_entries(entries)
+    }
+
+    fn scan_prefix(&self, prefix: &[u8], limit: Option<usize>) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let entries = {
+            let _publish = self.publish.read().unwrap();
+            let entries = self
+                .index
+                .range::<[u8], _>((Bound::Included(prefix), Bound::Unbounded))
+                .take_while(|entry| entry.key().starts_with(prefix));
+            live_entries(entries, limit)
+        };
+        self.read_entries(entries)
+    }
+
+    // read the values of index entries
+    fn read_entries(&self, entries: Vec<(Vec<u8>, IndexEntry)>) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut pairs = Vec::with_capacity(entries.len());
+        for (key, entry) in entries {
+            let value = self.read_value(&entry)?;
+            pairs.push((key, value));
+        }
+        Ok(pairs)
     }
 
     fn clean_readers(&self) {
@@ -80,7 +365,6 @@ impl LogReader {
             .borrow()
             .keys()
             .cloned()
-            .into_iter()
             .filter(|&id| id <= file_id_bar)
             .collect::<Vec<u64>>();
         for key in keys {
@@ -90,106 +374,616 @@ impl LogReader {
 }
 
 struct LogWriter {
-    reader: LogReader,
-    writer: CursorBufferWriter<File>,
+    writer: CursorBufferWriter<LogFile>,
     file_id: u64,
-    index: Arc<SkipMap<String, IndexEntry>>,
+    index: Arc<SkipMap<Vec<u8>, IndexEntry>>,
+    history: Arc<History>,
+    // see `LogReader::publish`
+    publish: Arc<RwLock<()>>,
+    expiries: Expiries,
+    // sequence number of the last write
+    seq: u64,
+    // sequence numbers of open snapshots, with how many are open at each
+    snapshots: BTreeMap<u64, usize>,
+    // the versions in the history by the sequence number of their superseding write
+    superseded: BTreeSet<(u64, VersionKey)>,
     inactive_data: u64,
+    // size of all log files
+    data_size: u64,
     log_dir: PathBuf,
+    options: KvStoreOptions,
+    faults: Faults,
+    compactor: Sender<CompactionRequest>,
+    // a compaction has been requested but not finished yet
+    compaction_pending: bool,
+    // a write to the active log has failed
+    failed: bool,
 }
 
 impl LogWriter {
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        let command = Command::set(key, value);
+    fn set(&mut self, key: Vec<u8>, value: Vec<u8>, expires_at: Option<u64>) -> Result<()> {
+        let seq = self.next_seq();
+        let command = Command::set(key, value, expires_at);
         let offset = self.writer.cursor;
-        serde_json::to_writer(&mut self.writer, &command)?;
-        self.writer.flush()?;
+        self.append(&command.encode(seq))?;
+        self.data_size += self.writer.cursor - offset;
         let key = command.key();
-        if let Some(index) = self.index.get(&key) {
-            self.inactive_data += index.value().len;
-        }
-        self.index.insert(
-            key,
-            IndexEntry::new(self.file_id, offset, self.writer.cursor),
-        );
-        if self.writer.cursor > MAX_FILE_SIZE {
-            self.file_id += 1;
-            self.writer = new_log_file(self.file_id, &self.log_dir)?;
+        let entry = IndexEntry::new(self.file_id, offset, self.writer.cursor, expires_at, seq);
+        {
+            let publish = Arc::clone(&self.publish);
+            let _publish = publish.write().unwrap();
+            self.keep_version(&key, seq);
+            let old = self.index.get(&key).map(|old| *old.value());
+            if let Some(old) = old {
+                self.inactive_data += old.len;
+            }
+            self.expiries
+                .update(&key, old.and_then(|old| old.expires_at), expires_at);
+            self.index.insert(key, entry);
         }
-        if self.inactive_data >= MAX_INACTIVE_DATA_SIZE {
-            self.compact()?;
+        if self.writer.cursor > self.options.max_file_size {
+            self.rotate(self.file_id + 1)?;
         }
+        self.maybe_compact();
 
         Ok(())
     }
 
-    fn remove(&mut self, key: String) -> Result<()> {
+    fn remove(&mut self, key: Vec<u8>) -> Result<()> {
         {
-            if self.index.contains_key(&key) {
+            let live = self
+                .index
+                .get(&key)
+                .is_some_and(|entry| !entry.value().is_expired(record::now_millis()));
+            if live {
+                let seq = self.next_seq();
                 let command = Command::rm(key);
-                serde_json::to_writer(&mut self.writer, &command)?;
-                self.writer.flush()?;
-                if self.writer.cursor > MAX_FILE_SIZE {
-                    self.file_id += 1;
-                    self.writer = new_log_file(self.file_id, &self.log_dir)?;
+                let offset = self.writer.cursor;
+                self.append(&command.encode(seq))?;
+                self.data_size += self.writer.cursor - offset;
+                let key = command.key();
+                {
+                    let publish = Arc::clone(&self.publish);
+                    let _publish = publish.write().unwrap();
+                    self.keep_version(&key, seq);
+                    match self.index.remove(&key) {
+                        Some(index) => {
+                            self.inactive_data += index.value().len;
+                            self.expiries.update(&key, index.value().expires_at, None);
+                        }
+                        None => return Err(KvsError::BrokenIndex.into()),
+                    }
                 }
-                if let Some(index) = self.index.remove(&command.key()) {
-                    self.inactive_data += index.value().len;
-                } else {
-                    return Err(KvsError::BrokenIndex.into());
+                if self.writer.cursor > self.options.max_file_size {
+                    self.rotate(self.file_id + 1)?;
                 }
             } else {
                 return Err(KvsError::KeyNotFound.into());
             }
         }
-        if self.inactive_data >= MAX_INACTIVE_DATA_SIZE {
-            self.compact()?;
+        self.maybe_compact();
+        Ok(())
+    }
+
+    // write all commands of a batch at once, between batch begin and commit records
+    fn write_batch(&mut self, ops: Vec<BatchOp>) -> Result<()> {
+        let base = self.writer.cursor;
+        let mut buf = record::encode_batch_begin();
+        let mut entries = Vec::with_capacity(ops.len());
+        for op in ops {
+            let seq = self.next_seq();
+            let (command, is_set) = match op {
+                BatchOp::Set { key, value } => (Command::set(key, value, None), true),
+                BatchOp::Remove { key } => (Command::rm(key), false),
+            };
+            let offset = base + buf.len() as u64;
+            buf.extend_from_slice(&command.encode(seq));
+            let end = base + buf.len() as u64;
+            let entry = if is_set {
+                Some(IndexEntry::new(self.file_id, offset, end, None, seq))
+            } else {
+                None
+            };
+            entries.push((command.key(), entry, seq));
+        }
+        buf.extend_from_slice(&record::encode_batch_commit());
+        self.append(&buf)?;
+        self.data_size += self.writer.cursor - base;
+
+        // readers see either none or all of the batch
+        {
+            let publish = Arc::clone(&self.publish);
+            let _publish = publish.write().unwrap();
+            for (key, entry, seq) in entries {
+                self.keep_version(&key, seq);
+                if let Some(old) = self.index.get(&key).map(|old| *old.value()) {
+                    self.inactive_data += old.len;
+                    // batch sets never expire
+                    self.expiries.update(&key, old.expires_at, None);
+                }
+                match entry {
+                    Some(entry) => {
+                        self.index.insert(key, entry);
+                    }
+                    None => {
+                        self.index.remove(&key);
+                    }
+                }
+            }
+        }
+        // a batch is never split over log files
+        if self.writer.cursor > self.options.max_file_size {
+            self.rotate(self.file_id + 1)?;
+        }
+        self.maybe_compact();
+        Ok(())
+    }
+
+    fn next_seq(&mut self) -> u64 {
+        self.seq += 1;
+        self.seq
+    }
+
+    // move the current version of a key to the history before the write
+    // numbered `seq` supersedes it, if an open snapshot may still read it
+    fn keep_version(&mut self, key: &[u8], seq: u64) {
+        let newest_snapshot = match self.snapshots.keys().next_back() {
+            Some(&snapshot) => snapshot,
+            None => return,
+        };
+        let current = match self.index.get(key) {
+            Some(entry) if entry.value().seq <= newest_snapshot => *entry.value(),
+            _ => return,
+        };
+        let version_key = (key.to_vec(), Reverse(current.seq));
+        self.superseded.insert((seq, version_key.clone()));
+        self.history.insert(
+            version_key,
+            Version {
+                entry: current,
+                superseded_at: seq,
+            },
+        );
+    }
+
+    // close a snapshot at `seq` and drop the versions no open snapshot reads anymore
+    fn release_snapshot(&mut self, seq: u64) {
+        match self.snapshots.get_mut(&seq) {
+            Some(count) if *count > 1 => {
+                *count -= 1;
+                return;
+            }
+            Some(_) => {
+                self.snapshots.remove(&seq);
+            }
+            None => return,
+        }
+        // only versions the released snapshot has read may be unread now,
+        // those the previous and the next open snapshots don't read: written
+        // after the previous one and superseded by the next one
+        let previous = self
+            .snapshots
+            .range(..seq)
+            .next_back()
+            .map(|(&prev, _)| prev);
+        let next = self
+            .snapshots
+            .range(seq..)
+            .next()
+            .map_or(u64::MAX, |(&next, _)| next);
+        let unread = self
+            .superseded
+            .range((seq.saturating_add(1), (Vec::new(), Reverse(u64::MAX)))..)
+            .take_while(|(superseded_at, _)| *superseded_at <= next)
+            .filter(|(_, (_, Reverse(written_at)))| previous.is_none_or(|prev| *written_at > prev))
+            .cloned()
+            .collect::<Vec<_>>();
+        let publish = Arc::clone(&self.publish);
+        let _publish = publish.write().unwrap();
+        for (superseded_at, version_key) in unread {
+            self.history.remove(&version_key);
+            self.superseded.remove(&(superseded_at, version_key));
+        }
+    }
+
+    // drop the keys which are due to expire from the index if they have,
+    // nothing is written since replaying their records expires them again
+    fn remove_expired(&mut self) -> usize {
+        let now = record::now_millis();
+        let mut removed = 0;
+        for key in self.expiries.take_due(now) {
+            let expired = self
+                .index
+                .get(&key)
+                .is_some_and(|entry| entry.value().is_expired(now));
+            if expired {
+                if let Some(entry) = self.index.remove(&key) {
+                    self.inactive_data += entry.value().len;
+                    removed += 1;
+                }
+            }
+        }
+        self.maybe_compact();
+        removed
+    }
+
+    // ask the compactor for a run once there is enough inactive data
+    fn maybe_compact(&mut self) {
+        let ratio = self.inactive_data as f64 / self.data_size.max(1) as f64;
+        if self.inactive_data >= self.options.compaction_threshold
+            && ratio >= self.options.compaction_ratio
+            && !self.compaction_pending
+        {
+            self.compaction_pending = true;
+            let _ = self.compactor.send(CompactionRequest::Run(None));
+        }
+    }
+
+    // append records to the active log. what a failed append has written is
+    // cut off again, so no torn record or unfinished batch is left in the
+    // middle of the log. if that fails too nothing is written after it until
+    // the store is reopened, which cuts it off
+    fn append(&mut self, buf: &[u8]) -> Result<()> {
+        self.check_failed()?;
+        let offset = self.writer.cursor;
+        let result = self
+            .writer
+            .write_all(buf)
+            .map_err(Into::into)
+            .and_then(|_| self.flush());
+        if result.is_err() {
+            self.failed = self.writer.truncate(offset).is_err();
+        }
+        result
+    }
+
+    fn check_failed(&self) -> Result<()> {
+        if self.failed {
+            return Err(KvsError::OtherError(
+                "an earlier write to the log failed, reopen the store".to_owned(),
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    // flush written data, and fsync it if every write should be synced
+    fn flush(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        if self.options.sync_policy() == SyncPolicy::Always {
+            self.writer.sync()?;
+        }
+        Ok(())
+    }
+
+    // switch to a new log file
+    fn rotate(&mut self, file_id: u64) -> Result<()> {
+        // a torn record must stay at the tail of the newest log
+        self.check_failed()?;
+        let mut writer = new_log_file(file_id, &self.log_dir, &self.faults)?;
+        std::mem::swap(&mut self.writer, &mut writer);
+        self.file_id = file_id;
+        let result = finish_log(&mut writer, self.options.sync_policy());
+        self.failed = result.is_err();
+        result
+    }
+}
+
+enum CompactionRequest {
+    /// Run a compaction, and report the result if someone is waiting for it
+    Run(Option<Sender<Result<()>>>),
+    /// Copy the logs to a directory and report the result
+    Checkpoint(PathBuf, Sender<Result<()>>),
+    Stop,
+}
+
+/// Compacts sealed logs in the background.
+///
+/// The active log is sealed at the start of a compaction, then live records
+/// in sealed logs are copied to new logs while writers keep appending to the
+/// active log. The index is only pointed to a copy if it still points to the
+/// copied record. Old versions open snapshots read are copied as well, as
+/// superseded records which are not replayed.
+struct Compactor {
+    writer: Arc<Mutex<LogWriter>>,
+    reader: LogReader,
+    index: Arc<SkipMap<Vec<u8>, IndexEntry>>,
+    log_dir: PathBuf,
+    options: KvStoreOptions,
+    stop: Arc<atomic::AtomicBool>,
+}
+
+impl Compactor {
+    fn run(self, receiver: Receiver<CompactionRequest>) {
+        loop {
+            match receiver.recv() {
+                Ok(CompactionRequest::Run(reply)) => {
+                    let result = self.compact();
+                    self.writer.lock().unwrap().compaction_pending = false;
+                    match reply {
+                        Some(reply) => {
+                            let _ = reply.send(result);
+                        }
+                        None => {
+                            if let Err(err) = result {
+                                error!("compaction failed: {}", err);
+                            }
+                        }
+                    }
+                }
+                Ok(CompactionRequest::Checkpoint(dest, reply)) => {
+                    let _ = reply.send(self.checkpoint(&dest));
+                }
+                Ok(CompactionRequest::Stop) | Err(_) => break,
+            }
+        }
+    }
+
+    /// copy all logs written so far to `dest`, sealed logs are hard linked
+    /// and the active one is copied up to its last write.
+    ///
+    /// It runs on the compactor thread, so no log is removed meanwhile.
+    fn checkpoint(&self, dest: &Path) -> Result<()> {
+        create_checkpoint_dir(dest)?;
+        let (active_id, active_len) = {
+            let mut writer = self.writer.lock().unwrap();
+            writer.writer.flush()?;
+            (writer.file_id, writer.writer.cursor)
+        };
+        // logs after the active one hold later writes
+        for file_id in get_file_ids(&self.log_dir)? {
+            if file_id == active_id {
+                let src = File::open(log_path(&self.log_dir, file_id))?;
+                let mut dst = File::create(log_path(dest, file_id))?;
+                io::copy(&mut src.take(active_len), &mut dst)?;
+                dst.sync_all()?;
+            } else if file_id < active_id {
+                link_or_copy(&log_path(&self.log_dir, file_id), &log_path(dest, file_id))?;
+                let hint_path = hint::hint_path(&self.log_dir, file_id);
+                if hint_path.exists() {
+                    link_or_copy(&hint_path, &hint::hint_path(dest, file_id))?;
+                }
+            }
         }
         Ok(())
     }
 
-    /// compact log by copy all active data to a new log file and
-    /// remove all old log files.
-    fn compact(&mut self) -> Result<()> {
-        let origin_compaction_file_id = self.file_id;
-        let mut compaction_file_id = self.file_id + 1;
-        let mut compaction_writer = new_log_file(compaction_file_id, &self.log_dir)?;
+    /// compact log by copy all live data in sealed log files to new log files
+    /// and remove the sealed log files.
+    fn compact(&self) -> Result<()> {
+        // seal the active log, and leave enough file ids before the next active
+        // log for the compacted data, so it's still replayed before newer writes
+        let max_file_size = self.options.max_file_size;
+        let (sealed_top, sealed_size, inactive_at_seal) = {
+            let mut writer = self.writer.lock().unwrap();
+            let sealed_top = writer.file_id;
+            let mut sealed_size = 0;
+            for file_id in get_file_ids(&self.log_dir)? {
+                sealed_size += fs::metadata(log_path(&self.log_dir, file_id))?.len();
+            }
+            writer.rotate(sealed_top + sealed_size / max_file_size + 2)?;
+            (sealed_top, sealed_size, writer.inactive_data)
+        };
+
+        let mut file = CompactionFile::new(sealed_top + 1, &self.log_dir, &self.reader.faults)?;
+        let mut expired = Vec::new();
+        let mut stale_data = 0;
+        let mut compacted_size = 0;
+        let now = record::now_millis();
         // traversing index entries
         for entry in self.index.iter() {
-            let index = entry.value();
-            let cmd = self.reader.read_command(index)?;
-            let offset = compaction_writer.cursor;
-            serde_json::to_writer(&mut compaction_writer, &cmd)?;
-            compaction_writer.flush()?;
-            let new_index = IndexEntry::new(compaction_file_id, offset, compaction_writer.cursor);
-            self.index.insert(entry.key().clone(), new_index);
-
-            if compaction_writer.cursor > MAX_FILE_SIZE {
-                compaction_writer.flush()?;
-                compaction_file_id += 1;
-                compaction_writer = new_log_file(compaction_file_id, &self.log_dir)?;
+            if self.stop.load(atomic::Ordering::SeqCst) {
+                return Ok(());
+            }
+            let origin = *entry.value();
+            if origin.file_id > sealed_top {
+                continue;
+            }
+            // expired records are not copied
+            if origin.is_expired(now) {
+                expired.push((entry.key().clone(), origin));
+                continue;
+            }
+            let cmd = self.reader.read_command(&origin)?;
+            let offset = file.writer.cursor;
+            file.writer.write_all(&cmd.encode(origin.seq))?;
+            file.hints.push(HintEntry {
+                key: entry.key().clone(),
+                offset,
+                len: file.writer.cursor - offset,
+                expires_at: origin.expires_at,
+                seq: origin.seq,
+            });
+            file.origins.push(origin);
+
+            let (size, stale) = self.roll_if_full(&mut file)?;
+            compacted_size += size;
+            stale_data += stale;
+        }
+        for version in self.reader.history.iter() {
+            if self.stop.load(atomic::Ordering::SeqCst) {
+                return Ok(());
             }
+            let origin = version.value().entry;
+            if origin.file_id > sealed_top || origin.is_expired(now) {
+                continue;
+            }
+            let cmd = self.reader.read_command(&origin)?;
+            let offset = file.writer.cursor;
+            file.writer.write_all(&cmd.encode_superseded(origin.seq))?;
+            let copy = IndexEntry::new(
+                file.file_id,
+                offset,
+                file.writer.cursor,
+                origin.expires_at,
+                origin.seq,
+            );
+            file.versions.push((version.key().clone(), origin, copy));
+
+            let (size, stale) = self.roll_if_full(&mut file)?;
+            compacted_size += size;
+            stale_data += stale;
         }
-        compaction_writer.flush()?;
-        self.file_id = compaction_file_id + 1;
-        self.writer = new_log_file(self.file_id, &self.log_dir)?;
+        compacted_size += file.writer.cursor;
+        stale_data += self.install(file)?;
 
+        {
+            let mut writer = self.writer.lock().unwrap();
+            for (key, origin) in expired {
+                let unchanged = self.index.get(&key).is_some_and(|entry| {
+                    entry.value().file_id == origin.file_id && entry.value().offset == origin.offset
+                });
+                if unchanged {
+                    self.index.remove(&key);
+                }
+            }
+            writer.inactive_data =
+                writer.inactive_data.saturating_sub(inactive_at_seal) + stale_data;
+            writer.data_size = writer.data_size.saturating_sub(sealed_size) + compacted_size;
+        }
         self.reader
             .inactive_file_id_top
-            .store(origin_compaction_file_id, atomic::Ordering::SeqCst);
+            .store(sealed_top, atomic::Ordering::SeqCst);
+        self.reader.clean_readers();
 
+        // nothing points to the sealed logs anymore
         let inactive_file_ids = get_file_ids(&self.log_dir)?
             .into_iter()
-            .filter(|&id| id <= origin_compaction_file_id)
+            .filter(|&id| id <= sealed_top)
             .collect::<Vec<u64>>();
-        self.reader.clean_readers();
-
         for file_id in inactive_file_ids {
-            fs::remove_file(self.log_dir.join(format!("{}.log", file_id)))?;
+            fs::remove_file(log_path(&self.log_dir, file_id))?;
+            match fs::remove_file(hint::hint_path(&self.log_dir, file_id)) {
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                result => result?,
+            }
         }
-        self.inactive_data = 0;
         Ok(())
     }
+
+    // install the compaction file once it's full and go on with a new one,
+    // return the size of the installed file and of its stale records
+    fn roll_if_full(&self, file: &mut CompactionFile) -> Result<(u64, u64)> {
+        if file.writer.cursor <= self.options.max_file_size {
+            return Ok((0, 0));
+        }
+        let next = CompactionFile::new(file.file_id + 1, &self.log_dir, &self.reader.faults)?;
+        let full = std::mem::replace(file, next);
+        let size = full.writer.cursor;
+        Ok((size, self.install(full)?))
+    }
+
+    // turn a finished compaction file into a log and point the index to it,
+    // return the size of copied records which have been overwritten meanwhile
+    // and of superseded records
+    fn install(&self, file: CompactionFile) -> Result<u64> {
+        let CompactionFile {
+            file_id,
+            mut writer,
+            hints,
+            origins,
+            versions,
+        } = file;
+        let path = compaction_path(&self.log_dir, file_id);
+        if hints.is_empty() && versions.is_empty() {
+            drop(writer);
+            fs::remove_file(path)?;
+            return Ok(0);
+        }
+        // compacted data must be on the disk before old logs go away
+        finish_log(&mut writer, self.options.sync_policy())?;
+        fs::rename(path, log_path(&self.log_dir, file_id))?;
+        hint::write_hint(&self.log_dir, file_id, &hints)?;
+
+        // the index is only modified with the writer locked
+        let _writer = self.writer.lock().unwrap();
+        let _publish = self.reader.publish.write().unwrap();
+        let mut stale_data = 0;
+        for (hint, origin) in hints.iter().zip(&origins) {
+            let end = hint.offset + hint.len;
+            let copy = IndexEntry::new(file_id, hint.offset, end, hint.expires_at, hint.seq);
+            match self.index.get(&hint.key) {
+                Some(entry)
+                    if entry.value().file_id == origin.file_id
+                        && entry.value().offset == origin.offset =>
+                {
+                    self.index.insert(hint.key.clone(), copy);
+                }
+                _ => {
+                    stale_data += hint.len;
+                    // it may have been kept for a snapshot meanwhile
+                    self.repoint_version((hint.key.clone(), Reverse(origin.seq)), origin, copy);
+                }
+            }
+        }
+        for (key, origin, copy) in versions {
+            stale_data += copy.len;
+            self.repoint_version(key, &origin, copy);
+        }
+        Ok(stale_data)
+    }
+
+    // point a version in the history to its copy if it's still at `origin`
+    fn repoint_version(&self, key: VersionKey, origin: &IndexEntry, copy: IndexEntry) {
+        let superseded_at = match self.reader.history.get(&key) {
+            Some(version) => {
+                let entry = version.value().entry;
+                if entry.file_id != origin.file_id || entry.offset != origin.offset {
+                    return;
+                }
+                version.value().superseded_at
+            }
+            None => return,
+        };
+        self.reader.history.insert(
+            key,
+            Version {
+                entry: copy,
+                superseded_at,
+            },
+        );
+    }
+}
+
+// a compaction file being written
+struct CompactionFile {
+    file_id: u64,
+    writer: CursorBufferWriter<LogFile>,
+    hints: Vec<HintEntry>,
+    // where the records of `hints` have been copied from
+    origins: Vec<IndexEntry>,
+    // superseded versions with where they have been copied from and to
+    versions: Vec<(VersionKey, IndexEntry, IndexEntry)>,
+}
+
+impl CompactionFile {
+    fn new(file_id: u64, dir: &Path, faults: &Faults) -> Result<CompactionFile> {
+        Ok(CompactionFile {
+            file_id,
+            writer: new_compaction_file(file_id, dir, faults)?,
+            hints: Vec::new(),
+            origins: Vec::new(),
+            versions: Vec::new(),
+        })
+    }
+}
+
+/// Stops the compactor once the last `KvStore` handle is dropped
+struct CompactorHandle {
+    sender: Sender<CompactionRequest>,
+    stop: Arc<atomic::AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for CompactorHandle {
+    fn drop(&mut self) {
+        // an unfinished compaction is safe to abandon, it's cleaned up on open
+        self.stop.store(true, atomic::Ordering::SeqCst);
+        let _ = self.sender.send(CompactionRequest::Stop);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
 }
 
 /// The `KvStore` is a to store Key/Value pairs based on log-structured storage.
@@ -217,187 +1011,882 @@ impl LogWriter {
 pub struct KvStore {
     reader: LogReader,
     writer: Arc<Mutex<LogWriter>>,
+    compactor: Arc<CompactorHandle>,
 }
 
 #[async_trait]
 impl KvsEngine for KvStore {
-    /// Set the string value of a given string key.
+    /// Set the value of a given key.
     ///
     /// If the given key already exists, the previous value will be overwitten.
     ///
     /// # Errors
     ///
     /// Errors may be thrown when I/O and serializing
-    async fn set(&self, key: String, value: String) -> Result<()> {
-        block_in_place(move || self.writer.lock().unwrap().set(key, value))
+    async fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        block_in_place(move || self.writer.lock().unwrap().set(key, value, None))
     }
 
-    /// Get the string value of a given string key.
+    /// Set the value of a given key, which expires after `ttl`.
+    ///
+    /// # Errors
+    ///
+    /// Errors may be thrown when I/O and serializing
+    async fn set_with_ttl_bytes(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let expires_at = record::expiry_after(ttl);
+        block_in_place(move || {
+            self.writer
+                .lock()
+                .unwrap()
+                .set(key, value, Some(expires_at))
+        })
+    }
+
+    /// Get the value of a given key.
     ///
     /// Returns `None` if the given key does not exist.
     ///
     /// # Errors
     ///
     /// Errors may be thrown when I/O and serializing
-    async fn get(&self, key: String) -> Result<Option<String>> {
+    async fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
         block_in_place(move || self.reader.get(key))
     }
 
-    /// Remove a given string key.
+    /// Remove a given key.
     ///
     /// # Errors
     ///
     /// Returns `KvsError::KeyNotFound` if the given ket does not exixt.
     /// Errors may be thrown when I/O and serializing
-    async fn remove(&self, key: String) -> Result<()> {
+    async fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
         block_in_place(move || self.writer.lock().unwrap().remove(key))
     }
+
+    /// Get how long a given key lives, `None` if it never expires.
+    ///
+    /// # Errors
+    ///
+    /// Returns `KvsError::KeyNotFound` if the given ket does not exixt.
+    async fn ttl_bytes(&self, key: Vec<u8>) -> Result<Option<Duration>> {
+        let entry = self.reader.live_entry(&key).ok_or(KvsError::KeyNotFound)?;
+        Ok(entry.expires_at.map(|expires_at| {
+            Duration::from_millis(expires_at.saturating_sub(record::now_millis()))
+        }))
+    }
+
+    /// Clear the expiry of a given key.
+    ///
+    /// The value is written again without an expiry if it had one.
+    ///
+    /// # Errors
+    ///
+    /// Returns `KvsError::KeyNotFound` if the given ket does not exixt.
+    /// Errors may be thrown when I/O and serializing
+    async fn persist_bytes(&self, key: Vec<u8>) -> Result<()> {
+        block_in_place(move || {
+            let mut writer = self.writer.lock().unwrap();
+            let entry = self.reader.live_entry(&key).ok_or(KvsError::KeyNotFound)?;
+            if entry.expires_at.is_none() {
+                return Ok(());
+            }
+            let value = self.reader.read_value(&entry)?;
+            writer.set(key, value, None)
+        })
+    }
+
+    /// Set a given key to `new` only if its value is `expected`.
+    ///
+    /// The swapped value expires after `ttl` if it's given.
+    ///
+    /// # Errors
+    ///
+    /// Returns `KvsError::CasMismatch` with the current value if it's not `expected`.
+    /// Errors may be thrown when I/O and serializing
+    async fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+        ttl: Option<Duration>,
+    ) -> Result<()> {
+        let expires_at = ttl.map(record::expiry_after);
+        block_in_place(move || {
+            // nothing else writes while the writer is locked
+            let mut writer = self.writer.lock().unwrap();
+            let current = match self.reader.live_entry(&key) {
+                Some(entry) => Some(self.reader.read_value(&entry)?),
+                None => None,
+            };
+            if current != expected {
+                return Err(KvsError::CasMismatch { current }.into());
+            }
+            match new {
+                Some(value) => writer.set(key, value, expires_at),
+                None if current.is_some() => writer.remove(key),
+                None => Ok(()),
+            }
+        })
+    }
+
+    /// Apply all writes of a batch, or none of them if it's interrupted.
+    ///
+    /// The batch is written to the log at once and then applied to the index
+    /// while readers are held off, so they see all of it at once or none of it.
+    ///
+    /// # Errors
+    ///
+    /// Errors may be thrown when I/O and serializing
+    async fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        block_in_place(move || self.writer.lock().unwrap().write_batch(batch.into_ops()))
+    }
+
+    /// Get the value of a given key along with the sequence number of its write.
+    ///
+    /// # Errors
+    ///
+    /// Errors may be thrown when I/O and serializing
+    async fn get_versioned_bytes(&self, key: Vec<u8>) -> Result<ReadVersion> {
+        block_in_place(move || match self.reader.live_entry(&key) {
+            Some(entry) => Ok(ReadVersion {
+                value: Some(self.reader.read_value(&entry)?),
+                seq: Some(entry.seq),
+            }),
+            None => Ok(ReadVersion {
+                value: None,
+                seq: None,
+            }),
+        })
+    }
+
+    /// Apply `writes` as a batch if no key in `reads` has been written since.
+    ///
+    /// The sequence numbers of the writes read are compared rather than
+    /// values, so a value written again is a conflict even if it's the same,
+    /// and nothing is read from the disk. They are checked and written with
+    /// the writer locked, so nothing can change them in between.
+    ///
+    /// # Errors
+    ///
+    /// Returns `KvsError::Conflict` if a value has changed.
+    /// Errors may be thrown when I/O and serializing
+    async fn commit_transaction(&self, reads: ReadSet, writes: WriteBatch) -> Result<()> {
+        block_in_place(move || {
+            let mut writer = self.writer.lock().unwrap();
+            for (key, read) in reads {
+                if self.reader.live_entry(&key).map(|entry| entry.seq) != read.seq {
+                    return Err(KvsError::Conflict.into());
+                }
+            }
+            if writes.is_empty() {
+                return Ok(());
+            }
+            writer.write_batch(writes.into_ops())
+        })
+    }
+
+    /// Drop all expired keys from the index.
+    ///
+    /// Keys with a TTL are kept in the order they expire, so only the keys
+    /// which are due are visited.
+    async fn remove_expired(&self) -> Result<usize> {
+        block_in_place(move || Ok(self.writer.lock().unwrap().remove_expired()))
+    }
+
+    /// Get key/value pairs whose keys are in `range`, in the order of keys.
+    ///
+    /// # Errors
+    ///
+    /// Errors may be thrown when I/O and serializing
+    async fn scan_bytes<R>(&self, range: R, limit: Option<usize>) -> Result<Vec<(Vec<u8>, Vec<u8>)>>
+    where
+        R: RangeBounds<Vec<u8>> + Send + 'static,
+    {
+        block_in_place(move || self.reader.scan(range, limit))
+    }
+
+    /// Get key/value pairs whose keys start with `prefix`, in the order of keys.
+    ///
+    /// # Errors
+    ///
+    /// Errors may be thrown when I/O and serializing
+    async fn scan_prefix_bytes(
+        &self,
+        prefix: Vec<u8>,
+        limit: Option<usize>,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        block_in_place(move || self.reader.scan_prefix(&prefix, limit))
+    }
+
+    /// Copy the logs to `dest`, waiting for a running compaction to finish first.
+    ///
+    /// Sealed logs are hard linked if `dest` is on the same file system.
+    ///
+    /// # Errors
+    ///
+    /// Errors may be thrown when I/O
+    async fn checkpoint(&self, dest: PathBuf) -> Result<()> {
+        block_in_place(move || {
+            let (sender, receiver) = channel::bounded(1);
+            self.compactor
+                .sender
+                .send(CompactionRequest::Checkpoint(dest, sender))
+                .map_err(|_| KvsError::OtherError("compactor stopped".to_owned()))?;
+            receiver
+                .recv()
+                .map_err(|_| KvsError::OtherError("compactor stopped".to_owned()))?
+        })
+    }
+
+    fn name(&self) -> &'static str {
+        "kvs"
+    }
 }
 
 impl KvStore {
     /// Open the `KvStore` at a given path. Return the KvStore.
     pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
-        let log_dir: PathBuf = path.into();
+        KvStore::open_with_options(path, EngineOptions::default())
+    }
+
+    /// Open the `KvStore` at a given path with `KvStoreOptions` or `EngineOptions`.
+    pub fn open_with_options(
+        path: impl Into<PathBuf>,
+        options: impl Into<KvStoreOptions>,
+    ) -> Result<KvStore> {
+        KvStore::open_with(path.into(), options.into(), Faults::default())
+    }
+
+    /// Open the `KvStore` at a given path, writing and syncing its log files
+    /// through a `FaultInjector` to test recovery.
+    #[cfg(feature = "fault-injection")]
+    pub fn open_with_faults(
+        path: impl Into<PathBuf>,
+        options: impl Into<KvStoreOptions>,
+        faults: FaultInjector,
+    ) -> Result<KvStore> {
+        KvStore::open_with(path.into(), options.into(), Faults::new(faults))
+    }
+
+    fn open_with(log_dir: PathBuf, options: KvStoreOptions, faults: Faults) -> Result<KvStore> {
+        options.validate()?;
         fs::create_dir_all(&log_dir)?;
+        remove_unfinished_compactions(&log_dir)?;
         let file_ids = get_file_ids(&log_dir)?;
         let file_id = file_ids.last().unwrap_or(&0_u64) + 1;
 
         let mut index = SkipMap::new();
         let mut readers = HashMap::new();
-        let inactive_data = load_logs(&log_dir, &file_ids, &mut index, &mut readers)?;
-        let writer = new_log_file(file_id, &log_dir)?;
+        let mut seq = 0;
+        let inactive_data = load_logs(
+            &log_dir,
+            &file_ids,
+            &mut index,
+            &mut readers,
+            &mut seq,
+            &options,
+            &faults,
+        )?;
+        let mut data_size = 0;
+        for &file_id in &file_ids {
+            data_size += fs::metadata(log_path(&log_dir, file_id))?.len();
+        }
+        // keep the newest logs open, the others are opened on demand
+        for file_id in file_ids.iter().rev().skip(options.max_open_files - 1) {
+            readers.remove(file_id);
+        }
+        let writer = new_log_file(file_id, &log_dir, &faults)?;
         readers.insert(
             file_id,
-            CursorBufferReader::new(File::open(log_dir.join(format!("{}.log", file_id)))?)?,
+            CursorBufferReader::with_capacity(
+                options.read_buffer_size,
+                LogFile::open(&log_path(&log_dir, file_id), &faults)?,
+            )?,
         );
+        let mut expiries = Expiries::default();
+        for entry in index.iter() {
+            expiries.update(entry.key(), None, entry.value().expires_at);
+        }
         let index = Arc::new(index);
+        let history = Arc::new(History::new());
+        let publish = Arc::new(RwLock::new(()));
         let reader = LogReader {
             index: index.clone(),
+            history: history.clone(),
+            publish: publish.clone(),
             log_dir: PathBuf::clone(&log_dir),
             readers: RefCell::new(readers),
             inactive_file_id_top: Arc::new(atomic::AtomicU64::new(0)),
+            read_buffer_size: options.read_buffer_size,
+            max_open_files: options.max_open_files,
+            faults: faults.clone(),
         };
+        let (sender, receiver) = channel::unbounded();
         let writer = Arc::new(Mutex::new(LogWriter {
-            reader: reader.clone(),
             writer,
             file_id,
-            index,
+            index: index.clone(),
+            history,
+            publish,
+            expiries,
+            seq,
+            snapshots: BTreeMap::new(),
+            superseded: BTreeSet::new(),
             inactive_data,
+            data_size,
             log_dir: PathBuf::clone(&log_dir),
+            options,
+            faults,
+            compactor: sender.clone(),
+            compaction_pending: false,
+            failed: false,
         }));
 
-        Ok(KvStore { reader, writer })
+        let stop = Arc::new(atomic::AtomicBool::new(false));
+        let compactor = Compactor {
+            writer: writer.clone(),
+            reader: reader.clone(),
+            index,
+            log_dir: PathBuf::clone(&log_dir),
+            options,
+            stop: stop.clone(),
+        };
+        let compactor = Arc::new(CompactorHandle {
+            sender,
+            stop,
+            thread: Some(thread::spawn(move || compactor.run(receiver))),
+        });
+
+        if let SyncPolicy::Interval(interval) = options.sync_policy() {
+            // the thread exits once the store is dropped
+            let writer = Arc::downgrade(&writer);
+            thread::spawn(move || loop {
+                thread::sleep(interval);
+                let writer = match writer.upgrade() {
+                    Some(writer) => writer,
+                    None => break,
+                };
+                // fsync through a handle of its own, so writes go on meanwhile
+                let file = writer.lock().unwrap().writer.sync_handle();
+                if let Err(err) = file.and_then(|file| file.sync_data()) {
+                    error!("background sync failed: {}", err);
+                }
+            });
+        }
+
+        Ok(KvStore {
+            reader,
+            writer,
+            compactor,
+        })
     }
+
+    /// Run a compaction in the background and wait for it to finish.
+    ///
+    /// Compactions are also started automatically once there is enough
+    /// inactive data, reads and writes go on while compacting.
+    pub async fn compact(&self) -> Result<()> {
+        block_in_place(move || {
+            let (sender, receiver) = channel::bounded(1);
+            self.compactor
+                .sender
+                .send(CompactionRequest::Run(Some(sender)))
+                .map_err(|_| KvsError::OtherError("compactor stopped".to_owned()))?;
+            receiver
+                .recv()
+                .map_err(|_| KvsError::OtherError("compactor stopped".to_owned()))?
+        })
+    }
+
+    /// Take a read-only `Snapshot` of the store as it is now.
+    ///
+    /// Values the snapshot sees are kept by compactions until it's dropped.
+    pub fn snapshot(&self) -> Snapshot {
+        let mut writer = self.writer.lock().unwrap();
+        let seq = writer.seq;
+        *writer.snapshots.entry(seq).or_insert(0) += 1;
+        Snapshot {
+            reader: self.reader.clone(),
+            writer: self.writer.clone(),
+            seq,
+        }
+    }
+}
+
+/// A read-only view of a `KvStore` at a point in time, see `KvStore::snapshot`.
+///
+/// Writes after the snapshot is taken are not seen by it, keys which
+/// expire meanwhile are gone though.
+///
+/// Example:
+///
+/// ```rust
+/// # use anyhow::{Result, Context};
+/// use kvs::{KvsEngine, KvStore};
+/// # fn main() -> Result<()> {
+/// let rt = tokio::runtime::Runtime::new().context("failed to create tokio runtime")?;
+/// rt.block_on(async move {
+///     let dir = tempfile::TempDir::new()?;
+///     let store = KvStore::open(dir.path())?;
+///     store.set("key".to_owned(), "old".to_owned()).await?;
+///     let snapshot = store.snapshot();
+///     store.set("key".to_owned(), "new".to_owned()).await?;
+///     assert_eq!(snapshot.get("key".to_owned()).await?, Some("old".to_owned()));
+///     Ok(())
+/// })
+/// # }
+///```
+pub struct Snapshot {
+    reader: LogReader,
+    writer: Arc<Mutex<LogWriter>>,
+    seq: u64,
 }
 
-fn new_log_file(file_id: u64, dir: &Path) -> Result<CursorBufferWriter<File>> {
-    let path = dir.join(format!("{}.log", file_id));
-    let writter = CursorBufferWriter::new(
-        OpenOptions::new()
-            .create(true)
-            .append(true)
-            .write(true)
-            .open(&path)?,
-    )?;
+impl Snapshot {
+    /// Sequence number of the last write the snapshot sees
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    /// Get the value of a given key as of the snapshot.
+    ///
+    /// # Errors
+    ///
+    /// Errors may be thrown when I/O and serializing
+    pub async fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        block_in_place(move || self.reader.get_at(&key, self.seq))
+    }
+
+    /// Get the string value of a given string key as of the snapshot.
+    pub async fn get(&self, key: String) -> Result<Option<String>> {
+        match self.get_bytes(key.into_bytes()).await? {
+            Some(value) => Ok(Some(into_string(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Get key/value pairs whose keys are in `range` as of the snapshot, in the order of keys.
+    ///
+    /// # Errors
+    ///
+    /// Errors may be thrown when I/O and serializing
+    pub async fn scan_bytes<R: RangeBounds<Vec<u8>>>(
+        &self,
+        range: R,
+        limit: Option<usize>,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        block_in_place(move || self.reader.scan_at(self.seq, range, limit))
+    }
+
+    /// Get string key/value pairs whose keys are in `range` as of the snapshot.
+    pub async fn scan<R: RangeBounds<String>>(
+        &self,
+        range: R,
+        limit: Option<usize>,
+    ) -> Result<Vec<(String, String)>> {
+        let range = (
+            bytes_bound(range.start_bound()),
+            bytes_bound(range.end_bound()),
+        );
+        into_string_pairs(self.scan_bytes(range, limit).await?)
+    }
+
+    /// Get key/value pairs whose keys start with `prefix` as of the snapshot.
+    ///
+    /// # Errors
+    ///
+    /// Errors may be thrown when I/O and serializing
+    pub async fn scan_prefix_bytes(
+        &self,
+        prefix: Vec<u8>,
+        limit: Option<usize>,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        block_in_place(move || self.reader.scan_prefix_at(self.seq, &prefix, limit))
+    }
+
+    /// Get string key/value pairs whose keys start with `prefix` as of the snapshot.
+    pub async fn scan_prefix(
+        &self,
+        prefix: String,
+        limit: Option<usize>,
+    ) -> Result<Vec<(String, String)>> {
+        into_string_pairs(self.scan_prefix_bytes(prefix.into_bytes(), limit).await?)
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        if let Ok(mut writer) = self.writer.lock() {
+            writer.release_snapshot(self.seq);
+        }
+    }
+}
+
+fn new_log_file(file_id: u64, dir: &Path, faults: &Faults) -> Result<CursorBufferWriter<LogFile>> {
+    let path = log_path(dir, file_id);
+    let writter = CursorBufferWriter::new(LogFile::append(&path, faults)?)?;
     Ok(writter)
 }
 
+// compacted data is written aside and renamed to a log once it's complete
+fn new_compaction_file(
+    file_id: u64,
+    dir: &Path,
+    faults: &Faults,
+) -> Result<CursorBufferWriter<LogFile>> {
+    let path = compaction_path(dir, file_id);
+    let writter = CursorBufferWriter::new(LogFile::create(&path, faults)?)?;
+    Ok(writter)
+}
+
+fn log_path(dir: &Path, file_id: u64) -> PathBuf {
+    dir.join(format!("{}.log", file_id))
+}
+
+fn compaction_path(dir: &Path, file_id: u64) -> PathBuf {
+    dir.join(format!("{}.compact", file_id))
+}
+
+// hard link a file which is never written again, or copy it across file systems
+fn link_or_copy(src: &Path, dst: &Path) -> Result<()> {
+    if fs::hard_link(src, dst).is_err() {
+        fs::copy(src, dst)?;
+    }
+    Ok(())
+}
+
+// flush a log file which will not be written anymore,
+// a background sync only covers the active file so it is synced here
+fn finish_log(writer: &mut CursorBufferWriter<LogFile>, sync_policy: SyncPolicy) -> Result<()> {
+    writer.flush()?;
+    if sync_policy != SyncPolicy::Never {
+        writer.sync()?;
+    }
+    Ok(())
+}
+
+// remove what a compaction interrupted by a crash has left
+fn remove_unfinished_compactions(dir: &Path) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_file() && path.extension() == Some("compact".as_ref()) {
+            fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
 // load all previously log data
 fn load_logs(
     dir: &Path,
     file_ids: &[u64],
-    index: &mut SkipMap<String, IndexEntry>,
-    readers: &mut HashMap<u64, CursorBufferReader<File>>,
+    index: &mut SkipMap<Vec<u8>, IndexEntry>,
+    readers: &mut HashMap<u64, CursorBufferReader<LogFile>>,
+    last_seq: &mut u64,
+    options: &KvStoreOptions,
+    faults: &Faults,
 ) -> Result<u64> {
     let mut inactive_data = 0_u64;
     for &file_id in file_ids {
-        let mut reader =
-            CursorBufferReader::new(File::open(dir.join(format!("{}.log", file_id)))?)?;
-
-        let mut de_stream =
-            serde_json::Deserializer::from_reader(&mut reader).into_iter::<Command>();
-        let mut offset = 0_u64;
-        while let Some(cmd) = de_stream.next() {
-            let curr_offset = de_stream.byte_offset() as u64;
-            match cmd? {
-                Command::Set { key, .. } => {
-                    // modify index to point to new data
-                    if let Some(ind) = index.get(&key) {
-                        inactive_data += ind.value().len;
-                    }
+        let path = dir.join(format!("{}.log", file_id));
+        let mut reader = CursorBufferReader::with_capacity(
+            options.read_buffer_size,
+            LogFile::open(&path, faults)?,
+        )?;
+
+        // compacted logs have a hint file, so values needn't be read
+        let log_len = reader.reader.get_ref().len()?;
+        if let Some(entries) = hint::read_hint(dir, file_id, log_len) {
+            for entry in entries {
+                let end = entry.offset + entry.len;
+                *last_seq = (*last_seq).max(entry.seq);
+                index_set(
+                    entry.key,
+                    IndexEntry::new(file_id, entry.offset, end, entry.expires_at, entry.seq),
+                    index,
+                    &mut inactive_data,
+                );
+            }
+            readers.insert(file_id, reader);
+            continue;
+        }
+
+        let torn = if record::is_legacy(reader.reader.fill_buf()?) {
+            load_legacy_log(file_id, &mut reader, index, &mut inactive_data)?
+        } else {
+            load_binary_log(file_id, &mut reader, index, &mut inactive_data, last_seq)?
+        };
+        if let Some(offset) = torn {
+            // only the newest log can be torn by a crash in the middle of a write,
+            // an incomplete record anywhere else means the data has been damaged
+            if Some(&file_id) != file_ids.last() {
+                return Err(KvsError::Corruption { file_id, offset }.into());
+            }
+            warn!(
+                "incomplete record at the tail of {}.log, truncate it to {} bytes",
+                file_id, offset
+            );
+            OpenOptions::new()
+                .write(true)
+                .open(&path)?
+                .set_len(offset)?;
+        }
+        readers.insert(file_id, reader);
+    }
+    Ok(inactive_data)
+}
 
-                    index.insert(key, IndexEntry::new(file_id, offset, curr_offset));
+// a command of a batch with its sequence number, offset and end
+type PendingCommand = (Command, u64, u64, u64);
+
+// replay a log file of binary records,
+// return the offset of the incomplete record at its tail if there is one
+fn load_binary_log(
+    file_id: u64,
+    reader: &mut CursorBufferReader<LogFile>,
+    index: &mut SkipMap<Vec<u8>, IndexEntry>,
+    inactive_data: &mut u64,
+    last_seq: &mut u64,
+) -> Result<Option<u64>> {
+    // where a batch which has not been committed yet begins, and its commands
+    let mut batch: Option<(u64, Vec<PendingCommand>)> = None;
+    loop {
+        let offset = reader.cursor;
+        // a torn write inside a batch tears the whole batch
+        let torn = batch.as_ref().map_or(offset, |(begin, _)| *begin);
+        match record::read_record(reader) {
+            Ok(Some(Record::Command(cmd, seq))) => {
+                *last_seq = (*last_seq).max(seq);
+                match batch.as_mut() {
+                    Some((_, cmds)) => cmds.push((cmd, seq, offset, reader.cursor)),
+                    None => apply_command(
+                        cmd,
+                        seq,
+                        file_id,
+                        offset,
+                        reader.cursor,
+                        index,
+                        inactive_data,
+                    ),
                 }
-                Command::Rm { key } => {
-                    if let Some(ind) = index.remove(&key) {
-                        inactive_data += ind.value().len;
+            }
+            // only snapshots of the process which wrote it read an old version
+            Ok(Some(Record::Superseded(_))) => *inactive_data += reader.cursor - offset,
+            Ok(Some(Record::BatchBegin)) if batch.is_none() => batch = Some((offset, Vec::new())),
+            Ok(Some(Record::BatchCommit)) if batch.is_some() => {
+                if let Some((_, cmds)) = batch.take() {
+                    for (cmd, seq, offset, end) in cmds {
+                        apply_command(cmd, seq, file_id, offset, end, index, inactive_data);
                     }
                 }
             }
-            offset = curr_offset;
+            // nested batches or a commit without batch are never written
+            Ok(Some(_)) => return Err(KvsError::Corruption { file_id, offset }.into()),
+            Ok(None) => return Ok(batch.map(|(begin, _)| begin)),
+            // a record running past the end of file is a torn write, unless
+            // a damaged length made it swallow the records after it
+            Err(RecordError::Truncated) if is_torn(reader, offset)? => return Ok(Some(torn)),
+            // a garbled record running up to the end of file is a torn write as well
+            Err(RecordError::Corrupted)
+                if reader.reader.fill_buf()?.is_empty() && is_torn(reader, offset)? =>
+            {
+                return Ok(Some(torn))
+            }
+            Err(err) => return Err(record_error(err, file_id, offset)),
+        }
+    }
+}
+
+// whether no intact record follows the unreadable one at `offset`,
+// the last write before a crash is the only one which can be torn
+fn is_torn(reader: &mut CursorBufferReader<LogFile>, offset: u64) -> Result<bool> {
+    reader.seek(SeekFrom::Start(offset + 1))?;
+    let mut rest = Vec::new();
+    reader.read_to_end(&mut rest)?;
+    Ok(!record::contains_record(&rest))
+}
+
+// replay a log file written as bare serde_json commands,
+// return the offset of the incomplete command at its tail if there is one
+fn load_legacy_log(
+    file_id: u64,
+    reader: &mut CursorBufferReader<LogFile>,
+    index: &mut SkipMap<Vec<u8>, IndexEntry>,
+    inactive_data: &mut u64,
+) -> Result<Option<u64>> {
+    let mut de_stream = serde_json::Deserializer::from_reader(reader).into_iter::<LegacyCommand>();
+    let mut offset = 0_u64;
+    while let Some(cmd) = de_stream.next() {
+        let curr_offset = de_stream.byte_offset() as u64;
+        match cmd {
+            Ok(cmd) => apply_command(
+                cmd.into(),
+                0,
+                file_id,
+                offset,
+                curr_offset,
+                index,
+                inactive_data,
+            ),
+            Err(err) if err.is_eof() => return Ok(Some(offset)),
+            Err(_) => return Err(KvsError::Corruption { file_id, offset }.into()),
+        }
+        offset = curr_offset;
+    }
+    Ok(None)
+}
+
+// the keys of two ordered iterators in order, each only once
+fn merge_keys(
+    left: impl Iterator<Item = Vec<u8>>,
+    right: impl Iterator<Item = Vec<u8>>,
+) -> impl Iterator<Item = Vec<u8>> {
+    let mut left = left.peekable();
+    let mut right = right.peekable();
+    std::iter::from_fn(move || {
+        let key = match (left.peek(), right.peek()) {
+            (Some(l), Some(r)) if l <= r => left.next()?,
+            (Some(_), Some(_)) | (None, Some(_)) => right.next()?,
+            (Some(_), None) => left.next()?,
+            (None, None) => return None,
+        };
+        while left.peek() == Some(&key) {
+            left.next();
+        }
+        while right.peek() == Some(&key) {
+            right.next();
+        }
+        Some(key)
+    })
+}
+
+// the index entries which have not expired, up to `limit` of them
+fn live_entries<'a>(
+    entries: impl Iterator<Item = map::Entry<'a, Vec<u8>, IndexEntry>>,
+    limit: Option<usize>,
+) -> Vec<(Vec<u8>, IndexEntry)> {
+    let now = record::now_millis();
+    entries
+        .filter(|entry| !entry.value().is_expired(now))
+        .take(limit.unwrap_or(usize::MAX))
+        .map(|entry| (entry.key().clone(), *entry.value()))
+        .collect()
+}
+
+// modify index to point to new data
+fn apply_command(
+    cmd: Command,
+    seq: u64,
+    file_id: u64,
+    offset: u64,
+    end: u64,
+    index: &mut SkipMap<Vec<u8>, IndexEntry>,
+    inactive_data: &mut u64,
+) {
+    match cmd {
+        Command::Set {
+            key, expires_at, ..
+        } => index_set(
+            key,
+            IndexEntry::new(file_id, offset, end, expires_at, seq),
+            index,
+            inactive_data,
+        ),
+        Command::Rm { key } => {
+            if let Some(ind) = index.remove(&key) {
+                *inactive_data += ind.value().len;
+            }
+        }
+    }
+}
+
+// an expired record is applied as a removal of the key
+fn index_set(
+    key: Vec<u8>,
+    entry: IndexEntry,
+    index: &mut SkipMap<Vec<u8>, IndexEntry>,
+    inactive_data: &mut u64,
+) {
+    if let Some(ind) = index.remove(&key) {
+        *inactive_data += ind.value().len;
+    }
+    if entry.is_expired(record::now_millis()) {
+        *inactive_data += entry.len;
+    } else {
+        index.insert(key, entry);
+    }
+}
+
+fn record_error(err: RecordError, file_id: u64, offset: u64) -> anyhow::Error {
+    match err {
+        RecordError::Io(err) => err.into(),
+        RecordError::Truncated | RecordError::Corrupted => {
+            KvsError::Corruption { file_id, offset }.into()
         }
-        readers.insert(file_id, reader);
     }
-    Ok(inactive_data)
 }
 
 // get all previously log files' ids to reconstruct index
 fn get_file_ids(path: &Path) -> Result<Vec<u64>> {
     // use flatten to unwarap Option or result
     let mut ids: Vec<u64> = fs::read_dir(path)?
-        .map(|res| res.map(|e| e.path()))
-        .flatten()
+        .flat_map(|res| res.map(|e| e.path()))
         .filter(|path| path.is_file() && path.extension() == Some("log".as_ref()))
-        .map(|path| {
+        .flat_map(|path| {
             path.file_name()
                 .and_then(OsStr::to_str)
                 .map(|name| name.trim_end_matches(".log"))
                 .map(str::parse::<u64>)
         })
         .flatten()
-        .flatten()
         .collect();
 
     ids.sort_unstable();
     Ok(ids)
 }
-#[derive(Debug, Serialize, Deserialize)]
-enum Command {
-    Set { key: String, value: String },
-    Rm { key: String },
-}
-
-impl Command {
-    fn set(key: String, value: String) -> Command {
-        Command::Set { key, value }
-    }
-
-    fn rm(key: String) -> Command {
-        Command::Rm { key }
-    }
-
-    fn key(self) -> String {
-        match self {
-            Command::Set { key, .. } => key,
-            Command::Rm { key, .. } => key,
-        }
-    }
-}
-
 // Corresponding to Bitcask keydir
+#[derive(Clone, Copy)]
 struct IndexEntry {
     file_id: u64,
     offset: u64,
     len: u64,
+    // milliseconds since the unix epoch
+    expires_at: Option<u64>,
+    // sequence number of the write
+    seq: u64,
 }
 impl IndexEntry {
-    fn new(file_id: u64, offset: u64, end: u64) -> IndexEntry {
+    fn new(file_id: u64, offset: u64, end: u64, expires_at: Option<u64>, seq: u64) -> IndexEntry {
         IndexEntry {
-            file_id: file_id,
-            offset: offset,
+            file_id,
+            offset,
             len: end - offset,
+            expires_at,
+            seq,
         }
     }
+
+    fn is_expired(&self, now: u64) -> bool {
+        matches!(self.expires_at, Some(expires_at) if expires_at <= now)
+    }
+}
+
+// superseded versions of keys open snapshots may read,
+// ordered by key and then from the newest to the oldest version
+type History = SkipMap<VersionKey, Version>;
+
+// a key with the sequence number of one of its versions
+type VersionKey = (Vec<u8>, Reverse<u64>);
+
+// a version of a key superseded by a later write
+#[derive(Clone, Copy)]
+struct Version {
+    entry: IndexEntry,
+    // sequence number of the superseding write
+    superseded_at: u64,
 }
 
 // serde_json recommand us to use buffer
@@ -408,11 +1897,11 @@ struct CursorBufferReader<T: Read + Seek> {
 
 impl<T: Read + Seek> CursorBufferReader<T> {
     // seek need mut
-    fn new(mut inner: T) -> Result<Self> {
-        let cursor = inner.seek(SeekFrom::Current(0))?;
+    fn with_capacity(capacity: usize, mut inner: T) -> Result<Self> {
+        let cursor = inner.stream_position()?;
         Ok(CursorBufferReader {
-            reader: BufReader::new(inner),
-            cursor: cursor,
+            reader: BufReader::with_capacity(capacity, inner),
+            cursor,
         })
     }
 }
@@ -440,14 +1929,39 @@ struct CursorBufferWriter<T: Write + Seek> {
 impl<T: Write + Seek> CursorBufferWriter<T> {
     // seek need mut
     fn new(mut inner: T) -> Result<Self> {
-        let cursor = inner.seek(SeekFrom::Current(0))?;
+        let cursor = inner.stream_position()?;
         Ok(CursorBufferWriter {
             writer: BufWriter::new(inner),
-            cursor: cursor,
+            cursor,
         })
     }
 }
 
+impl CursorBufferWriter<LogFile> {
+    // flush buffered data and fsync the file
+    fn sync(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()
+    }
+
+    // flush buffered data and get a handle to fsync the file with
+    fn sync_handle(&mut self) -> io::Result<LogFile> {
+        self.writer.flush()?;
+        self.writer.get_ref().try_clone()
+    }
+
+    // drop buffered data and cut the file back to `offset`
+    fn truncate(&mut self, offset: u64) -> io::Result<()> {
+        let file = self.writer.get_ref().try_clone()?;
+        // the old buffer is dropped without being flushed, even if the file
+        // can't be cut, or it would be written after the torn record
+        let (_, _) = std::mem::replace(&mut self.writer, BufWriter::new(file)).into_parts();
+        self.writer.get_ref().set_len(offset)?;
+        self.cursor = offset;
+        Ok(())
+    }
+}
+
 impl<T: Write + Seek> Write for CursorBufferWriter<T> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         let n = self.writer.write(buf)?;
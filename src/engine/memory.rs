@@ -0,0 +1,372 @@
+use super::batch::{BatchOp, WriteBatch};
+use super::create_checkpoint_dir;
+use super::expiry::Expiries;
+use super::record::{self, expiry_after, now_millis, Command, Record};
+use super::{ReadSet, ReadVersion};
+use crate::{KvsEngine, KvsError, Result};
+use async_trait::async_trait;
+use crossbeam_skiplist::{map, SkipMap};
+use log::error;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::runtime::{Handle, RuntimeFlavor};
+use tokio::task::block_in_place;
+
+/// File a `MemoryKvsEngine` snapshot is written to in its directory
+const SNAPSHOT_FILE: &str = "memory.snapshot";
+
+/// The `MemoryKvsEngine` keeps Key/Value pairs in memory only.
+///
+/// Nothing is written to the disk unless it's opened on a directory, or a
+/// checkpoint is made. Reads never block, writes are serialized.
+///
+/// Example:
+///
+/// ```rust
+/// # use anyhow::{Result, Context};
+/// use kvs::{KvsEngine, MemoryKvsEngine};
+/// # fn main() -> Result<()> {
+/// let rt = tokio::runtime::Runtime::new().context("failed to create tokio runtime")?;
+/// rt.block_on(async move {
+///     let store = MemoryKvsEngine::new();
+///     store.set("key".to_string(), "value".to_string()).await?;
+///     assert_eq!(store.get("key".to_string()).await?, Some("value".to_string()));
+///     Ok(())
+/// })
+/// # }
+///```
+#[derive(Clone, Default)]
+pub struct MemoryKvsEngine {
+    map: Arc<SkipMap<Vec<u8>, MemoryEntry>>,
+    // held by every write, so a write reading the map sees no other write
+    writes: Arc<Mutex<Writes>>,
+    // only held, so the snapshot is written when the last handle goes
+    _snapshot: Option<Arc<SnapshotOnDrop>>,
+}
+
+#[derive(Clone)]
+struct MemoryEntry {
+    value: Vec<u8>,
+    // milliseconds since the unix epoch
+    expires_at: Option<u64>,
+    // sequence number of the write
+    seq: u64,
+}
+
+#[derive(Default)]
+struct Writes {
+    // keys which expire, in the order they do
+    expiries: Expiries,
+    // sequence number of the last write
+    seq: u64,
+}
+
+impl MemoryEntry {
+    fn is_expired(&self, now: u64) -> bool {
+        matches!(self.expires_at, Some(expires_at) if expires_at <= now)
+    }
+}
+
+/// Writes a snapshot once the last `MemoryKvsEngine` handle is dropped
+struct SnapshotOnDrop {
+    map: Arc<SkipMap<Vec<u8>, MemoryEntry>>,
+    dir: PathBuf,
+}
+
+impl Drop for SnapshotOnDrop {
+    fn drop(&mut self) {
+        // the last handle may be dropped on a worker of the runtime, which
+        // hands its other tasks on while this one writes
+        let write = || write_snapshot(live_entries(&self.map), &self.dir);
+        let res = match Handle::try_current() {
+            Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => {
+                block_in_place(write)
+            }
+            _ => write(),
+        };
+        if let Err(err) = res {
+            error!("failed to write memory snapshot: {}", err);
+        }
+    }
+}
+
+impl MemoryKvsEngine {
+    /// An empty `MemoryKvsEngine`
+    pub fn new() -> MemoryKvsEngine {
+        MemoryKvsEngine::default()
+    }
+
+    /// Load the snapshot in `dir` if there is one, a new snapshot is
+    /// written there once the last handle is dropped.
+    ///
+    /// A checkpoint of a `MemoryKvsEngine` is opened this way.
+    pub fn open(dir: impl Into<PathBuf>) -> Result<MemoryKvsEngine> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        let map = Arc::new(SkipMap::new());
+        let mut writes = Writes::default();
+        let path = dir.join(SNAPSHOT_FILE);
+        if path.exists() {
+            let mut reader = BufReader::new(File::open(path)?);
+            let now = now_millis();
+            loop {
+                match record::read_record(&mut reader) {
+                    Ok(Some(Record::Command(
+                        Command::Set {
+                            key,
+                            value,
+                            expires_at,
+                        },
+                        _,
+                    ))) => {
+                        writes.seq += 1;
+                        let entry = MemoryEntry {
+                            value,
+                            expires_at,
+                            seq: writes.seq,
+                        };
+                        if !entry.is_expired(now) {
+                            writes.expiries.update(&key, None, expires_at);
+                            map.insert(key, entry);
+                        }
+                    }
+                    Ok(None) => break,
+                    _ => {
+                        return Err(
+                            KvsError::OtherError("corrupted memory snapshot".to_owned()).into()
+                        )
+                    }
+                }
+            }
+        }
+        Ok(MemoryKvsEngine {
+            map: map.clone(),
+            writes: Arc::new(Mutex::new(writes)),
+            _snapshot: Some(Arc::new(SnapshotOnDrop { map, dir })),
+        })
+    }
+
+    // the entry of a key unless it's absent or expired
+    fn live_entry(&self, key: &[u8]) -> Option<MemoryEntry> {
+        let entry = self.map.get(key)?.value().clone();
+        if entry.is_expired(now_millis()) {
+            None
+        } else {
+            Some(entry)
+        }
+    }
+
+    fn live_value(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.live_entry(key).map(|entry| entry.value)
+    }
+
+    fn insert(&self, writes: &mut Writes, key: Vec<u8>, value: Vec<u8>, expires_at: Option<u64>) {
+        let old = self.map.get(&key).and_then(|old| old.value().expires_at);
+        writes.expiries.update(&key, old, expires_at);
+        writes.seq += 1;
+        let entry = MemoryEntry {
+            value,
+            expires_at,
+            seq: writes.seq,
+        };
+        self.map.insert(key, entry);
+    }
+
+    fn remove(&self, writes: &mut Writes, key: &[u8]) {
+        if let Some(old) = self.map.remove(key) {
+            writes.expiries.update(key, old.value().expires_at, None);
+        }
+    }
+
+    fn apply(&self, writes: &mut Writes, batch: WriteBatch) {
+        for op in batch.into_ops() {
+            match op {
+                BatchOp::Set { key, value } => self.insert(writes, key, value, None),
+                BatchOp::Remove { key } => self.remove(writes, &key),
+            }
+        }
+    }
+
+    fn collect_pairs<'a>(
+        entries: impl Iterator<Item = map::Entry<'a, Vec<u8>, MemoryEntry>>,
+        limit: Option<usize>,
+    ) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let now = now_millis();
+        entries
+            .filter(|entry| !entry.value().is_expired(now))
+            .take(limit.unwrap_or(usize::MAX))
+            .map(|entry| (entry.key().clone(), entry.value().value.clone()))
+            .collect()
+    }
+}
+
+#[async_trait]
+impl KvsEngine for MemoryKvsEngine {
+    async fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        let mut writes = self.writes.lock().unwrap();
+        self.insert(&mut writes, key, value, None);
+        Ok(())
+    }
+
+    async fn set_with_ttl_bytes(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let mut writes = self.writes.lock().unwrap();
+        self.insert(&mut writes, key, value, Some(expiry_after(ttl)));
+        Ok(())
+    }
+
+    async fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        Ok(self.live_value(&key))
+    }
+
+    async fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        let mut writes = self.writes.lock().unwrap();
+        self.live_entry(&key).ok_or(KvsError::KeyNotFound)?;
+        self.remove(&mut writes, &key);
+        Ok(())
+    }
+
+    async fn ttl_bytes(&self, key: Vec<u8>) -> Result<Option<Duration>> {
+        let entry = self.live_entry(&key).ok_or(KvsError::KeyNotFound)?;
+        Ok(entry
+            .expires_at
+            .map(|expires_at| Duration::from_millis(expires_at.saturating_sub(now_millis()))))
+    }
+
+    async fn persist_bytes(&self, key: Vec<u8>) -> Result<()> {
+        let mut writes = self.writes.lock().unwrap();
+        let entry = self.live_entry(&key).ok_or(KvsError::KeyNotFound)?;
+        self.insert(&mut writes, key, entry.value, None);
+        Ok(())
+    }
+
+    /// Drop all expired keys, visiting only the keys which are due.
+    async fn remove_expired(&self) -> Result<usize> {
+        let mut writes = self.writes.lock().unwrap();
+        let now = now_millis();
+        let mut removed = 0;
+        for key in writes.expiries.take_due(now) {
+            if let Some(entry) = self.map.get(&key) {
+                if entry.value().is_expired(now) {
+                    entry.remove();
+                    removed += 1;
+                }
+            }
+        }
+        Ok(removed)
+    }
+
+    async fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        let mut writes = self.writes.lock().unwrap();
+        self.apply(&mut writes, batch);
+        Ok(())
+    }
+
+    async fn get_versioned_bytes(&self, key: Vec<u8>) -> Result<ReadVersion> {
+        let entry = self.live_entry(&key);
+        Ok(ReadVersion {
+            seq: entry.as_ref().map(|entry| entry.seq),
+            value: entry.map(|entry| entry.value),
+        })
+    }
+
+    async fn commit_transaction(&self, reads: ReadSet, batch: WriteBatch) -> Result<()> {
+        let mut writes = self.writes.lock().unwrap();
+        // sequence numbers are compared rather than values, so a value
+        // written again is a conflict even if it's the same
+        for (key, read) in reads {
+            if self.live_entry(&key).map(|entry| entry.seq) != read.seq {
+                return Err(KvsError::Conflict.into());
+            }
+        }
+        self.apply(&mut writes, batch);
+        Ok(())
+    }
+
+    async fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+        ttl: Option<Duration>,
+    ) -> Result<()> {
+        let mut writes = self.writes.lock().unwrap();
+        let current = self.live_value(&key);
+        if current != expected {
+            return Err(KvsError::CasMismatch { current }.into());
+        }
+        match new {
+            Some(value) => self.insert(&mut writes, key, value, ttl.map(expiry_after)),
+            None => self.remove(&mut writes, &key),
+        }
+        Ok(())
+    }
+
+    async fn scan_bytes<R>(&self, range: R, limit: Option<usize>) -> Result<Vec<(Vec<u8>, Vec<u8>)>>
+    where
+        R: RangeBounds<Vec<u8>> + Send + 'static,
+    {
+        Ok(MemoryKvsEngine::collect_pairs(self.map.range(range), limit))
+    }
+
+    async fn scan_prefix_bytes(
+        &self,
+        prefix: Vec<u8>,
+        limit: Option<usize>,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let entries = self
+            .map
+            .range::<[u8], _>((Bound::Included(prefix.as_slice()), Bound::Unbounded))
+            .take_while(|entry| entry.key().starts_with(&prefix));
+        Ok(MemoryKvsEngine::collect_pairs(entries, limit))
+    }
+
+    /// Write a snapshot to `dest`, which `MemoryKvsEngine::open` loads.
+    ///
+    /// Writes wait only while the pairs are copied, not while they're written
+    /// out, reads go on meanwhile.
+    async fn checkpoint(&self, dest: PathBuf) -> Result<()> {
+        block_in_place(move || {
+            create_checkpoint_dir(&dest)?;
+            let entries = {
+                let _writes = self.writes.lock().unwrap();
+                live_entries(&self.map)
+            };
+            write_snapshot(entries, &dest)
+        })
+    }
+
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+}
+
+// write the pairs as records, to a temporary file first so a crash
+// never leaves a partial snapshot behind
+fn write_snapshot(
+    entries: impl IntoIterator<Item = (Vec<u8>, MemoryEntry)>,
+    dir: &Path,
+) -> Result<()> {
+    let tmp_path = dir.join(format!("{}.tmp", SNAPSHOT_FILE));
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    for (key, entry) in entries {
+        let command = Command::set(key, entry.value, entry.expires_at);
+        writer.write_all(&command.encode(0))?;
+    }
+    let file = writer.into_inner().map_err(|err| err.into_error())?;
+    file.sync_all()?;
+    fs::rename(tmp_path, dir.join(SNAPSHOT_FILE))?;
+    Ok(())
+}
+
+// a copy of every live pair, so writing them out holds nothing
+fn live_entries(map: &SkipMap<Vec<u8>, MemoryEntry>) -> Vec<(Vec<u8>, MemoryEntry)> {
+    let now = now_millis();
+    map.iter()
+        .filter(|entry| !entry.value().is_expired(now))
+        .map(|entry| (entry.key().clone(), entry.value().clone()))
+        .collect()
+}
@@ -1,28 +1,309 @@
 //! Provide different engines for our k/v store
-use crate::Result;
+use crate::{KvsError, Result};
 use async_trait::async_trait;
+use std::fs;
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
 
 /// trait for k/v store engin
+///
+/// Keys and values are arbitrary bytes, the `String` methods are
+/// conveniences over the `_bytes` ones.
 #[async_trait]
-pub trait KvsEngine: Clone + Send + 'static {
-    /// Sets the string value of a given string key.
+pub trait KvsEngine: Clone + Send + Sync + 'static {
+    /// Sets the value of a given key.
     ///
     /// If the given key already exists, the previous value will be overwitten.
-    async fn set(&self, key: String, value: String) -> Result<()>;
+    async fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()>;
 
-    /// Get the string value of a given string key.
+    /// Get the value of a given key.
     ///
     /// Returns `None` if the given key does not exist.
-    async fn get(&self, key: String) -> Result<Option<String>>;
+    async fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>>;
 
-    /// Remove a given string key.
+    /// Sets the value of a given key, which expires after `ttl`.
+    ///
+    /// Expired keys are treated as absent.
+    async fn set_with_ttl_bytes(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()>;
+
+    /// Remove a given key.
+    ///
+    /// # Errors
+    ///
+    /// Returns `KvsError::KeyNotFound` if the given ket does not exixt.
+    async fn remove_bytes(&self, key: Vec<u8>) -> Result<()>;
+
+    /// Get how long a given key lives, `None` if it never expires.
     ///
     /// # Errors
     ///
     /// Returns `KvsError::KeyNotFound` if the given ket does not exixt.
-    async fn remove(&self, key: String) -> Result<()>;
+    async fn ttl_bytes(&self, key: Vec<u8>) -> Result<Option<Duration>>;
+
+    /// Clear the expiry of a given key.
+    ///
+    /// # Errors
+    ///
+    /// Returns `KvsError::KeyNotFound` if the given ket does not exixt.
+    async fn persist_bytes(&self, key: Vec<u8>) -> Result<()>;
+
+    /// Remove keys which have expired, return how many were removed.
+    async fn remove_expired(&self) -> Result<usize>;
+
+    /// Apply all writes of a `WriteBatch`, or none of them.
+    ///
+    /// Values set by a batch never expire.
+    async fn write_batch(&self, batch: WriteBatch) -> Result<()>;
+
+    /// Start an optimistic `Transaction`
+    fn begin(&self) -> Transaction<Self> {
+        Transaction::new(self.clone())
+    }
+
+    /// Get the value of a given key along with the sequence number of its
+    /// write, which `commit_transaction` checks.
+    ///
+    /// Engines which don't number their writes return no sequence number,
+    /// their transactions compare values instead.
+    async fn get_versioned_bytes(&self, key: Vec<u8>) -> Result<ReadVersion> {
+        Ok(ReadVersion {
+            value: self.get_bytes(key).await?,
+            seq: None,
+        })
+    }
+
+    /// Apply `writes` atomically if no key in `reads` has been written since
+    /// it was read by `get_versioned_bytes`.
+    ///
+    /// This is what `Transaction::commit` runs.
+    ///
+    /// # Errors
+    ///
+    /// Returns `KvsError::Conflict` if a key has changed, nothing is written then.
+    async fn commit_transaction(&self, reads: ReadSet, writes: WriteBatch) -> Result<()>;
+
+    /// Set a given key to `new` only if its value is `expected`, atomically.
+    ///
+    /// `None` stands for an absent key, so `expected` of `None` only creates
+    /// the key and `new` of `None` removes it. The new value expires after
+    /// `ttl` if it's given and never otherwise, like one set by `set`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `KvsError::CasMismatch` with the current value if it's not `expected`.
+    async fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+        ttl: Option<Duration>,
+    ) -> Result<()>;
+
+    /// Get key/value pairs whose keys are in `range`, in the order of keys.
+    ///
+    /// At most `limit` pairs are returned if it's given.
+    async fn scan_bytes<R>(
+        &self,
+        range: R,
+        limit: Option<usize>,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>>
+    where
+        R: RangeBounds<Vec<u8>> + Send + 'static;
+
+    /// Get key/value pairs whose keys start with `prefix`, in the order of keys.
+    ///
+    /// At most `limit` pairs are returned if it's given.
+    async fn scan_prefix_bytes(
+        &self,
+        prefix: Vec<u8>,
+        limit: Option<usize>,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>>;
+
+    /// Write a consistent copy of the store to `dest`, while it keeps serving requests.
+    ///
+    /// The copy can be opened by the same engine. `dest` is created if it
+    /// doesn't exist, and must be empty otherwise.
+    ///
+    /// Engines which can't copy themselves return an error by default.
+    async fn checkpoint(&self, _dest: PathBuf) -> Result<()> {
+        Err(KvsError::OtherError("checkpoint not supported".to_owned()).into())
+    }
+
+    /// Name of the engine, which a `KvsServer` tells its clients.
+    fn name(&self) -> &'static str {
+        "custom"
+    }
+
+    /// Sets the string value of a given string key.
+    async fn set(&self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes()).await
+    }
+
+    /// Get the string value of a given string key.
+    ///
+    /// # Errors
+    ///
+    /// Returns `KvsError::Utf8` if the value is not valid UTF-8.
+    async fn get(&self, key: String) -> Result<Option<String>> {
+        match self.get_bytes(key.into_bytes()).await? {
+            Some(value) => Ok(Some(into_string(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Sets the string value of a given string key, which expires after `ttl`.
+    async fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.set_with_ttl_bytes(key.into_bytes(), value.into_bytes(), ttl)
+            .await
+    }
+
+    /// Remove a given string key.
+    async fn remove(&self, key: String) -> Result<()> {
+        self.remove_bytes(key.into_bytes()).await
+    }
+
+    /// Get how long a given string key lives, `None` if it never expires.
+    async fn ttl(&self, key: String) -> Result<Option<Duration>> {
+        self.ttl_bytes(key.into_bytes()).await
+    }
+
+    /// Clear the expiry of a given string key.
+    async fn persist(&self, key: String) -> Result<()> {
+        self.persist_bytes(key.into_bytes()).await
+    }
+
+    /// Set a given string key to `new` only if its value is `expected`, atomically.
+    async fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<()> {
+        self.compare_and_swap_bytes(
+            key.into_bytes(),
+            expected.map(String::into_bytes),
+            new.map(String::into_bytes),
+            None,
+        )
+        .await
+    }
+
+    /// Get string key/value pairs whose keys are in `range`, in the order of keys.
+    async fn scan<R>(&self, range: R, limit: Option<usize>) -> Result<Vec<(String, String)>>
+    where
+        R: RangeBounds<String> + Send + 'static,
+    {
+        let range = (
+            bytes_bound(range.start_bound()),
+            bytes_bound(range.end_bound()),
+        );
+        into_string_pairs(self.scan_bytes(range, limit).await?)
+    }
+
+    /// Get string key/value pairs whose keys start with `prefix`, in the order of keys.
+    async fn scan_prefix(
+        &self,
+        prefix: String,
+        limit: Option<usize>,
+    ) -> Result<Vec<(String, String)>> {
+        into_string_pairs(self.scan_prefix_bytes(prefix.into_bytes(), limit).await?)
+    }
+}
+
+// UTF-8 orders the same as its bytes, so a string range maps to a byte range
+pub(crate) fn bytes_bound(bound: Bound<&String>) -> Bound<Vec<u8>> {
+    match bound {
+        Bound::Included(key) => Bound::Included(key.clone().into_bytes()),
+        Bound::Excluded(key) => Bound::Excluded(key.clone().into_bytes()),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+pub(crate) fn into_string(bytes: Vec<u8>) -> Result<String> {
+    Ok(String::from_utf8(bytes).map_err(KvsError::Utf8)?)
+}
+
+pub(crate) fn into_string_pairs(pairs: Vec<(Vec<u8>, Vec<u8>)>) -> Result<Vec<(String, String)>> {
+    pairs
+        .into_iter()
+        .map(|(key, value)| Ok((into_string(key)?, into_string(value)?)))
+        .collect()
+}
+
+// create the directory of a checkpoint, refusing to mix it with other files
+pub(crate) fn create_checkpoint_dir(dest: &Path) -> Result<()> {
+    if dest.exists() && fs::read_dir(dest)?.next().is_some() {
+        return Err(KvsError::OtherError(format!(
+            "checkpoint directory {} is not empty",
+            dest.display()
+        ))
+        .into());
+    }
+    fs::create_dir_all(dest)?;
+    Ok(())
+}
+
+/// When an engine forces written data down to the disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// fsync before acknowledging every write
+    Always,
+    /// fsync in the background at the given interval
+    Interval(Duration),
+    /// leave it to the OS
+    Never,
 }
+
+impl FromStr for SyncPolicy {
+    type Err = KvsError;
+
+    /// Parse `always`, `never` or an interval in milliseconds like `100ms`
+    fn from_str(s: &str) -> std::result::Result<SyncPolicy, KvsError> {
+        match s {
+            "always" => Ok(SyncPolicy::Always),
+            "never" => Ok(SyncPolicy::Never),
+            _ => s
+                .strip_suffix("ms")
+                .and_then(|ms| ms.parse::<u64>().ok())
+                .filter(|&ms| ms > 0)
+                .map(|ms| SyncPolicy::Interval(Duration::from_millis(ms)))
+                .ok_or_else(|| KvsError::OtherError(format!("invalid sync policy {}", s))),
+        }
+    }
+}
+
+/// Options used when opening an engine
+#[derive(Debug, Clone, Copy, Default)]
+pub struct EngineOptions {
+    sync_policy: Option<SyncPolicy>,
+}
+
+impl EngineOptions {
+    /// Set the `SyncPolicy` of writes.
+    ///
+    /// `KvStore` never syncs and `SledKvsEngine` syncs every write by default.
+    pub fn sync_policy(mut self, sync_policy: SyncPolicy) -> EngineOptions {
+        self.sync_policy = Some(sync_policy);
+        self
+    }
+}
+
 pub use self::sled::SledKvsEngine;
-pub use kv::KvStore;
+pub use batch::WriteBatch;
+#[cfg(feature = "fault-injection")]
+pub use file::FaultInjector;
+pub use kv::{KvStore, KvStoreOptions, Snapshot};
+pub use memory::MemoryKvsEngine;
+pub(crate) use record::now_millis;
+pub use transaction::{ReadSet, ReadVersion, Transaction};
+mod batch;
+mod expiry;
+mod file;
+mod hint;
 mod kv;
+mod memory;
+mod record;
 mod sled;
+mod transaction;
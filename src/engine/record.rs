@@ -0,0 +1,306 @@
+//! Binary record layout of `KvStore` log files.
+//!
+//! Every record is a fixed size header followed by the key and the value:
+//!
+//! ```text
+//! | magic (2) | crc32 (4) | version (1) | kind (1) | timestamp (8) | key_len (4) | value_len (4) | expires_at (8) | seq (8) | key | value |
+//! ```
+//!
+//! All integers are little endian. The crc32 covers every byte after itself,
+//! so a flipped bit in the header, the key or the value is detected.
+//! `expires_at` is in milliseconds since the unix epoch, 0 for no expiry.
+//! `seq` is the sequence number of the write, snapshots see the writes up
+//! to theirs. Version 1 records have neither `expires_at` nor `seq` and
+//! never expire, version 2 records have no `seq`, both are read with seq 0.
+//!
+//! The records of a write batch are put between a batch begin and a batch
+//! commit record, which have neither key nor value. A batch without its
+//! commit record is not applied.
+//! Compaction keeps old versions which open snapshots still read as
+//! superseded records, they are skipped when the log is replayed.
+//! Log files written before this layout existed hold bare serde_json
+//! commands, they are still readable and are rewritten by compaction.
+use crc32fast::Hasher;
+use serde::Deserialize;
+use std::convert::TryInto;
+use std::io::{self, Read};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Magic bytes at the start of every binary record
+const MAGIC: [u8; 2] = *b"KV";
+/// Current version of the record layout
+const VERSION: u8 = 3;
+/// Size of the record header shared by all versions
+const HEADER_SIZE: usize = 24;
+/// Size of the `expires_at` field since version 2
+const EXPIRY_SIZE: usize = 8;
+/// Size of the `seq` field since version 3
+const SEQ_SIZE: usize = 8;
+
+const KIND_SET: u8 = 0;
+const KIND_RM: u8 = 1;
+const KIND_BATCH_BEGIN: u8 = 2;
+const KIND_BATCH_COMMIT: u8 = 3;
+const KIND_SUPERSEDED: u8 = 4;
+
+#[derive(Debug)]
+pub(super) enum Command {
+    Set {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        /// milliseconds since the unix epoch
+        expires_at: Option<u64>,
+    },
+    Rm {
+        key: Vec<u8>,
+    },
+}
+
+/// What a record in the log holds
+#[derive(Debug)]
+pub(super) enum Record {
+    /// A command with the sequence number of the write
+    Command(Command, u64),
+    /// An old value kept for open snapshots, it's not replayed
+    Superseded(Command),
+    BatchBegin,
+    BatchCommit,
+}
+
+/// Command of legacy serde_json logs, which only held strings
+#[derive(Deserialize)]
+pub(super) enum LegacyCommand {
+    Set { key: String, value: String },
+    Rm { key: String },
+}
+
+impl From<LegacyCommand> for Command {
+    fn from(cmd: LegacyCommand) -> Command {
+        match cmd {
+            LegacyCommand::Set { key, value } => {
+                Command::set(key.into_bytes(), value.into_bytes(), None)
+            }
+            LegacyCommand::Rm { key } => Command::rm(key.into_bytes()),
+        }
+    }
+}
+
+/// Why a record could not be decoded
+#[derive(Debug)]
+pub(super) enum RecordError {
+    Io(io::Error),
+    /// The record ends before its header says it should
+    Truncated,
+    /// Bad magic, unknown version, checksum mismatch or malformed content
+    Corrupted,
+}
+
+impl From<io::Error> for RecordError {
+    fn from(err: io::Error) -> RecordError {
+        RecordError::Io(err)
+    }
+}
+
+impl Command {
+    pub(super) fn set(key: Vec<u8>, value: Vec<u8>, expires_at: Option<u64>) -> Command {
+        Command::Set {
+            key,
+            value,
+            expires_at,
+        }
+    }
+
+    pub(super) fn rm(key: Vec<u8>) -> Command {
+        Command::Rm { key }
+    }
+
+    pub(super) fn key(self) -> Vec<u8> {
+        match self {
+            Command::Set { key, .. } => key,
+            Command::Rm { key, .. } => key,
+        }
+    }
+
+    /// Encode the command as a binary record stamped with the current time
+    pub(super) fn encode(&self, seq: u64) -> Vec<u8> {
+        match self {
+            Command::Set {
+                key,
+                value,
+                expires_at,
+            } => encode_record(KIND_SET, key, value, *expires_at, seq),
+            Command::Rm { key } => encode_record(KIND_RM, key, &[], None, seq),
+        }
+    }
+
+    /// Encode a set command as a superseded record, which is not replayed
+    pub(super) fn encode_superseded(&self, seq: u64) -> Vec<u8> {
+        match self {
+            Command::Set {
+                key,
+                value,
+                expires_at,
+            } => encode_record(KIND_SUPERSEDED, key, value, *expires_at, seq),
+            Command::Rm { .. } => panic!("only set commands are superseded"),
+        }
+    }
+
+    /// Decode a whole record, either binary or legacy serde_json
+    pub(super) fn decode(buf: &[u8]) -> Result<Command, RecordError> {
+        if is_legacy(buf) {
+            return serde_json::from_slice::<LegacyCommand>(buf)
+                .map(Command::from)
+                .map_err(|_| RecordError::Corrupted);
+        }
+        let mut reader = buf;
+        match read_record(&mut reader)? {
+            Some(Record::Command(cmd, _)) | Some(Record::Superseded(cmd)) if reader.is_empty() => {
+                Ok(cmd)
+            }
+            Some(_) => Err(RecordError::Corrupted),
+            None => Err(RecordError::Truncated),
+        }
+    }
+}
+
+/// Encode the record starting a write batch
+pub(super) fn encode_batch_begin() -> Vec<u8> {
+    encode_record(KIND_BATCH_BEGIN, &[], &[], None, 0)
+}
+
+/// Encode the record committing a write batch
+pub(super) fn encode_batch_commit() -> Vec<u8> {
+    encode_record(KIND_BATCH_COMMIT, &[], &[], None, 0)
+}
+
+// encode a binary record stamped with the current time
+fn encode_record(kind: u8, key: &[u8], value: &[u8], expires_at: Option<u64>, seq: u64) -> Vec<u8> {
+    let mut buf =
+        Vec::with_capacity(HEADER_SIZE + EXPIRY_SIZE + SEQ_SIZE + key.len() + value.len());
+    buf.extend_from_slice(&MAGIC);
+    // placeholder of crc
+    buf.extend_from_slice(&[0; 4]);
+    buf.push(VERSION);
+    buf.push(kind);
+    buf.extend_from_slice(&now_millis().to_le_bytes());
+    buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&(value.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&expires_at.unwrap_or(0).to_le_bytes());
+    buf.extend_from_slice(&seq.to_le_bytes());
+    buf.extend_from_slice(key);
+    buf.extend_from_slice(value);
+    let crc = checksum(&buf[6..]);
+    buf[2..6].copy_from_slice(&crc.to_le_bytes());
+    buf
+}
+
+/// Legacy records are serde_json objects, binary ones start with `MAGIC`
+pub(super) fn is_legacy(buf: &[u8]) -> bool {
+    buf.first() == Some(&b'{')
+}
+
+/// Read the next binary record from `reader`.
+///
+/// Returns `None` if `reader` is at its end before the record starts.
+pub(super) fn read_record<R: Read>(reader: &mut R) -> Result<Option<Record>, RecordError> {
+    let mut header = [0_u8; HEADER_SIZE];
+    let n = read_full(reader, &mut header)?;
+    if n == 0 {
+        return Ok(None);
+    }
+    if n < HEADER_SIZE {
+        return Err(RecordError::Truncated);
+    }
+    if header[0..2] != MAGIC || !(1..=VERSION).contains(&header[6]) {
+        return Err(RecordError::Corrupted);
+    }
+    // fields appended by later versions of the layout
+    let extra_len = match header[6] {
+        1 => 0,
+        2 => EXPIRY_SIZE,
+        _ => EXPIRY_SIZE + SEQ_SIZE,
+    };
+    let mut extra = [0_u8; EXPIRY_SIZE + SEQ_SIZE];
+    if read_full(reader, &mut extra[..extra_len])? < extra_len {
+        return Err(RecordError::Truncated);
+    }
+    let crc = u32::from_le_bytes(header[2..6].try_into().unwrap());
+    let kind = header[7];
+    let key_len = u32::from_le_bytes(header[16..20].try_into().unwrap()) as u64;
+    let value_len = u32::from_le_bytes(header[20..24].try_into().unwrap()) as u64;
+
+    // read through `take` so a corrupted length can't make us allocate
+    // more than the file actually holds
+    let mut body = Vec::new();
+    reader.take(key_len + value_len).read_to_end(&mut body)?;
+    if (body.len() as u64) < key_len + value_len {
+        return Err(RecordError::Truncated);
+    }
+
+    let mut hasher = Hasher::new();
+    hasher.update(&header[6..]);
+    hasher.update(&extra[..extra_len]);
+    hasher.update(&body);
+    if hasher.finalize() != crc {
+        return Err(RecordError::Corrupted);
+    }
+
+    let value = body.split_off(key_len as usize);
+    let key = body;
+    let expires_at = Some(u64::from_le_bytes(extra[..8].try_into().unwrap())).filter(|&t| t != 0);
+    let seq = u64::from_le_bytes(extra[8..].try_into().unwrap());
+    match kind {
+        KIND_SET => Ok(Some(Record::Command(
+            Command::set(key, value, expires_at),
+            seq,
+        ))),
+        KIND_RM => Ok(Some(Record::Command(Command::Rm { key }, seq))),
+        KIND_SUPERSEDED => Ok(Some(Record::Superseded(Command::set(
+            key, value, expires_at,
+        )))),
+        KIND_BATCH_BEGIN => Ok(Some(Record::BatchBegin)),
+        KIND_BATCH_COMMIT => Ok(Some(Record::BatchCommit)),
+        _ => Err(RecordError::Corrupted),
+    }
+}
+
+/// Whether an intact record starts anywhere in `buf`
+pub(super) fn contains_record(buf: &[u8]) -> bool {
+    buf.windows(MAGIC.len())
+        .enumerate()
+        .filter(|(_, window)| *window == MAGIC)
+        .any(|(pos, _)| matches!(read_record(&mut &buf[pos..]), Ok(Some(_))))
+}
+
+/// Milliseconds since the unix epoch
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// When a key set now with `ttl` expires
+pub(super) fn expiry_after(ttl: Duration) -> u64 {
+    now_millis().saturating_add(ttl.as_millis() as u64)
+}
+
+fn checksum(buf: &[u8]) -> u32 {
+    let mut hasher = Hasher::new();
+    hasher.update(buf);
+    hasher.finalize()
+}
+
+// like `read_exact`, but reports how many bytes were read before EOF
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match reader.read(&mut buf[n..]) {
+            Ok(0) => break,
+            Ok(m) => n += m,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(n)
+}
@@ -1,10 +1,30 @@
+use super::batch::{BatchOp, WriteBatch};
+use super::record::{expiry_after, now_millis};
+use super::{create_checkpoint_dir, EngineOptions, ReadSet, SyncPolicy};
 use crate::Result;
 use crate::{KvsEngine, KvsError};
 use async_trait::async_trait;
-use sled;
+use sled::transaction::{
+    ConflictableTransactionError, ConflictableTransactionResult, TransactionError,
+    TransactionalTree,
+};
+use sled::Transactional;
+use std::collections::BTreeSet;
+use std::convert::TryInto;
+use std::ops::RangeBounds;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::{Arc, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
 use tokio::task::block_in_place;
 
+// tree holding the expiry of keys, apart from the data in the default tree
+const EXPIRY_TREE: &str = "expiry";
+// how long a checkpoint's watcher waits for an event before it checks if it's stopped
+const WATCH_INTERVAL: Duration = Duration::from_millis(10);
+
 /// The `SledKvsEngine` is used to store Key/Value pairs based on `sled`.
 /// Example:
 ///
@@ -29,48 +49,432 @@ use tokio::task::block_in_place;
 #[derive(Clone)]
 pub struct SledKvsEngine {
     db: sled::Db,
+    // expiry of keys in milliseconds since the unix epoch, big endian
+    expiry: sled::Tree,
+    sync_policy: SyncPolicy,
+    // held shared by writes and exclusively by checkpoints at their end
+    writes: Arc<RwLock<()>>,
 }
 
 impl SledKvsEngine {
     /// create a new `SledKvsEngine` engine
     pub fn open(path: impl Into<PathBuf>) -> Result<SledKvsEngine> {
+        SledKvsEngine::open_with_options(path, EngineOptions::default())
+    }
+
+    /// create a new `SledKvsEngine` engine with `EngineOptions`.
+    ///
+    /// Every write is synced unless another `SyncPolicy` is given.
+    pub fn open_with_options(
+        path: impl Into<PathBuf>,
+        options: EngineOptions,
+    ) -> Result<SledKvsEngine> {
+        let sync_policy = options.sync_policy.unwrap_or(SyncPolicy::Always);
+        let flush_every_ms = match sync_policy {
+            // keep sled's own background flush, writes are flushed anyway
+            SyncPolicy::Always => Some(500),
+            SyncPolicy::Interval(interval) => Some(interval.as_millis() as u64),
+            SyncPolicy::Never => None,
+        };
+        let db = sled::Config::new()
+            .path(path.into())
+            .flush_every_ms(flush_every_ms)
+            .open()?;
+        let expiry = db.open_tree(EXPIRY_TREE)?;
         Ok(SledKvsEngine {
-            db: sled::open(path.into())?,
+            db,
+            expiry,
+            sync_policy,
+            writes: Arc::new(RwLock::new(())),
         })
     }
+
+    fn flush(&self) -> Result<()> {
+        if self.sync_policy == SyncPolicy::Always {
+            self.db.flush()?;
+        }
+        Ok(())
+    }
+
+    // write a value together with its expiry
+    fn insert(&self, key: Vec<u8>, value: Vec<u8>, expires_at: Option<u64>) -> Result<()> {
+        let _writes = self.writes.read().unwrap();
+        let data: &sled::Tree = &self.db;
+        (data, &self.expiry)
+            .transaction(|(data, expiry)| {
+                data.insert(key.as_slice(), value.as_slice())?;
+                match expires_at {
+                    Some(expires_at) => expiry.insert(key.as_slice(), &expires_at.to_be_bytes())?,
+                    None => expiry.remove(key.as_slice())?,
+                };
+                Ok(())
+            })
+            .map_err(transaction_error)?;
+        self.flush()
+    }
+
+    // remove an expired key unless it has been set again meanwhile
+    fn remove_if_unchanged(&self, key: &[u8], expires_at: &sled::IVec) -> Result<bool> {
+        let _writes = self.writes.read().unwrap();
+        let data: &sled::Tree = &self.db;
+        (data, &self.expiry)
+            .transaction(|(data, expiry)| {
+                if expiry.get(key)?.as_ref() != Some(expires_at) {
+                    return Ok(false);
+                }
+                expiry.remove(key)?;
+                data.remove(key)?;
+                Ok(true)
+            })
+            .map_err(transaction_error)
+    }
+
+    fn expires_at(&self, key: &[u8]) -> Result<Option<u64>> {
+        Ok(self.expiry.get(key)?.map(|ivec| decode_expiry(&ivec)))
+    }
+
+    fn is_expired(&self, key: &[u8], now: u64) -> Result<bool> {
+        Ok(matches!(self.expires_at(key)?, Some(expires_at) if expires_at <= now))
+    }
+
+    fn collect_pairs(
+        &self,
+        iter: sled::Iter,
+        limit: Option<usize>,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let now = now_millis();
+        let mut pairs = Vec::new();
+        for pair in iter {
+            if pairs.len() >= limit.unwrap_or(usize::MAX) {
+                break;
+            }
+            let (key, value) = pair?;
+            if !self.is_expired(&key, now)? {
+                pairs.push((key.to_vec(), value.to_vec()));
+            }
+        }
+        Ok(pairs)
+    }
 }
 
 #[async_trait]
 impl KvsEngine for SledKvsEngine {
-    async fn set(&self, key: String, value: String) -> Result<()> {
+    async fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        block_in_place(move || self.insert(key, value, None))
+    }
+
+    async fn set_with_ttl_bytes(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let expires_at = expiry_after(ttl);
+        block_in_place(move || self.insert(key, value, Some(expires_at)))
+    }
+
+    async fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
         block_in_place(move || {
-            self.db.insert(key.as_str(), value.as_str())?;
-            self.db.flush()?;
-            Ok(())
+            if self.is_expired(&key, now_millis())? {
+                return Ok(None);
+            }
+            Ok(self.db.get(key)?.map(|ivec| ivec.to_vec()))
         })
     }
 
-    async fn get(&self, key: String) -> Result<Option<String>> {
+    async fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
         block_in_place(move || {
-            let value = self.db.get(key.as_str())?.map(|ivec| ivec.to_vec());
-            if let Some(value) = value {
-                let s = String::from_utf8(value)?;
-                Ok(Some(s))
+            if self.is_expired(&key, now_millis())? {
+                return Err(KvsError::KeyNotFound.into());
+            }
+            let _writes = self.writes.read().unwrap();
+            let data: &sled::Tree = &self.db;
+            let old_value = (data, &self.expiry)
+                .transaction(|(data, expiry)| {
+                    expiry.remove(key.as_slice())?;
+                    Ok(data.remove(key.as_slice())?)
+                })
+                .map_err(transaction_error)?;
+            if old_value.is_some() {
+                self.flush()?;
+                Ok(())
             } else {
-                Ok(None)
+                Err(KvsError::KeyNotFound.into())
             }
         })
     }
 
-    async fn remove(&self, key: String) -> Result<()> {
+    async fn ttl_bytes(&self, key: Vec<u8>) -> Result<Option<Duration>> {
         block_in_place(move || {
-            let old_value = self.db.remove(key.as_str())?;
-            if let Some(_) = old_value {
-                self.db.flush()?;
-                Ok(())
+            let now = now_millis();
+            if self.is_expired(&key, now)? || !self.db.contains_key(&key)? {
+                return Err(KvsError::KeyNotFound.into());
+            }
+            Ok(self
+                .expires_at(&key)?
+                .map(|expires_at| Duration::from_millis(expires_at.saturating_sub(now))))
+        })
+    }
+
+    async fn persist_bytes(&self, key: Vec<u8>) -> Result<()> {
+        block_in_place(move || {
+            let now = now_millis();
+            let _writes = self.writes.read().unwrap();
+            let data: &sled::Tree = &self.db;
+            let found = (data, &self.expiry)
+                .transaction(|(data, expiry)| {
+                    let expired = matches!(
+                        expiry.get(key.as_slice())?,
+                        Some(ivec) if decode_expiry(&ivec) <= now
+                    );
+                    if expired || data.get(key.as_slice())?.is_none() {
+                        return Ok(false);
+                    }
+                    expiry.remove(key.as_slice())?;
+                    Ok(true)
+                })
+                .map_err(transaction_error)?;
+            if found {
+                self.flush()
             } else {
                 Err(KvsError::KeyNotFound.into())
             }
         })
     }
+
+    async fn remove_expired(&self) -> Result<usize> {
+        block_in_place(move || {
+            let now = now_millis();
+            let mut removed = 0;
+            for pair in self.expiry.iter() {
+                let (key, expires_at) = pair?;
+                if decode_expiry(&expires_at) <= now
+                    && self.remove_if_unchanged(&key, &expires_at)?
+                {
+                    removed += 1;
+                }
+            }
+            if removed > 0 {
+                self.flush()?;
+            }
+            Ok(removed)
+        })
+    }
+
+    async fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+        ttl: Option<Duration>,
+    ) -> Result<()> {
+        let expires_at = ttl.map(expiry_after);
+        block_in_place(move || {
+            let now = now_millis();
+            let _writes = self.writes.read().unwrap();
+            let data: &sled::Tree = &self.db;
+            // the compare and both writes happen at once, so a concurrent
+            // write never lands in between
+            (data, &self.expiry)
+                .transaction(|(data, expiry)| {
+                    let current = live_value(data, expiry, &key, now)?;
+                    if current.as_deref() != expected.as_deref() {
+                        return Err(ConflictableTransactionError::Abort(
+                            current.map(|ivec| ivec.to_vec()),
+                        ));
+                    }
+                    match &new {
+                        Some(value) => data.insert(key.as_slice(), value.as_slice())?,
+                        None => data.remove(key.as_slice())?,
+                    };
+                    match (&new, expires_at) {
+                        (Some(_), Some(expires_at)) => {
+                            expiry.insert(key.as_slice(), &expires_at.to_be_bytes())?
+                        }
+                        _ => expiry.remove(key.as_slice())?,
+                    };
+                    Ok(())
+                })
+                .map_err(|err| match err {
+                    TransactionError::Abort(current) => KvsError::CasMismatch { current }.into(),
+                    TransactionError::Storage(err) => anyhow::Error::from(err),
+                })?;
+            self.flush()
+        })
+    }
+
+    async fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        block_in_place(move || {
+            let (data_batch, expiry_batch) = sled_batches(batch);
+            let _writes = self.writes.read().unwrap();
+            let data: &sled::Tree = &self.db;
+            (data, &self.expiry)
+                .transaction(|(data, expiry)| {
+                    data.apply_batch(&data_batch)?;
+                    expiry.apply_batch(&expiry_batch)?;
+                    Ok(())
+                })
+                .map_err(transaction_error)?;
+            self.flush()
+        })
+    }
+
+    async fn commit_transaction(&self, reads: ReadSet, writes: WriteBatch) -> Result<()> {
+        block_in_place(move || {
+            let now = now_millis();
+            let (data_batch, expiry_batch) = sled_batches(writes);
+            let _writes = self.writes.read().unwrap();
+            let data: &sled::Tree = &self.db;
+            (data, &self.expiry)
+                .transaction(|(data, expiry)| {
+                    for (key, read) in &reads {
+                        let current = live_value(data, expiry, key, now)?;
+                        if current.as_deref() != read.value.as_deref() {
+                            return Err(ConflictableTransactionError::Abort(()));
+                        }
+                    }
+                    data.apply_batch(&data_batch)?;
+                    expiry.apply_batch(&expiry_batch)?;
+                    Ok(())
+                })
+                .map_err(|err| match err {
+                    TransactionError::Abort(()) => KvsError::Conflict.into(),
+                    err => transaction_error(err),
+                })?;
+            self.flush()
+        })
+    }
+
+    async fn scan_bytes<R>(&self, range: R, limit: Option<usize>) -> Result<Vec<(Vec<u8>, Vec<u8>)>>
+    where
+        R: RangeBounds<Vec<u8>> + Send + 'static,
+    {
+        block_in_place(move || self.collect_pairs(self.db.range(range), limit))
+    }
+
+    async fn scan_prefix_bytes(
+        &self,
+        prefix: Vec<u8>,
+        limit: Option<usize>,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        block_in_place(move || self.collect_pairs(self.db.scan_prefix(prefix), limit))
+    }
+
+    /// Copy both trees to a new database at `dest`.
+    ///
+    /// The trees are copied while writes go on, the keys written meanwhile
+    /// are watched and copied again at the end. Writes only wait for that
+    /// last step, reads never wait.
+    async fn checkpoint(&self, dest: PathBuf) -> Result<()> {
+        block_in_place(move || {
+            create_checkpoint_dir(&dest)?;
+            let copy = sled::Config::new().path(dest).open()?;
+            let copy_expiry = copy.open_tree(EXPIRY_TREE)?;
+            let trees = [(&*self.db, &*copy), (&self.expiry, &copy_expiry)];
+            let watchers = trees.map(|(src, _)| KeyWatcher::start(src));
+            for (src, dst) in trees {
+                for pair in src.iter() {
+                    let (key, value) = pair?;
+                    dst.insert(key, value)?;
+                }
+            }
+            {
+                let _writes = self.writes.write().unwrap();
+                for ((src, dst), watcher) in trees.iter().zip(watchers) {
+                    for key in watcher.stop()? {
+                        match src.get(&key)? {
+                            Some(value) => dst.insert(key, value)?,
+                            None => dst.remove(key)?,
+                        };
+                    }
+                }
+            }
+            copy.flush()?;
+            Ok(())
+        })
+    }
+
+    fn name(&self) -> &'static str {
+        "sled"
+    }
+}
+
+// notes the keys written to a tree until it's stopped, its events are taken
+// all along as writers wait once a subscriber has too many of them
+struct KeyWatcher {
+    stop: Arc<AtomicBool>,
+    thread: JoinHandle<BTreeSet<sled::IVec>>,
+}
+
+impl KeyWatcher {
+    fn start(tree: &sled::Tree) -> KeyWatcher {
+        let mut subscriber = tree.watch_prefix(vec![]);
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = Arc::clone(&stop);
+        let thread = thread::spawn(move || {
+            let mut keys = BTreeSet::new();
+            loop {
+                match subscriber.next_timeout(WATCH_INTERVAL) {
+                    Ok(event) => {
+                        keys.insert(event.key().clone());
+                    }
+                    Err(RecvTimeoutError::Timeout) if !stopped.load(Ordering::SeqCst) => {}
+                    Err(_) => return keys,
+                }
+            }
+        });
+        KeyWatcher { stop, thread }
+    }
+
+    // the keys written since the watcher started, once no write is in progress
+    fn stop(self) -> Result<BTreeSet<sled::IVec>> {
+        self.stop.store(true, Ordering::SeqCst);
+        self.thread
+            .join()
+            .map_err(|_| KvsError::OtherError("checkpoint watcher panicked".to_owned()).into())
+    }
+}
+
+// batches of the data and the expiry trees applying a `WriteBatch`,
+// values set by a batch never expire and removed keys lose their expiry
+fn sled_batches(batch: WriteBatch) -> (sled::Batch, sled::Batch) {
+    let mut data_batch = sled::Batch::default();
+    let mut expiry_batch = sled::Batch::default();
+    for op in batch.into_ops() {
+        match op {
+            BatchOp::Set { key, value } => {
+                expiry_batch.remove(key.as_slice());
+                data_batch.insert(key, value);
+            }
+            BatchOp::Remove { key } => {
+                expiry_batch.remove(key.as_slice());
+                data_batch.remove(key);
+            }
+        }
+    }
+    (data_batch, expiry_batch)
+}
+
+// the value of a key within a transaction, unless it's absent or expired
+fn live_value<E>(
+    data: &TransactionalTree,
+    expiry: &TransactionalTree,
+    key: &[u8],
+    now: u64,
+) -> ConflictableTransactionResult<Option<sled::IVec>, E> {
+    match expiry.get(key)? {
+        Some(ivec) if decode_expiry(&ivec) <= now => Ok(None),
+        _ => Ok(data.get(key)?),
+    }
+}
+
+fn decode_expiry(bytes: &[u8]) -> u64 {
+    bytes.try_into().map(u64::from_be_bytes).unwrap_or(0)
+}
+
+fn transaction_error(err: TransactionError<()>) -> anyhow::Error {
+    match err {
+        TransactionError::Storage(err) => err.into(),
+        TransactionError::Abort(()) => {
+            KvsError::OtherError("transaction aborted".to_owned()).into()
+        }
+    }
 }
@@ -0,0 +1,98 @@
+use super::{KvsEngine, WriteBatch};
+use crate::Result;
+use std::collections::BTreeMap;
+
+/// Keys read by a transaction with what it has seen of them
+pub type ReadSet = BTreeMap<Vec<u8>, ReadVersion>;
+
+/// What a transaction has seen of a key, see `KvsEngine::get_versioned_bytes`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReadVersion {
+    /// The value seen, `None` if the key was absent
+    pub value: Option<Vec<u8>>,
+    /// Sequence number of the write of the value seen, `None` if the key
+    /// was absent or the engine doesn't number its writes
+    pub seq: Option<u64>,
+}
+
+/// An optimistic transaction started by `KvsEngine::begin`.
+///
+/// Writes are buffered until `commit`, which fails with
+/// `KvsError::Conflict` if a key read by the transaction has changed since.
+/// Dropping a transaction discards it.
+///
+/// Example:
+///
+/// ```rust
+/// # use anyhow::{Result, Context};
+/// use kvs::{KvsEngine, KvStore};
+/// # fn main() -> Result<()> {
+/// let rt = tokio::runtime::Runtime::new().context("failed to create tokio runtime")?;
+/// rt.block_on(async move {
+///     let dir = tempfile::TempDir::new()?;
+///     let store = KvStore::open(dir.path())?;
+///     let mut txn = store.begin();
+///     let count = txn.get("count").await?.unwrap_or_default();
+///     txn.set("count", [count, b"1".to_vec()].concat());
+///     txn.commit().await?;
+///     Ok(())
+/// })
+/// # }
+///```
+pub struct Transaction<E: KvsEngine> {
+    engine: E,
+    reads: ReadSet,
+    // `None` removes the key
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl<E: KvsEngine> Transaction<E> {
+    pub(crate) fn new(engine: E) -> Transaction<E> {
+        Transaction {
+            engine,
+            reads: ReadSet::new(),
+            writes: BTreeMap::new(),
+        }
+    }
+
+    /// Get the value of a given key, as written by this transaction if it has been.
+    pub async fn get(&mut self, key: impl Into<Vec<u8>>) -> Result<Option<Vec<u8>>> {
+        let key = key.into();
+        if let Some(value) = self.writes.get(&key) {
+            return Ok(value.clone());
+        }
+        if let Some(read) = self.reads.get(&key) {
+            return Ok(read.value.clone());
+        }
+        let read = self.engine.get_versioned_bytes(key.clone()).await?;
+        let value = read.value.clone();
+        self.reads.insert(key, read);
+        Ok(value)
+    }
+
+    /// Set the value of a given key on commit
+    pub fn set(&mut self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) {
+        self.writes.insert(key.into(), Some(value.into()));
+    }
+
+    /// Remove a given key on commit, it's not an error if the key does not exist
+    pub fn remove(&mut self, key: impl Into<Vec<u8>>) {
+        self.writes.insert(key.into(), None);
+    }
+
+    /// Apply all writes if no key read has changed since it was read.
+    ///
+    /// # Errors
+    ///
+    /// Returns `KvsError::Conflict` if a key read has changed, nothing is written then.
+    pub async fn commit(self) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        for (key, value) in self.writes {
+            match value {
+                Some(value) => batch.set(key, value),
+                None => batch.remove(key),
+            }
+        }
+        self.engine.commit_transaction(self.reads, batch).await
+    }
+}
@@ -1,4 +1,3 @@
-use sled;
 use std::io;
 use std::string::FromUtf8Error;
 use thiserror::Error;
@@ -26,6 +25,15 @@ pub enum KvsError {
     #[error("broken command")]
     BrokenCommand,
 
+    /// Record in log failed its checksum or could not be decoded
+    #[error("corrupted record in {file_id}.log at offset {offset}")]
+    Corruption {
+        /// id of the log file holding the record
+        file_id: u64,
+        /// offset of the record in the log file
+        offset: u64,
+    },
+
     /// Engine log file is not correct
     #[error("broken engine log file")]
     BrokenEngine,
@@ -38,6 +46,40 @@ pub enum KvsError {
     #[error("Key not found")]
     KeyNotFound,
 
+    /// Value of a key differs from the one expected by a compare-and-swap
+    #[error("current value differs")]
+    CasMismatch {
+        /// value of the key when the swap was tried, `None` if it's absent
+        current: Option<Vec<u8>>,
+    },
+
+    /// A key read by a transaction has changed before it committed
+    #[error("transaction conflicts with another write")]
+    Conflict,
+
+    /// A dump is truncated or malformed at the pair with this index
+    #[error("broken dump at pair {0}")]
+    BrokenDump(u64),
+
+    /// A request failed on the server as some data couldn't be decoded, its
+    /// own like a damaged log or what it was sent like a broken dump, with
+    /// the server's message
+    #[error("undecodable data on the server: {0}")]
+    RemoteCorruption(String),
+
+    /// The server refused a request of a client it doesn't trust
+    #[error("unauthorized")]
+    Unauthorized,
+
+    /// The server refused a request as the client sends too many
+    #[error("rate limited")]
+    RateLimited,
+
+    /// The client and the server share no protocol version, or a request
+    /// can't be understood by the server
+    #[error("protocol mismatch: {0}")]
+    ProtocolMismatch(String),
+
     /// Error with a string message
     #[error("other error {0}")]
     OtherError(String),
@@ -1,13 +1,26 @@
 #![deny(missing_docs)]
 //! A simple string key/value store
-pub use client::KvsClient;
-pub use engine::{KvStore, KvsEngine, SledKvsEngine};
+pub use client::{KvsClient, RemoteTransaction};
+pub use codec::Codec;
+pub use dump::{export, import, DumpEntry, DumpFormat, DumpReader, DumpStore, DumpWriter};
+#[cfg(feature = "fault-injection")]
+pub use engine::FaultInjector;
+pub use engine::{
+    EngineOptions, KvStore, KvStoreOptions, KvsEngine, MemoryKvsEngine, ReadSet, ReadVersion,
+    SledKvsEngine, Snapshot, SyncPolicy, Transaction, WriteBatch,
+};
 pub use err::KvsError;
 pub(crate) use err::Result;
+pub use protocol::ServerInfo;
 pub use server::KvsServer;
 mod client;
+mod codec;
+pub mod conformance;
+mod dump;
 mod engine;
 mod err;
 mod protocol;
+mod resp;
 /// A simple string key/value store Server
 pub mod server;
+mod wire;
@@ -1,14 +1,218 @@
+use crate::{KvsError, WriteBatch};
 use serde::{Deserialize, Serialize};
-/// Enum represents `Request` to k/v server
+use std::io;
+use std::ops::Bound;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Version of the protocol spoken by this crate, raised whenever a
+/// `Request` or a `Response` changes
+pub(crate) const PROTOCOL_VERSION: u32 = 3;
+/// First version answering failed requests with `Response::Error`
+pub(crate) const ERROR_CODE_VERSION: u32 = 2;
+/// First version whose frames after the hello are `Tagged`, so requests
+/// can be pipelined and answered out of order
+pub(crate) const PIPELINING_VERSION: u32 = 3;
+/// Oldest version of the protocol still spoken
+pub(crate) const MIN_PROTOCOL_VERSION: u32 = 1;
+/// Optional features a server supports, a client asks for those it uses
+pub(crate) const FEATURES: &[&str] = &["ttl", "cas", "batch", "transaction", "scan", "checkpoint"];
+
+/// Enum represents `Request` to k/v server, keys and values are raw bytes
+/// sent in the formats of `crate::wire`
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Request {
-    Set { key: String, value: String },
-    Get { key: String },
-    Rm { key: String },
+    Set {
+        #[serde(with = "crate::wire::bytes")]
+        key: Vec<u8>,
+        #[serde(with = "crate::wire::bytes")]
+        value: Vec<u8>,
+    },
+    SetWithTtl {
+        #[serde(with = "crate::wire::bytes")]
+        key: Vec<u8>,
+        #[serde(with = "crate::wire::bytes")]
+        value: Vec<u8>,
+        ttl: Duration,
+    },
+    Get {
+        #[serde(with = "crate::wire::bytes")]
+        key: Vec<u8>,
+    },
+    Rm {
+        #[serde(with = "crate::wire::bytes")]
+        key: Vec<u8>,
+    },
+    Ttl {
+        #[serde(with = "crate::wire::bytes")]
+        key: Vec<u8>,
+    },
+    Persist {
+        #[serde(with = "crate::wire::bytes")]
+        key: Vec<u8>,
+    },
+    Cas {
+        #[serde(with = "crate::wire::bytes")]
+        key: Vec<u8>,
+        #[serde(with = "crate::wire::option_bytes")]
+        expected: Option<Vec<u8>>,
+        #[serde(with = "crate::wire::option_bytes")]
+        new: Option<Vec<u8>>,
+    },
+    Batch {
+        batch: WriteBatch,
+    },
+    /// start a transaction on the connection
+    Begin,
+    TxnGet {
+        #[serde(with = "crate::wire::bytes")]
+        key: Vec<u8>,
+    },
+    TxnSet {
+        #[serde(with = "crate::wire::bytes")]
+        key: Vec<u8>,
+        #[serde(with = "crate::wire::bytes")]
+        value: Vec<u8>,
+    },
+    TxnRm {
+        #[serde(with = "crate::wire::bytes")]
+        key: Vec<u8>,
+    },
+    Commit,
+    Abort,
+    Scan {
+        #[serde(with = "crate::wire::bound_bytes")]
+        start: Bound<Vec<u8>>,
+        #[serde(with = "crate::wire::bound_bytes")]
+        end: Bound<Vec<u8>>,
+        limit: Option<usize>,
+    },
+    ScanPrefix {
+        #[serde(with = "crate::wire::bytes")]
+        prefix: Vec<u8>,
+        limit: Option<usize>,
+    },
+    /// admin request writing a copy of the store to a directory relative to
+    /// the server's backup directory
+    Checkpoint {
+        dest: PathBuf,
+    },
+    /// handshake sent first, with the newest protocol version the client
+    /// speaks and the features it wants
+    Hello {
+        version: u32,
+        features: Vec<String>,
+    },
 }
 /// Enum represents `Response` send from k/v server to client
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Response {
     Err(String),
-    Ok(Option<String>),
+    Ok(#[serde(with = "crate::wire::option_bytes")] Option<Vec<u8>>),
+    Pairs(#[serde(with = "crate::wire::pairs")] Vec<(Vec<u8>, Vec<u8>)>),
+    Ttl(Option<Duration>),
+    /// a compare-and-swap found this value instead of the expected one
+    CasMismatch(#[serde(with = "crate::wire::option_bytes")] Option<Vec<u8>>),
+    /// a transaction failed to commit as a key it read has changed
+    Conflict,
+    /// handshake accepted
+    Hello(ServerInfo),
+    /// a request failed, `Err` is sent instead before protocol version 2
+    Error {
+        code: ErrorCode,
+        message: String,
+    },
+}
+
+/// A request or a response with the id of the request, ids are picked by
+/// the client and 0 answers a request whose id couldn't be read
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Tagged<T> {
+    pub id: u64,
+    pub body: T,
+}
+
+/// Kind of error a request failed with, so clients needn't match messages
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ErrorCode {
+    KeyNotFound,
+    /// data couldn't be decoded, the server's or what it was sent
+    Corruption,
+    Io,
+    /// a transaction or a write conflicts with another write
+    Conflict,
+    Unauthorized,
+    RateLimited,
+    /// the request isn't understood or isn't allowed at this point
+    ProtocolError,
+    Internal,
+}
+
+impl ErrorCode {
+    /// Code of an error a request failed with on the server
+    pub(crate) fn of(err: &anyhow::Error) -> ErrorCode {
+        if let Some(err) = err.downcast_ref::<KvsError>() {
+            return match err {
+                KvsError::KeyNotFound => ErrorCode::KeyNotFound,
+                KvsError::Io(_) => ErrorCode::Io,
+                KvsError::Sled(err) => ErrorCode::of_sled(err),
+                KvsError::Serde(_)
+                | KvsError::BrokenCommand
+                | KvsError::Corruption { .. }
+                | KvsError::BrokenEngine
+                | KvsError::BrokenIndex
+                | KvsError::BrokenDump(_)
+                | KvsError::RemoteCorruption(_) => ErrorCode::Corruption,
+                KvsError::Conflict => ErrorCode::Conflict,
+                KvsError::Unauthorized => ErrorCode::Unauthorized,
+                KvsError::RateLimited => ErrorCode::RateLimited,
+                KvsError::ProtocolMismatch(_) => ErrorCode::ProtocolError,
+                _ => ErrorCode::Internal,
+            };
+        }
+        if err.is::<io::Error>() {
+            ErrorCode::Io
+        } else if let Some(err) = err.downcast_ref::<sled::Error>() {
+            ErrorCode::of_sled(err)
+        } else if err.is::<serde_json::Error>() {
+            ErrorCode::Corruption
+        } else {
+            ErrorCode::Internal
+        }
+    }
+
+    fn of_sled(err: &sled::Error) -> ErrorCode {
+        match err {
+            sled::Error::Io(_) => ErrorCode::Io,
+            sled::Error::Corruption { .. } => ErrorCode::Corruption,
+            _ => ErrorCode::Internal,
+        }
+    }
+
+    /// The `KvsError` a client returns for an error with this code
+    pub(crate) fn into_error(self, message: String) -> KvsError {
+        match self {
+            ErrorCode::KeyNotFound => KvsError::KeyNotFound,
+            ErrorCode::Corruption => KvsError::RemoteCorruption(message),
+            ErrorCode::Io => KvsError::Io(io::Error::other(message)),
+            ErrorCode::Conflict => KvsError::Conflict,
+            ErrorCode::Unauthorized => KvsError::Unauthorized,
+            ErrorCode::RateLimited => KvsError::RateLimited,
+            ErrorCode::ProtocolError => KvsError::ProtocolMismatch(message),
+            ErrorCode::Internal => KvsError::OtherError(message),
+        }
+    }
+}
+
+/// What a `KvsServer` tells a client in the handshake
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerInfo {
+    /// protocol version spoken on the connection
+    pub version: u32,
+    /// version of the server's crate
+    pub server_version: String,
+    /// name of the server's engine, like `kvs`
+    pub engine: String,
+    /// features asked for which the server supports
+    pub features: Vec<String>,
 }
@@ -0,0 +1,508 @@
+use crate::{KvsEngine, KvsError, Result, WriteBatch};
+use bytes::{Buf, BytesMut};
+use futures::prelude::*;
+use log::error;
+use std::io;
+use std::ops::Bound;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_util::codec::{Decoder, Encoder, Framed};
+
+/// Longest bulk string a command may hold, like a frame of the kvs protocol
+const MAX_BULK_LEN: usize = 8 * 1024 * 1024;
+/// Most arguments of a command
+const MAX_ARGS: usize = 1024 * 1024;
+/// Most bytes a command may take, all of its arguments and headers
+const MAX_COMMAND_LEN: usize = 64 * 1024 * 1024;
+/// Longest line of an inline command or a header
+const MAX_LINE_LEN: usize = 64 * 1024;
+/// Keys returned by a `SCAN` without `COUNT`
+const DEFAULT_SCAN_COUNT: usize = 10;
+
+/// A RESP2 reply
+#[derive(Debug)]
+enum Reply {
+    Simple(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn error(message: impl AsRef<str>) -> Reply {
+        Reply::Error(format!("ERR {}", message.as_ref()))
+    }
+
+    fn wrong_args(command: &str) -> Reply {
+        Reply::error(format!(
+            "wrong number of arguments for '{}' command",
+            command
+        ))
+    }
+}
+
+/// Decodes commands, as arrays of bulk strings or inline, and encodes replies
+#[derive(Default)]
+struct RespCodec {
+    // a command sent as an array, whose arguments are still being read
+    array: Option<PartialArray>,
+}
+
+/// The arguments of an array read so far, they're taken off the buffer as
+/// soon as each is whole so nothing is parsed twice
+struct PartialArray {
+    remaining: usize,
+    args: Vec<Vec<u8>>,
+    // bytes of the command taken off the buffer
+    len: usize,
+}
+
+impl Decoder for RespCodec {
+    type Item = Vec<Vec<u8>>;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<Vec<Vec<u8>>>> {
+        if self.array.is_none() {
+            if src.first() != Some(&b'*') {
+                return Ok(parse_inline(src)?.map(|(args, len)| {
+                    src.advance(len);
+                    args
+                }));
+            }
+            let (count, len) = match parse_header(src, 0, b'*')? {
+                Some(parsed) => parsed,
+                None => return Ok(None),
+            };
+            if count > MAX_ARGS as i64 {
+                return Err(protocol_error("invalid multibulk length"));
+            }
+            src.advance(len);
+            self.array = Some(PartialArray {
+                remaining: count.max(0) as usize,
+                args: Vec::new(),
+                len,
+            });
+        }
+        let array = self.array.as_mut().expect("array being read");
+        while array.remaining > 0 {
+            let (len, start) = match parse_header(src, 0, b'$')? {
+                Some(parsed) => parsed,
+                None => return Ok(None),
+            };
+            if !(0..=MAX_BULK_LEN as i64).contains(&len) {
+                return Err(protocol_error("invalid bulk length"));
+            }
+            let end = start + len as usize;
+            if array.len + end + 2 > MAX_COMMAND_LEN {
+                return Err(protocol_error("too big request"));
+            }
+            if src.len() < end + 2 {
+                src.reserve(end + 2 - src.len());
+                return Ok(None);
+            }
+            if &src[end..end + 2] != b"\r\n" {
+                return Err(protocol_error("expected CRLF after bulk string"));
+            }
+            array.args.push(src[start..end].to_vec());
+            src.advance(end + 2);
+            array.len += end + 2;
+            array.remaining -= 1;
+        }
+        Ok(self.array.take().map(|array| array.args))
+    }
+}
+
+impl Encoder<Reply> for RespCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, reply: Reply, dst: &mut BytesMut) -> io::Result<()> {
+        match reply {
+            Reply::Simple(s) => dst.extend_from_slice(format!("+{}\r\n", s).as_bytes()),
+            Reply::Error(s) => dst.extend_from_slice(format!("-{}\r\n", s).as_bytes()),
+            Reply::Integer(n) => dst.extend_from_slice(format!(":{}\r\n", n).as_bytes()),
+            Reply::Bulk(None) => dst.extend_from_slice(b"$-1\r\n"),
+            Reply::Bulk(Some(bytes)) => {
+                dst.extend_from_slice(format!("${}\r\n", bytes.len()).as_bytes());
+                dst.extend_from_slice(&bytes);
+                dst.extend_from_slice(b"\r\n");
+            }
+            Reply::Array(replies) => {
+                dst.extend_from_slice(format!("*{}\r\n", replies.len()).as_bytes());
+                for reply in replies {
+                    self.encode(reply, dst)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn protocol_error(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// the line starting at `start` without its CRLF, and where the next one starts
+fn parse_line(src: &[u8], start: usize) -> io::Result<Option<(&[u8], usize)>> {
+    match src[start..].windows(2).position(|w| w == b"\r\n") {
+        Some(len) => Ok(Some((&src[start..start + len], start + len + 2))),
+        None if src.len() - start > MAX_LINE_LEN => Err(protocol_error("too big line")),
+        None => Ok(None),
+    }
+}
+
+// the number on a header line like `*3` or `$5`
+fn parse_header(src: &[u8], start: usize, kind: u8) -> io::Result<Option<(i64, usize)>> {
+    let (line, next) = match parse_line(src, start)? {
+        Some(parsed) => parsed,
+        None => return Ok(None),
+    };
+    if line.first() != Some(&kind) {
+        return Err(protocol_error(&format!("expected '{}'", kind as char)));
+    }
+    let n = std::str::from_utf8(&line[1..])
+        .ok()
+        .and_then(|n| n.parse().ok())
+        .ok_or_else(|| protocol_error("invalid length"))?;
+    Ok(Some((n, next)))
+}
+
+// a command typed on a line, as by telnet, and its length
+fn parse_inline(src: &[u8]) -> io::Result<Option<(Vec<Vec<u8>>, usize)>> {
+    match src.iter().position(|&b| b == b'\n') {
+        Some(end) => {
+            let args = src[..end]
+                .split(|b| b.is_ascii_whitespace())
+                .filter(|arg| !arg.is_empty())
+                .map(<[u8]>::to_vec)
+                .collect();
+            Ok(Some((args, end + 1)))
+        }
+        None if src.len() > MAX_LINE_LEN => Err(protocol_error("too big inline request")),
+        None => Ok(None),
+    }
+}
+
+/// Serve Redis clients on `listener` until the server is closed
+pub(crate) async fn serve<E: KvsEngine>(listener: TcpListener, engine: E, state: Arc<AtomicBool>) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(err) => {
+                error!("failed to accept a RESP connection: {}", err);
+                return;
+            }
+        };
+        if !state.load(Ordering::Relaxed) {
+            break;
+        }
+        let engine = engine.clone();
+        tokio::spawn(async move {
+            if let Err(err) = handle_connection(engine, stream).await {
+                error!("{}", err);
+            }
+        });
+    }
+}
+
+async fn handle_connection<E: KvsEngine>(engine: E, stream: TcpStream) -> Result<()> {
+    let mut framed = Framed::new(stream, RespCodec::default());
+    while let Some(frame) = framed.next().await {
+        let args = match frame {
+            Ok(args) => args,
+            // the rest of the stream can't be made sense of
+            Err(err) if err.kind() == io::ErrorKind::InvalidData => {
+                framed
+                    .send(Reply::error(format!("Protocol error: {}", err)))
+                    .await?;
+                break;
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let (name, args) = match args.split_first() {
+            Some((name, args)) => (String::from_utf8_lossy(name).to_ascii_lowercase(), args),
+            None => continue,
+        };
+        if name == "quit" {
+            framed.send(Reply::Simple("OK")).await?;
+            break;
+        }
+        let reply = match execute(&engine, &name, args).await {
+            Ok(reply) => reply,
+            Err(err) => Reply::error(err.to_string()),
+        };
+        framed.send(reply).await?;
+    }
+    Ok(())
+}
+
+async fn execute<E: KvsEngine>(engine: &E, name: &str, args: &[Vec<u8>]) -> Result<Reply> {
+    let reply = match (name, args) {
+        ("ping", []) => Reply::Simple("PONG"),
+        ("ping", [message]) => Reply::Bulk(Some(message.clone())),
+        ("get", [key]) => Reply::Bulk(engine.get_bytes(key.clone()).await?),
+        ("set", [key, value, options @ ..]) => set(engine, key, value, options).await?,
+        ("del", keys) if !keys.is_empty() => {
+            let mut removed = 0;
+            for key in keys {
+                match engine.remove_bytes(key.clone()).await {
+                    Ok(()) => removed += 1,
+                    Err(err) if matches!(err.downcast_ref(), Some(KvsError::KeyNotFound)) => {}
+                    Err(err) => return Err(err),
+                }
+            }
+            Reply::Integer(removed)
+        }
+        // a key given twice is counted twice, like Redis does
+        ("exists", keys) if !keys.is_empty() => {
+            let mut found = 0;
+            for key in keys {
+                if engine.get_bytes(key.clone()).await?.is_some() {
+                    found += 1;
+                }
+            }
+            Reply::Integer(found)
+        }
+        ("mget", keys) if !keys.is_empty() => {
+            let mut values = Vec::new();
+            for key in keys {
+                values.push(Reply::Bulk(engine.get_bytes(key.clone()).await?));
+            }
+            Reply::Array(values)
+        }
+        ("mset", pairs) if !pairs.is_empty() && pairs.len() % 2 == 0 => {
+            let mut batch = WriteBatch::new();
+            for pair in pairs.chunks(2) {
+                batch.set(pair[0].clone(), pair[1].clone());
+            }
+            engine.write_batch(batch).await?;
+            Reply::Simple("OK")
+        }
+        ("scan", [cursor, options @ ..]) => scan(engine, cursor, options).await?,
+        ("info", [] | [_]) => {
+            let info = format!(
+                "# Server\r\nredis_mode:standalone\r\nkvs_version:{}\r\nkvs_engine:{}\r\n",
+                env!("CARGO_PKG_VERSION"),
+                engine.name()
+            );
+            Reply::Bulk(Some(info.into_bytes()))
+        }
+        ("ping" | "get" | "set" | "del" | "exists" | "mget" | "mset" | "scan" | "info", _) => {
+            Reply::wrong_args(name)
+        }
+        _ => Reply::error(format!("unknown command '{}'", name)),
+    };
+    Ok(reply)
+}
+
+#[derive(PartialEq)]
+enum Condition {
+    // NX, set only if the key is absent
+    Absent,
+    // XX, set only if the key exists
+    Present,
+}
+
+// SET with EX, PX, NX or XX, a condition is checked by a compare-and-swap
+// which sets the expiry along with the value
+async fn set<E: KvsEngine>(
+    engine: &E,
+    key: &[u8],
+    value: &[u8],
+    options: &[Vec<u8>],
+) -> Result<Reply> {
+    let mut ttl = None;
+    let mut condition = None;
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        match option.to_ascii_uppercase().as_slice() {
+            unit @ (b"EX" | b"PX") if ttl.is_none() => {
+                let amount: u64 = match options.next().map(|n| std::str::from_utf8(n)) {
+                    Some(Ok(n)) => match n.parse() {
+                        Ok(n) => n,
+                        Err(_) => {
+                            return Ok(Reply::error("value is not an integer or out of range"))
+                        }
+                    },
+                    _ => return Ok(Reply::error("syntax error")),
+                };
+                if amount == 0 {
+                    return Ok(Reply::error("invalid expire time in 'set' command"));
+                }
+                ttl = Some(if unit == b"EX" {
+                    Duration::from_secs(amount)
+                } else {
+                    Duration::from_millis(amount)
+                });
+            }
+            b"NX" if condition.is_none() => condition = Some(Condition::Absent),
+            b"XX" if condition.is_none() => condition = Some(Condition::Present),
+            _ => return Ok(Reply::error("syntax error")),
+        }
+    }
+
+    let (key, value) = (key.to_vec(), value.to_vec());
+    let condition = match condition {
+        Some(condition) => condition,
+        None => {
+            match ttl {
+                Some(ttl) => engine.set_with_ttl_bytes(key, value, ttl).await?,
+                None => engine.set_bytes(key, value).await?,
+            }
+            return Ok(Reply::Simple("OK"));
+        }
+    };
+    let mut expected = match condition {
+        Condition::Absent => None,
+        Condition::Present => match engine.get_bytes(key.clone()).await? {
+            Some(current) => Some(current),
+            None => return Ok(Reply::Bulk(None)),
+        },
+    };
+    loop {
+        match engine
+            .compare_and_swap_bytes(key.clone(), expected, Some(value.clone()), ttl)
+            .await
+        {
+            Ok(()) => break,
+            Err(err) => match err.downcast::<KvsError>() {
+                // changed since it was read, but still present
+                Ok(KvsError::CasMismatch {
+                    current: Some(current),
+                }) if condition == Condition::Present => expected = Some(current),
+                Ok(KvsError::CasMismatch { .. }) => return Ok(Reply::Bulk(None)),
+                Ok(err) => return Err(err.into()),
+                Err(err) => return Err(err),
+            },
+        }
+    }
+    Ok(Reply::Simple("OK"))
+}
+
+// SCAN with MATCH and COUNT, keys come in order and a cursor is the last
+// key returned so far in hex, so any connection can go on with a scan
+async fn scan<E: KvsEngine>(engine: &E, cursor: &[u8], options: &[Vec<u8>]) -> Result<Reply> {
+    let mut pattern = None;
+    let mut count = DEFAULT_SCAN_COUNT;
+    for option in options.chunks(2) {
+        match (option[0].to_ascii_uppercase().as_slice(), option.get(1)) {
+            (b"MATCH", Some(p)) => pattern = Some(p.clone()),
+            (b"COUNT", Some(n)) => {
+                count = match std::str::from_utf8(n).ok().and_then(|n| n.parse().ok()) {
+                    Some(n) if n > 0 => n,
+                    _ => return Ok(Reply::error("syntax error")),
+                }
+            }
+            _ => return Ok(Reply::error("syntax error")),
+        }
+    }
+    // 0 starts and ends a scan, hex always has an even length
+    let start = match cursor {
+        b"0" => Bound::Unbounded,
+        _ => match decode_hex(cursor) {
+            Some(key) => Bound::Excluded(key),
+            None => return Ok(Reply::error("invalid cursor")),
+        },
+    };
+
+    let pairs = engine
+        .scan_bytes((start, Bound::Unbounded), Some(count))
+        .await?;
+    let next = match pairs.last() {
+        Some((key, _)) if pairs.len() == count => encode_hex(key),
+        _ => "0".to_owned(),
+    };
+    let keys = pairs
+        .into_iter()
+        .map(|(key, _)| key)
+        .filter(|key| pattern.as_ref().is_none_or(|p| glob_match(p, key)))
+        .map(|key| Reply::Bulk(Some(key)))
+        .collect();
+    Ok(Reply::Array(vec![
+        Reply::Bulk(Some(next.into_bytes())),
+        Reply::Array(keys),
+    ]))
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode_hex(hex: &[u8]) -> Option<Vec<u8>> {
+    let pairs = hex.chunks_exact(2);
+    if !pairs.remainder().is_empty() {
+        return None;
+    }
+    let digit = |b: u8| (b as char).to_digit(16);
+    pairs
+        .map(|pair| Some((digit(pair[0])? * 16 + digit(pair[1])?) as u8))
+        .collect()
+}
+
+// whether `text` matches a glob-style pattern of Redis, with `*`, `?`,
+// `[...]` classes and `\` escapes
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // where to go on if the text after the last `*` doesn't match
+    let mut backtrack = None;
+    while t < text.len() {
+        if pattern.get(p) == Some(&b'*') {
+            p += 1;
+            backtrack = Some((p, t));
+            continue;
+        }
+        if let Some(len) = pattern.get(p..).and_then(|rest| match_one(rest, text[t])) {
+            p += len;
+            t += 1;
+            continue;
+        }
+        match backtrack {
+            // the `*` takes one more byte
+            Some((star_p, star_t)) => {
+                p = star_p;
+                t = star_t + 1;
+                backtrack = Some((star_p, star_t + 1));
+            }
+            None => return false,
+        }
+    }
+    pattern[p..].iter().all(|&b| b == b'*')
+}
+
+// length of the element starting `pattern` if it matches `c`
+fn match_one(pattern: &[u8], c: u8) -> Option<usize> {
+    match *pattern.first()? {
+        b'?' => Some(1),
+        b'\\' if pattern.len() > 1 => (pattern[1] == c).then_some(2),
+        b'[' => {
+            let mut i = 1;
+            let negate = pattern.get(i) == Some(&b'^');
+            if negate {
+                i += 1;
+            }
+            let mut matched = false;
+            while i < pattern.len() && pattern[i] != b']' {
+                if pattern[i] == b'\\' && i + 1 < pattern.len() {
+                    matched |= pattern[i + 1] == c;
+                    i += 2;
+                } else if i + 2 < pattern.len() && pattern[i + 1] == b'-' && pattern[i + 2] != b']'
+                {
+                    let (low, high) = (
+                        pattern[i].min(pattern[i + 2]),
+                        pattern[i].max(pattern[i + 2]),
+                    );
+                    matched |= (low..=high).contains(&c);
+                    i += 3;
+                } else {
+                    matched |= pattern[i] == c;
+                    i += 1;
+                }
+            }
+            // past the `]`, or the end of an unclosed class
+            (matched != negate).then_some((i + 1).min(pattern.len()))
+        }
+        b => (b == c).then_some(1),
+    }
+}
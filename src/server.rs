@@ -1,13 +1,24 @@
-use crate::protocol::{Request, Response};
-use crate::{KvsEngine, Result};
+use crate::codec::{
+    accept_codec, framed_reader, framed_writer, is_decode_error, reframe, undecodable_id,
+    FramedReader, FramedWriter,
+};
+use crate::protocol::{
+    ErrorCode, Request, Response, Tagged, ERROR_CODE_VERSION, FEATURES, MIN_PROTOCOL_VERSION,
+    PIPELINING_VERSION, PROTOCOL_VERSION,
+};
+use crate::{resp, Codec, KvsEngine, KvsError, Result, ServerInfo, Transaction};
 use futures::prelude::*;
 use log::{debug, error};
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Component, Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
-use tokio_serde::formats::SymmetricalJson;
-use tokio_util::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
+use tokio::task::JoinSet;
 
 /// A `KvsServer`
 pub struct KvsServer<E>
@@ -16,15 +27,58 @@ where
 {
     engine: E,
     state: Arc<AtomicBool>,
+    sweep_interval: Duration,
+    codecs: Arc<[Codec]>,
+    resp_addr: Option<SocketAddr>,
+    backup_dir: Option<Arc<Path>>,
 }
 
+/// Expired keys are removed every second by default
+const DEFAULT_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+/// Most pipelined requests of a connection run at once, later ones wait to be read
+const MAX_IN_FLIGHT: usize = 64;
+
 impl<E> KvsServer<E>
 where
-    E: KvsEngine + Sync,
+    E: KvsEngine,
 {
     /// A new `KvsServer`
     pub fn new(engine: E, state: Arc<AtomicBool>) -> KvsServer<E> {
-        KvsServer { engine, state }
+        KvsServer {
+            engine,
+            state,
+            sweep_interval: DEFAULT_SWEEP_INTERVAL,
+            codecs: Arc::new([Codec::Json, Codec::Bincode]),
+            resp_addr: None,
+            backup_dir: None,
+        }
+    }
+
+    /// Set how often expired keys are removed in the background
+    pub fn sweep_interval(mut self, interval: Duration) -> KvsServer<E> {
+        self.sweep_interval = interval;
+        self
+    }
+
+    /// Set the codecs clients may pick, all of them by default
+    pub fn codecs(mut self, codecs: impl Into<Vec<Codec>>) -> KvsServer<E> {
+        self.codecs = codecs.into().into();
+        self
+    }
+
+    /// Also serve Redis clients speaking RESP2 on `addr`, which see the same
+    /// keys and values
+    pub fn resp_listener(mut self, addr: SocketAddr) -> KvsServer<E> {
+        self.resp_addr = Some(addr);
+        self
+    }
+
+    /// Let clients make checkpoints, to directories within `dir` only.
+    ///
+    /// Checkpoint requests are refused unless it's set.
+    pub fn backup_dir(mut self, dir: impl Into<PathBuf>) -> KvsServer<E> {
+        self.backup_dir = Some(dir.into().into());
+        self
     }
 
     /// start running a `KvsServer`
@@ -35,6 +89,19 @@ where
         A: ToSocketAddrs,
     {
         let listener = TcpListener::bind(addr).await?;
+        if let Some(resp_addr) = self.resp_addr {
+            let resp_listener = TcpListener::bind(resp_addr).await?;
+            tokio::spawn(resp::serve(
+                resp_listener,
+                self.engine.clone(),
+                self.state.clone(),
+            ));
+        }
+        tokio::spawn(sweep_expired(
+            self.engine.clone(),
+            self.state.clone(),
+            self.sweep_interval,
+        ));
         loop {
             let (stream, _) = listener.accept().await?;
 
@@ -42,8 +109,10 @@ where
                 break;
             }
             let engine = self.engine.clone();
+            let codecs = self.codecs.clone();
+            let backup_dir = self.backup_dir.clone();
             tokio::spawn(async move {
-                if let Err(err) = handle_request(engine, stream).await {
+                if let Err(err) = handle_request(engine, stream, &codecs, backup_dir).await {
                     error!("{}", err);
                 }
             });
@@ -52,44 +121,397 @@ where
     }
 }
 
+/// remove expired keys periodically until the server is closed
+async fn sweep_expired(engine: impl KvsEngine, state: Arc<AtomicBool>, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    while state.load(Ordering::Relaxed) {
+        ticker.tick().await;
+        match engine.remove_expired().await {
+            Ok(0) => {}
+            Ok(removed) => debug!("Removed {} expired keys", removed),
+            Err(err) => error!("failed to remove expired keys: {}", err),
+        }
+    }
+}
+
 /// handle a income connection
-async fn handle_request(engine: impl KvsEngine, mut stream: TcpStream) -> Result<()> {
+async fn handle_request<E: KvsEngine>(
+    engine: E,
+    mut stream: TcpStream,
+    codecs: &[Codec],
+    backup_dir: Option<Arc<Path>>,
+) -> Result<()> {
     let addr = stream.peer_addr()?;
+    let codec = accept_codec(&mut stream, codecs).await?;
     let (read_half, write_half) = stream.split();
 
-    let mut reader = tokio_serde::SymmetricallyFramed::new(
-        FramedRead::new(read_half, LengthDelimitedCodec::new()),
-        SymmetricalJson::<Request>::default(),
-    );
-
-    let mut writer = tokio_serde::SymmetricallyFramed::new(
-        FramedWrite::new(write_half, LengthDelimitedCodec::new()),
-        SymmetricalJson::<Response>::default(),
-    );
-
-    while let Some(request) = reader.try_next().await? {
-        debug!("Recv req {:?} from {}", request, addr);
-        let res = match request {
-            Request::Get { key } => match engine.get(key).await {
-                Ok(value) => Response::Ok(value),
-                Err(err) => Response::Err(format!("err: {}", err)),
-            },
-            Request::Set { key, value } => match engine.set(key, value).await {
-                Ok(_) => Response::Ok(None),
-                Err(err) => Response::Err(format!("err: {}", err)),
-            },
-            Request::Rm { key } => match engine.remove(key).await {
-                Ok(_) => Response::Ok(None),
-                Err(err) => Response::Err(format!("err: {}", err)),
-            },
+    let mut reader = request_reader(read_half, codec);
+
+    let mut writer = framed_writer::<_, Response>(write_half, codec);
+
+    let mut session = Session {
+        engine,
+        txn: None,
+        version: MIN_PROTOCOL_VERSION,
+        backup_dir,
+    };
+    // requests are answered one at a time until the client says hello with
+    // a version which tags them, they are pipelined from then on
+    while let Some(frame) = reader.next().await {
+        let res = match frame {
+            Ok(request) => {
+                debug!("Recv req {:?} from {}", request, addr);
+                session.handle(request).await
+            }
+            // likely sent by a newer client, which is told so rather than dropped
+            Err(err) if is_decode_error(&err) => undecodable(err),
+            Err(err) => return Err(err.into()),
         };
+        let res = for_version(res, session.version);
         debug!("Send response {:?} to {}", res, addr);
         writer.send(res).await?;
+        if session.version >= PIPELINING_VERSION {
+            let reader = reframe(reader, codec);
+            let writer = reframe(writer, codec);
+            return pipeline(session, reader, writer, codec).await;
+        }
     }
     // info!("conn close");
     Ok(())
 }
 
+// answer tagged requests as soon as each is done, requests which don't use
+// the state of the connection run at once on tasks of their own, so a slow
+// one doesn't hold up the others
+async fn pipeline<E, R, W>(
+    mut session: Session<E>,
+    mut reader: FramedReader<R, Tagged<Request>>,
+    mut writer: FramedWriter<W, Tagged<Response>>,
+    codec: Codec,
+) -> Result<()>
+where
+    E: KvsEngine,
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    // shared by the tasks, an engine's clone may open readers of its own
+    let engine = Arc::new(session.engine.clone());
+    let mut in_flight = JoinSet::new();
+    let mut reading = true;
+    while reading || !in_flight.is_empty() {
+        tokio::select! {
+            frame = reader.next(), if reading && in_flight.len() < MAX_IN_FLIGHT => match frame {
+                Some(Ok(Tagged { id, body })) if uses_session(&body) => {
+                    let body = session.handle(body).await;
+                    writer.send(Tagged { id, body }).await?;
+                }
+                Some(Ok(Tagged { id, body })) => {
+                    let engine = Arc::clone(&engine);
+                    let backup_dir = session.backup_dir.clone();
+                    in_flight.spawn(async move {
+                        Tagged {
+                            id,
+                            body: execute(&*engine, backup_dir.as_deref(), body).await,
+                        }
+                    });
+                }
+                // only the request fails, 0 if even its id can't be read
+                Some(Err(err)) if is_decode_error(&err) => {
+                    let id = undecodable_id(&err, codec).unwrap_or(0);
+                    writer.send(Tagged { id, body: undecodable(err) }).await?;
+                }
+                Some(Err(err)) => return Err(err.into()),
+                None => reading = false,
+            },
+            Some(res) = in_flight.join_next() => writer.send(res?).await?,
+        }
+    }
+    Ok(())
+}
+
+/// State of a connection
+struct Session<E: KvsEngine> {
+    engine: E,
+    // the transaction in progress on this connection
+    txn: Option<Transaction<E>>,
+    // protocol version spoken, clients which never say hello speak the oldest one
+    version: u32,
+    // where checkpoints may be written, `None` if they are refused
+    backup_dir: Option<Arc<Path>>,
+}
+
+impl<E: KvsEngine> Session<E> {
+    async fn handle(&mut self, request: Request) -> Response {
+        match request {
+            Request::Begin => match self.txn {
+                Some(_) => protocol_error("transaction already in progress"),
+                None => {
+                    self.txn = Some(self.engine.begin());
+                    Response::Ok(None)
+                }
+            },
+            Request::TxnGet { key } => match self.txn.as_mut() {
+                Some(txn) => match txn.get(key).await {
+                    Ok(value) => Response::Ok(value),
+                    Err(err) => failure(err),
+                },
+                None => no_transaction(),
+            },
+            Request::TxnSet { key, value } => match self.txn.as_mut() {
+                Some(txn) => {
+                    txn.set(key, value);
+                    Response::Ok(None)
+                }
+                None => no_transaction(),
+            },
+            Request::TxnRm { key } => match self.txn.as_mut() {
+                Some(txn) => {
+                    txn.remove(key);
+                    Response::Ok(None)
+                }
+                None => no_transaction(),
+            },
+            Request::Commit => match self.txn.take() {
+                Some(txn) => match txn.commit().await {
+                    Ok(_) => Response::Ok(None),
+                    Err(err) => match err.downcast_ref::<KvsError>() {
+                        Some(KvsError::Conflict) => Response::Conflict,
+                        _ => failure(err),
+                    },
+                },
+                None => no_transaction(),
+            },
+            Request::Abort => {
+                self.txn = None;
+                Response::Ok(None)
+            }
+            // the frames can't change once they're tagged
+            Request::Hello { .. } if self.version >= PIPELINING_VERSION => {
+                protocol_error("hello was already said")
+            }
+            Request::Hello { version, features } => {
+                let res = hello(&self.engine, version, features);
+                if let Response::Hello(info) = &res {
+                    self.version = info.version;
+                }
+                res
+            }
+            request => execute(&self.engine, self.backup_dir.as_deref(), request).await,
+        }
+    }
+}
+
+// whether a request is handled by `Session::handle` rather than `execute`
+fn uses_session(request: &Request) -> bool {
+    match request {
+        Request::Begin
+        | Request::TxnGet { .. }
+        | Request::TxnSet { .. }
+        | Request::TxnRm { .. }
+        | Request::Commit
+        | Request::Abort
+        | Request::Hello { .. } => true,
+        Request::Set { .. }
+        | Request::SetWithTtl { .. }
+        | Request::Get { .. }
+        | Request::Rm { .. }
+        | Request::Ttl { .. }
+        | Request::Persist { .. }
+        | Request::Cas { .. }
+        | Request::Batch { .. }
+        | Request::Scan { .. }
+        | Request::ScanPrefix { .. }
+        | Request::Checkpoint { .. } => false,
+    }
+}
+
+/// Answer a request which doesn't use the state of its connection
+async fn execute(engine: &impl KvsEngine, backup_dir: Option<&Path>, request: Request) -> Response {
+    match request {
+        Request::Get { key } => match engine.get_bytes(key).await {
+            Ok(value) => Response::Ok(value),
+            Err(err) => failure(err),
+        },
+        Request::Set { key, value } => match engine.set_bytes(key, value).await {
+            Ok(_) => Response::Ok(None),
+            Err(err) => failure(err),
+        },
+        Request::SetWithTtl { key, value, ttl } => {
+            match engine.set_with_ttl_bytes(key, value, ttl).await {
+                Ok(_) => Response::Ok(None),
+                Err(err) => failure(err),
+            }
+        }
+        Request::Rm { key } => match engine.remove_bytes(key).await {
+            Ok(_) => Response::Ok(None),
+            Err(err) => failure(err),
+        },
+        Request::Ttl { key } => match engine.ttl_bytes(key).await {
+            Ok(ttl) => Response::Ttl(ttl),
+            Err(err) => failure(err),
+        },
+        Request::Persist { key } => match engine.persist_bytes(key).await {
+            Ok(_) => Response::Ok(None),
+            Err(err) => failure(err),
+        },
+        Request::Cas { key, expected, new } => {
+            match engine
+                .compare_and_swap_bytes(key, expected, new, None)
+                .await
+            {
+                Ok(_) => Response::Ok(None),
+                Err(err) => match err.downcast::<KvsError>() {
+                    Ok(KvsError::CasMismatch { current }) => Response::CasMismatch(current),
+                    Ok(err) => failure(err.into()),
+                    Err(err) => failure(err),
+                },
+            }
+        }
+        Request::Batch { batch } => match engine.write_batch(batch).await {
+            Ok(_) => Response::Ok(None),
+            Err(err) => failure(err),
+        },
+        Request::Scan { start, end, limit } => match engine.scan_bytes((start, end), limit).await {
+            Ok(pairs) => Response::Pairs(pairs),
+            Err(err) => failure(err),
+        },
+        Request::ScanPrefix { prefix, limit } => {
+            match engine.scan_prefix_bytes(prefix, limit).await {
+                Ok(pairs) => Response::Pairs(pairs),
+                Err(err) => failure(err),
+            }
+        }
+        Request::Checkpoint { dest } => match checkpoint_dir(backup_dir, &dest) {
+            Ok(dest) => match engine.checkpoint(dest).await {
+                Ok(_) => Response::Ok(None),
+                Err(err) => failure(err),
+            },
+            Err(err) => failure(err),
+        },
+        // handled by `Session::handle`, which has the state they need
+        Request::Begin
+        | Request::TxnGet { .. }
+        | Request::TxnSet { .. }
+        | Request::TxnRm { .. }
+        | Request::Commit
+        | Request::Abort
+        | Request::Hello { .. } => protocol_error("the request needs the state of its connection"),
+    }
+}
+
+// where a checkpoint to `dest` is written, which must be a relative path
+// staying within the backup directory, symlinks in it resolved
+fn checkpoint_dir(backup_dir: Option<&Path>, dest: &Path) -> Result<PathBuf> {
+    let backup_dir = backup_dir.ok_or(KvsError::Unauthorized)?;
+    let outside = || {
+        KvsError::OtherError(format!(
+            "checkpoint directory {} must be a relative path within the backup directory",
+            dest.display()
+        ))
+    };
+    let relative = dest.components().next().is_some()
+        && dest
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)));
+    if !relative {
+        return Err(outside().into());
+    }
+    // the deepest part of the path which exists yet, a symlink even if it's
+    // dangling, the rest is created within it
+    let backup_dir = backup_dir.canonicalize()?;
+    let mut existing = backup_dir.join(dest);
+    let mut missing = Vec::new();
+    while fs::symlink_metadata(&existing).is_err() {
+        missing.extend(existing.file_name().map(ToOwned::to_owned));
+        existing.pop();
+    }
+    let mut dir = existing.canonicalize()?;
+    if !dir.starts_with(&backup_dir) {
+        return Err(outside().into());
+    }
+    dir.extend(missing.iter().rev());
+    Ok(dir)
+}
+
+fn request_reader<R: AsyncRead>(read: R, codec: Codec) -> FramedReader<R, Request> {
+    framed_reader(read, codec)
+}
+
+/// Decode `data` the way requests are read from a connection, until it
+/// ends or a frame is not a request, return how many requests it holds.
+/// The first byte picks the codec, JSON if it's even and bincode if it's odd.
+///
+/// Only built with `--cfg fuzzing`, for the fuzz targets.
+#[cfg(fuzzing)]
+pub fn decode_requests(data: &[u8]) -> usize {
+    let (codec, frames) = match data.split_first() {
+        Some((first, frames)) if first % 2 == 1 => (Codec::Bincode, frames),
+        Some((_, frames)) => (Codec::Json, frames),
+        None => return 0,
+    };
+    let mut reader = request_reader(frames, codec);
+    let mut count = 0;
+    while let Ok(Some(_)) = futures::executor::block_on(reader.try_next()) {
+        count += 1;
+    }
+    count
+}
+
+// the newest protocol version both sides speak, and the features asked for
+// which are supported
+fn hello(engine: &impl KvsEngine, version: u32, features: Vec<String>) -> Response {
+    if version < MIN_PROTOCOL_VERSION {
+        return protocol_error(format!(
+            "protocol version {} isn't supported, the server speaks {} to {}",
+            version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+        ));
+    }
+    Response::Hello(ServerInfo {
+        version: version.min(PROTOCOL_VERSION),
+        server_version: env!("CARGO_PKG_VERSION").to_owned(),
+        engine: engine.name().to_owned(),
+        features: features
+            .into_iter()
+            .filter(|feature| FEATURES.contains(&feature.as_str()))
+            .collect(),
+    })
+}
+
+fn undecodable(err: std::io::Error) -> Response {
+    protocol_error(format!(
+        "unknown request, the server speaks protocol version {} to {}: {}",
+        MIN_PROTOCOL_VERSION, PROTOCOL_VERSION, err
+    ))
+}
+
+fn no_transaction() -> Response {
+    protocol_error("no transaction in progress")
+}
+
+fn protocol_error(message: impl Into<String>) -> Response {
+    Response::Error {
+        code: ErrorCode::ProtocolError,
+        message: message.into(),
+    }
+}
+
+fn failure(err: anyhow::Error) -> Response {
+    Response::Error {
+        code: ErrorCode::of(&err),
+        message: err.to_string(),
+    }
+}
+
+// clients before `ERROR_CODE_VERSION` only know errors as messages
+fn for_version(res: Response, version: u32) -> Response {
+    match res {
+        Response::Error { message, .. } if version < ERROR_CODE_VERSION => {
+            Response::Err(format!("err: {}", message))
+        }
+        res => res,
+    }
+}
+
 /// close the server
 pub async fn close_server<A: ToSocketAddrs>(state: Arc<AtomicBool>, addr: A) {
     state.store(false, Ordering::Relaxed);
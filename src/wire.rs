@@ -0,0 +1,174 @@
+//! Serde formats of keys and values in requests and responses.
+//!
+//! Human readable formats like JSON carry them as strings if they are valid
+//! UTF-8 and as `{"base64": "..."}` otherwise, rather than as arrays of
+//! numbers costing up to 4 bytes a byte. Arrays are still accepted.
+//! Binary formats like bincode carry them as plain bytes.
+use serde::de::{self, MapAccess, SeqAccess, Visitor};
+use serde::ser::SerializeMap;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+
+const BASE64_FIELD: &str = "base64";
+
+struct Bytes<'a>(&'a [u8]);
+
+impl Serialize for Bytes<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if !serializer.is_human_readable() {
+            return serializer.serialize_bytes(self.0);
+        }
+        match std::str::from_utf8(self.0) {
+            Ok(text) => serializer.serialize_str(text),
+            Err(_) => {
+                let mut map = serializer.serialize_map(Some(1))?;
+                map.serialize_entry(BASE64_FIELD, &base64::encode(self.0))?;
+                map.end()
+            }
+        }
+    }
+}
+
+struct ByteBuf(Vec<u8>);
+
+impl<'de> Deserialize<'de> for ByteBuf {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<ByteBuf, D::Error> {
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_any(BytesVisitor)
+        } else {
+            deserializer.deserialize_byte_buf(BytesVisitor)
+        }
+    }
+}
+
+struct BytesVisitor;
+
+impl<'de> Visitor<'de> for BytesVisitor {
+    type Value = ByteBuf;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a string, base64 or bytes")
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<ByteBuf, E> {
+        Ok(ByteBuf(v.as_bytes().to_vec()))
+    }
+
+    fn visit_string<E: de::Error>(self, v: String) -> Result<ByteBuf, E> {
+        Ok(ByteBuf(v.into_bytes()))
+    }
+
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<ByteBuf, E> {
+        Ok(ByteBuf(v.to_vec()))
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<ByteBuf, E> {
+        Ok(ByteBuf(v))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<ByteBuf, A::Error> {
+        let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0).min(4096));
+        while let Some(byte) = seq.next_element()? {
+            bytes.push(byte);
+        }
+        Ok(ByteBuf(bytes))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<ByteBuf, A::Error> {
+        match map.next_key::<String>()? {
+            Some(field) if field == BASE64_FIELD => {
+                let encoded: String = map.next_value()?;
+                base64::decode(&encoded)
+                    .map(ByteBuf)
+                    .map_err(|err| de::Error::custom(format!("invalid base64: {}", err)))
+            }
+            Some(field) => Err(de::Error::unknown_field(&field, &[BASE64_FIELD])),
+            None => Err(de::Error::missing_field(BASE64_FIELD)),
+        }
+    }
+}
+
+/// Format of a key or a value
+pub(crate) mod bytes {
+    use super::*;
+
+    pub(crate) fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        Bytes(bytes).serialize(serializer)
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<u8>, D::Error> {
+        Ok(ByteBuf::deserialize(deserializer)?.0)
+    }
+}
+
+/// Format of a key or a value which may be absent
+pub(crate) mod option_bytes {
+    use super::*;
+
+    pub(crate) fn serialize<S: Serializer>(
+        bytes: &Option<Vec<u8>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        bytes.as_deref().map(Bytes).serialize(serializer)
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Vec<u8>>, D::Error> {
+        Ok(Option::<ByteBuf>::deserialize(deserializer)?.map(|bytes| bytes.0))
+    }
+}
+
+/// Format of a bound of a scan
+pub(crate) mod bound_bytes {
+    use super::*;
+    use std::ops::Bound;
+
+    pub(crate) fn serialize<S: Serializer>(
+        bound: &Bound<Vec<u8>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match bound {
+            Bound::Included(key) => Bound::Included(Bytes(key)),
+            Bound::Excluded(key) => Bound::Excluded(Bytes(key)),
+            Bound::Unbounded => Bound::Unbounded,
+        }
+        .serialize(serializer)
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Bound<Vec<u8>>, D::Error> {
+        Ok(match Bound::<ByteBuf>::deserialize(deserializer)? {
+            Bound::Included(key) => Bound::Included(key.0),
+            Bound::Excluded(key) => Bound::Excluded(key.0),
+            Bound::Unbounded => Bound::Unbounded,
+        })
+    }
+}
+
+/// Format of the pairs found by a scan
+pub(crate) mod pairs {
+    use super::*;
+
+    type Pairs = Vec<(Vec<u8>, Vec<u8>)>;
+
+    pub(crate) fn serialize<S: Serializer>(
+        pairs: &[(Vec<u8>, Vec<u8>)],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(pairs.iter().map(|(key, value)| (Bytes(key), Bytes(value))))
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Pairs, D::Error> {
+        let pairs = Vec::<(ByteBuf, ByteBuf)>::deserialize(deserializer)?;
+        Ok(pairs
+            .into_iter()
+            .map(|(key, value)| (key.0, value.0))
+            .collect())
+    }
+}
@@ -1,6 +1,8 @@
 use assert_cmd::prelude::*;
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
 use std::process::Command;
 use std::sync::mpsc;
 use std::thread;
@@ -20,28 +22,28 @@ fn client_cli_invalid_get() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get"])
+        .args(["get"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "extra", "field"])
+        .args(["get", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--addr", "invalid-addr"])
+        .args(["get", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -52,35 +54,35 @@ fn client_cli_invalid_set() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set"])
+        .args(["set"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "missing_field"])
+        .args(["set", "missing_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key", "value", "extra_field"])
+        .args(["set", "key", "value", "extra_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key", "value", "--addr", "invalid-addr"])
+        .args(["set", "key", "value", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -91,28 +93,28 @@ fn client_cli_invalid_rm() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm"])
+        .args(["rm"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "extra", "field"])
+        .args(["rm", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key", "--addr", "invalid-addr"])
+        .args(["rm", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key", "--unknown-flag"])
+        .args(["rm", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -123,7 +125,7 @@ fn client_cli_invalid_subcommand() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["unknown"])
+        .args(["unknown"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -134,7 +136,7 @@ fn client_cli_invalid_subcommand() {
 fn client_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-client").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
@@ -145,25 +147,61 @@ fn client_cli_version() {
 fn server_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
 }
 
+#[test]
+fn server_cli_invalid_sync() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--sync", "sometimes"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--sync", "0ms"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+#[test]
+fn server_cli_kvs_only_flags() {
+    for engine in ["sled", "memory"] {
+        let temp_dir = TempDir::new().unwrap();
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--engine", engine, "--max-file-size", "1024"])
+            .current_dir(&temp_dir)
+            .assert()
+            .failure()
+            .stderr(contains(
+                "--max-file-size is only supported by the kvs engine",
+            ));
+        assert!(!temp_dir.path().join("engine.log").exists());
+    }
+}
+
 #[test]
 fn cli_log_configuration() {
     let temp_dir = TempDir::new().unwrap();
     let stderr_path = temp_dir.path().join("stderr");
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
     let mut child = cmd
-        .args(&["--engine", "kvs", "--addr", "127.0.0.1:4001"])
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4001"])
         .current_dir(&temp_dir)
         .stderr(File::create(&stderr_path).unwrap())
         .spawn()
         .unwrap();
     thread::sleep(Duration::from_secs(1));
     child.kill().expect("server exited before killed");
+    child.wait().unwrap();
 
     let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
     assert!(content.contains(env!("CARGO_PKG_VERSION")));
@@ -178,15 +216,16 @@ fn cli_wrong_engine() {
         let temp_dir = TempDir::new().unwrap();
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         let mut child = cmd
-            .args(&["--engine", "sled", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "sled", "--addr", "127.0.0.1:4002"])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-        cmd.args(&["--engine", "kvs", "--addr", "127.0.0.1:4003"])
+        cmd.args(["--engine", "kvs", "--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
             .failure();
@@ -197,15 +236,16 @@ fn cli_wrong_engine() {
         let temp_dir = TempDir::new().unwrap();
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         let mut child = cmd
-            .args(&["--engine", "kvs", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "kvs", "--addr", "127.0.0.1:4002"])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-        cmd.args(&["--engine", "sled", "--addr", "127.0.0.1:4003"])
+        cmd.args(["--engine", "sled", "--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
             .failure();
@@ -215,21 +255,25 @@ fn cli_wrong_engine() {
 fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let temp_dir = TempDir::new().unwrap();
+    let backup_dir = TempDir::new().unwrap();
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--addr", addr])
+        .arg("--backup-dir")
+        .arg(backup_dir.path())
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key1", "value1", "--addr", addr])
+        .args(["set", "key1", "value1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -237,7 +281,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -245,7 +289,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key1", "value2", "--addr", addr])
+        .args(["set", "key1", "value2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -253,7 +297,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -261,7 +305,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key2", "--addr", addr])
+        .args(["get", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -269,7 +313,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key2", "--addr", addr])
+        .args(["rm", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .failure()
@@ -277,7 +321,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key2", "value3", "--addr", addr])
+        .args(["set", "key2", "value3", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -285,12 +329,184 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key1", "--addr", addr])
+        .args(["scan", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("key1\tvalue2\nkey2\tvalue3\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["scan", "--start", "key2", "--end", "key3", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("key2\tvalue3\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["prefix", "key", "--limit", "1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("key1\tvalue2\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["rm", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout(is_empty());
 
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key3", "value4", "--ttl", "1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["ttl", "key3", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("1\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key4", "value5", "--ttl", "1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["persist", "key4", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["ttl", "key4", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("No expiry\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["cas", "key5", "--new", "value6", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args([
+            "cas",
+            "key5",
+            "--expected",
+            "value7",
+            "--new",
+            "value8",
+            "--addr",
+            addr,
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("current value differs"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["cas", "key5", "--expected", "value6", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key5", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Key not found"));
+
+    thread::sleep(Duration::from_millis(1500));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key3", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Key not found"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key4", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value5\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["export", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("{\"key\":\"key4\",\"value\":\"value5\"}\n"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["backup", "backup", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["backup", "backup", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("is not empty"));
+
+    // checkpoints stay within the backup directory
+    for dest in ["../escaped", "/tmp/escaped"] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["backup", dest, "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .failure()
+            .stderr(contains("within the backup directory"));
+    }
+    // nor do symlinks in them lead out of it
+    #[cfg(unix)]
+    {
+        let outside = TempDir::new().unwrap();
+        std::os::unix::fs::symlink(outside.path(), backup_dir.path().join("link")).unwrap();
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["backup", "link/escaped", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .failure()
+            .stderr(contains("within the backup directory"));
+        assert_eq!(fs::read_dir(outside.path()).unwrap().count(), 0);
+    }
+
     sender.send(()).unwrap();
     handle.join().unwrap();
 
@@ -298,32 +514,58 @@ fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key2", "--addr", addr])
+        .args(["get", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout(contains("value3"));
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout(contains("Key not found"));
     sender.send(()).unwrap();
     handle.join().unwrap();
+
+    // Serve the backup
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", engine, "--addr", addr])
+        .current_dir(backup_dir.path().join("backup"))
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().unwrap();
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key4", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value5\n");
+    sender.send(()).unwrap();
+    handle.join().unwrap();
 }
 
 #[test]
@@ -335,3 +577,540 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+#[test]
+fn cli_dump_offline() {
+    let temp_dir = TempDir::new().unwrap();
+    let dump = "{\"key\":\"key1\",\"value\":\"value1\"}\n\
+                {\"key\":[255],\"value\":\"value2\"}\n";
+    fs::write(temp_dir.path().join("dump.json"), dump).unwrap();
+
+    Command::cargo_bin("kvs-dump")
+        .unwrap()
+        .args(["import", "kvs", "--engine", "kvs", "--input", "dump.json"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-dump")
+        .unwrap()
+        .args([
+            "export", "kvs", "--format", "binary", "--output", "dump.bin",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-dump")
+        .unwrap()
+        .args(["import", "sled", "--engine", "sled", "--input", "dump.bin"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-dump")
+        .unwrap()
+        .args(["export", "sled"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(dump);
+
+    Command::cargo_bin("kvs-dump")
+        .unwrap()
+        .args(["export", "sled", "--engine", "kvs"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Engine inconsistent"));
+}
+
+#[test]
+fn cli_migrate_engine() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4006";
+    let serve = |engine: &str, check: &dyn Fn()| {
+        let (sender, receiver) = mpsc::sync_channel(0);
+        let mut child = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--engine", engine, "--addr", addr])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        let handle = thread::spawn(move || {
+            let _ = receiver.recv(); // wait for main thread to finish
+            child.kill().expect("server exited before killed");
+            child.wait().unwrap();
+        });
+        thread::sleep(Duration::from_secs(1));
+        check();
+        sender.send(()).unwrap();
+        handle.join().unwrap();
+    };
+    let client = |args: &[&str]| {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(args)
+            .args(["--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+    };
+
+    serve("kvs", &|| {
+        client(&["set", "key1", "value1"]);
+        client(&["set", "key2", "value2", "--ttl", "100"]);
+        client(&["set", "key3", "value3"]);
+        client(&["rm", "key3"]);
+    });
+
+    // as left by a migration interrupted while copying
+    fs::write(
+        temp_dir.path().join("migration.json"),
+        r#"{"source":"kvs","target":"sled","phase":"copying"}"#,
+    )
+    .unwrap();
+    fs::create_dir(temp_dir.path().join("migrate-to-sled")).unwrap();
+    fs::write(
+        temp_dir.path().join("migrate-to-sled").join("db"),
+        "partial",
+    )
+    .unwrap();
+    // a backup directory nested in the data directory is not the engine's
+    fs::create_dir(temp_dir.path().join("backups")).unwrap();
+    fs::write(temp_dir.path().join("backups").join("1.log"), "").unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("finish it with --migrate-to sled"));
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--migrate-to", "kvs"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("a migration to sled was interrupted"));
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--migrate-to", "sled"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    assert!(temp_dir.path().join("kvs-backup").join("1.log").exists());
+    assert!(!temp_dir.path().join("1.log").exists());
+    assert!(!temp_dir.path().join("migration.json").exists());
+    assert!(!temp_dir.path().join("migrate-to-sled").exists());
+    assert!(temp_dir.path().join("backups").join("1.log").exists());
+    assert!(!temp_dir.path().join("kvs-backup").join("backups").exists());
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--migrate-to", "sled"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("already stored by sled"));
+
+    serve("sled", &|| {
+        client(&["get", "key1"]).stdout("value1\n");
+        client(&["get", "key3"]).stdout(contains("Key not found"));
+        let output = client(&["ttl", "key2"]).get_output().stdout.clone();
+        let secs: u64 = String::from_utf8(output).unwrap().trim().parse().unwrap();
+        assert!(secs > 90 && secs <= 100);
+    });
+
+    // and back, the backup of the first migration stays where it is
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--migrate-to", "kvs"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    assert!(temp_dir.path().join("kvs-backup").exists());
+    assert!(temp_dir.path().join("sled-backup").join("db").exists());
+
+    serve("kvs", &|| {
+        client(&["get", "key1"]).stdout("value1\n");
+        client(&["get", "key2"]).stdout("value2\n");
+    });
+}
+
+#[test]
+fn cli_access_server_memory_engine() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4007";
+    let serve = |check: &dyn Fn()| {
+        let (sender, receiver) = mpsc::sync_channel(0);
+        let mut child = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--engine", "memory", "--addr", addr])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        let handle = thread::spawn(move || {
+            let _ = receiver.recv(); // wait for main thread to finish
+            child.kill().expect("server exited before killed");
+            child.wait().unwrap();
+        });
+        thread::sleep(Duration::from_secs(1));
+        check();
+        sender.send(()).unwrap();
+        handle.join().unwrap();
+    };
+    let client = |args: &[&str]| {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(args)
+            .args(["--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+    };
+
+    serve(&|| {
+        client(&["set", "key1", "value1"]).stdout(is_empty());
+        client(&["get", "key1"]).stdout("value1\n");
+        // checkpoints are refused without a backup directory
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["backup", "backup", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .failure()
+            .stderr(contains("unauthorized"));
+    });
+    // nothing is written to the data directory, not even engine.log
+    assert_eq!(fs::read_dir(&temp_dir).unwrap().count(), 0);
+
+    serve(&|| {
+        client(&["get", "key1"]).stdout(contains("Key not found"));
+    });
+}
+
+// the memory engine writes a snapshot when it's stopped by SIGTERM and
+// loads it when it starts again
+#[cfg(unix)]
+#[test]
+fn cli_memory_snapshot() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4015";
+    let args = ["--engine", "memory", "--memory-snapshot-dir", "snapshot"];
+    let client = |args: &[&str]| {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(args)
+            .args(["--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+    };
+
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(args)
+        .args(["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    client(&["set", "key1", "value1"]);
+    Command::new("kill")
+        .args(["-TERM", &child.id().to_string()])
+        .assert()
+        .success();
+    assert!(child.wait().unwrap().success());
+    assert!(temp_dir
+        .path()
+        .join("snapshot")
+        .join("memory.snapshot")
+        .exists());
+
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(args)
+        .args(["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().unwrap();
+    });
+    thread::sleep(Duration::from_secs(1));
+    client(&["get", "key1"]).stdout("value1\n");
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--memory-snapshot-dir", "snapshot"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("only supported by the memory engine"));
+}
+
+#[test]
+fn cli_access_server_codec() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4008";
+    let serve = |codecs: &[&str], check: &dyn Fn()| {
+        let (sender, receiver) = mpsc::sync_channel(0);
+        let mut child = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--engine", "kvs", "--addr", addr])
+            .args(codecs)
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        let handle = thread::spawn(move || {
+            let _ = receiver.recv(); // wait for main thread to finish
+            child.kill().expect("server exited before killed");
+            child.wait().unwrap();
+        });
+        thread::sleep(Duration::from_secs(1));
+        check();
+        sender.send(()).unwrap();
+        handle.join().unwrap();
+    };
+    let client = |codec: &str, args: &[&str]| {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(args)
+            .args(["--addr", addr, "--codec", codec])
+            .current_dir(&temp_dir)
+            .assert()
+    };
+
+    // clients of either codec share the same data
+    serve(&[], &|| {
+        client("bincode", &["set", "key1", "value1"])
+            .success()
+            .stdout(is_empty());
+        client("json", &["get", "key1"])
+            .success()
+            .stdout("value1\n");
+        client("json", &["set", "key2", "value2"]).success();
+        client("bincode", &["get", "key2"])
+            .success()
+            .stdout("value2\n");
+        client("bincode", &["scan", "--limit", "1"])
+            .success()
+            .stdout("key1\tvalue1\n");
+    });
+
+    serve(&["--codecs", "json"], &|| {
+        client("bincode", &["get", "key1"])
+            .failure()
+            .stderr(contains("doesn't accept the bincode codec"));
+        client("json", &["get", "key1"])
+            .success()
+            .stdout("value1\n");
+    });
+
+    serve(&["--codecs", "bincode"], &|| {
+        client("json", &["get", "key1"]).failure();
+        client("bincode", &["get", "key1"])
+            .success()
+            .stdout("value1\n");
+    });
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr, "--codec", "xml"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+#[test]
+fn cli_resp_listener() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4012";
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "memory", "--addr", addr])
+        .args(["--resp-addr", "127.0.0.1:4013"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().unwrap();
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let stream = TcpStream::connect("127.0.0.1:4013").unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut writer = stream;
+    // typed as by telnet
+    writer.write_all(b"PING\r\n").unwrap();
+    assert_eq!(read_reply(&mut reader), "+PONG\r\n");
+    let mut redis = |args: &[&str]| {
+        writer.write_all(&encode_command(args)).unwrap();
+        read_reply(&mut reader)
+    };
+
+    assert_eq!(redis(&["PING"]), "+PONG\r\n");
+    assert_eq!(redis(&["SET", "key1", "value1"]), "+OK\r\n");
+    assert_eq!(redis(&["get", "key1"]), "$6\r\nvalue1\r\n");
+    assert_eq!(redis(&["SET", "key1", "value2", "NX"]), "$-1\r\n");
+    assert_eq!(redis(&["SET", "key2", "value2", "XX"]), "$-1\r\n");
+    assert_eq!(redis(&["SET", "key1", "value3", "XX"]), "+OK\r\n");
+    assert_eq!(
+        redis(&["SET", "key2", "value2", "NX", "EX", "100"]),
+        "+OK\r\n"
+    );
+    assert!(redis(&["SET", "key2", "value2", "EX", "0"]).starts_with("-ERR invalid expire"));
+    assert!(redis(&["SET", "key2", "value2", "EX"]).starts_with("-ERR syntax error"));
+    assert_eq!(redis(&["MSET", "a", "1", "b", "2"]), "+OK\r\n");
+    assert_eq!(
+        redis(&["MGET", "a", "b", "missing"]),
+        "*3\r\n$1\r\n1\r\n$1\r\n2\r\n$-1\r\n"
+    );
+    assert_eq!(redis(&["EXISTS", "a", "missing", "a"]), ":2\r\n");
+    assert_eq!(redis(&["DEL", "a", "missing"]), ":1\r\n");
+    assert!(redis(&["INFO"]).contains("kvs_engine:memory"));
+    assert!(redis(&["GET"]).starts_with("-ERR wrong number of arguments"));
+    assert!(redis(&["FLUSHALL"]).starts_with("-ERR unknown command"));
+
+    // every key, two at a time, then only those matching
+    let mut keys = Vec::new();
+    let mut cursor = "0".to_owned();
+    loop {
+        let reply = redis(&["SCAN", &cursor, "COUNT", "2"]);
+        let lines: Vec<&str> = reply.split("\r\n").collect();
+        cursor = lines[2].to_owned();
+        keys.extend(
+            lines[4..]
+                .iter()
+                .skip(1)
+                .step_by(2)
+                .map(|key| key.to_string()),
+        );
+        if cursor == "0" {
+            break;
+        }
+    }
+    assert_eq!(keys, ["b", "key1", "key2"]);
+    assert_eq!(
+        redis(&["SCAN", "0", "MATCH", "key[0-9]"]),
+        "*2\r\n$1\r\n0\r\n*2\r\n$4\r\nkey1\r\n$4\r\nkey2\r\n"
+    );
+    assert!(redis(&["SCAN", "12345"]).starts_with("-ERR invalid cursor"));
+    assert!(redis(&["SCAN", "zz"]).starts_with("-ERR invalid cursor"));
+    // a cursor is the last key in hex, any connection can go on with it
+    let mut other = BufReader::new(TcpStream::connect("127.0.0.1:4013").unwrap());
+    other
+        .get_mut()
+        .write_all(&encode_command(&["SCAN", "6b657931"]))
+        .unwrap();
+    assert_eq!(
+        read_reply(&mut other),
+        "*2\r\n$1\r\n0\r\n*1\r\n$4\r\nkey2\r\n"
+    );
+
+    // one of many racing conditional sets wins, with its expiry
+    let racers: Vec<_> = (0..8)
+        .map(|i| {
+            thread::spawn(move || {
+                let stream = TcpStream::connect("127.0.0.1:4013").unwrap();
+                let mut conn = BufReader::new(stream);
+                let value = format!("value{}", i);
+                let command = encode_command(&["SET", "race", &value, "NX", "EX", "100"]);
+                conn.get_mut().write_all(&command).unwrap();
+                read_reply(&mut conn)
+            })
+        })
+        .collect();
+    let replies: Vec<String> = racers
+        .into_iter()
+        .map(|racer| racer.join().unwrap())
+        .collect();
+    assert_eq!(
+        replies.iter().filter(|reply| *reply == "+OK\r\n").count(),
+        1
+    );
+    assert!(replies
+        .iter()
+        .all(|reply| reply == "+OK\r\n" || reply == "$-1\r\n"));
+
+    // the kvs protocol sees the same data
+    let client = |args: &[&str]| {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(args)
+            .args(["--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+    };
+    client(&["get", "key1"]).stdout("value3\n");
+    client(&["ttl", "key2"]).stdout("100\n");
+    client(&["ttl", "race"]).stdout("100\n");
+    client(&["set", "key3", "value3"]);
+    assert_eq!(redis(&["GET", "key3"]), "$6\r\nvalue3\r\n");
+
+    // a command sent a byte at a time
+    let mut slow = BufReader::new(TcpStream::connect("127.0.0.1:4013").unwrap());
+    for byte in encode_command(&["GET", "key3"]) {
+        slow.get_mut().write_all(&[byte]).unwrap();
+    }
+    assert_eq!(read_reply(&mut slow), "$6\r\nvalue3\r\n");
+    // a command is refused as soon as it's known to be too big
+    let mut big = BufReader::new(TcpStream::connect("127.0.0.1:4013").unwrap());
+    let arg = vec![b'x'; 8 * 1024 * 1024];
+    big.get_mut().write_all(b"*9\r\n").unwrap();
+    for _ in 0..7 {
+        big.get_mut().write_all(b"$8388608\r\n").unwrap();
+        big.get_mut().write_all(&arg).unwrap();
+        big.get_mut().write_all(b"\r\n").unwrap();
+    }
+    big.get_mut().write_all(b"$8388608\r\n").unwrap();
+    assert!(read_reply(&mut big).starts_with("-ERR Protocol error: too big request"));
+
+    assert_eq!(redis(&["QUIT"]), "+OK\r\n");
+    assert_eq!(reader.read(&mut [0; 1]).unwrap(), 0);
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+// a command as an array of bulk strings
+fn encode_command(args: &[&str]) -> Vec<u8> {
+    let mut command = format!("*{}\r\n", args.len());
+    for arg in args {
+        command.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+    }
+    command.into_bytes()
+}
+
+// a whole RESP reply as it's sent
+fn read_reply(reader: &mut impl BufRead) -> String {
+    let mut reply = String::new();
+    reader.read_line(&mut reply).unwrap();
+    let len: i64 = reply[1..reply.len() - 2].parse().unwrap_or(-1);
+    match reply.as_bytes()[0] {
+        b'$' if len >= 0 => {
+            let mut bulk = vec![0; len as usize + 2];
+            reader.read_exact(&mut bulk).unwrap();
+            reply.push_str(&String::from_utf8(bulk).unwrap());
+        }
+        b'*' => {
+            for _ in 0..len.max(0) {
+                reply.push_str(&read_reply(reader));
+            }
+        }
+        _ => {}
+    }
+    reply
+}
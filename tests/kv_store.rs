@@ -1,8 +1,24 @@
 use anyhow::{Context, Result};
+use async_trait::async_trait;
 use awaitgroup::WaitGroup;
-use kvs::{KvStore, KvsEngine};
+use kvs::server::close_server;
+use kvs::{
+    conformance, Codec, DumpEntry, DumpFormat, DumpReader, DumpWriter, EngineOptions,
+    FaultInjector, KvStore, KvStoreOptions, KvsClient, KvsEngine, KvsError, KvsServer,
+    MemoryKvsEngine, ReadSet, SledKvsEngine, SyncPolicy, WriteBatch,
+};
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tempfile::TempDir;
-use tokio;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
 use walkdir::WalkDir;
 
 // Should get previously stored value
@@ -185,7 +201,7 @@ fn concurrent_set() -> Result<()> {
         for i in 0..1000 {
             let store = store.clone();
             let worker = wg.worker();
-            let _ = tokio::spawn(async move {
+            tokio::spawn(async move {
                 store
                     .set(format!("key{}", i), format!("value{}", i))
                     .await
@@ -234,7 +250,7 @@ fn concurrent_get() -> Result<()> {
         for thread_id in 0..100 {
             let store = store.clone();
             let worker = wg.worker();
-            let _ = tokio::spawn(async move {
+            tokio::spawn(async move {
                 for i in 0..100 {
                     let key_id = (i + thread_id) % 100;
                     assert_eq!(
@@ -253,7 +269,7 @@ fn concurrent_get() -> Result<()> {
         let mut wg = WaitGroup::new();
         for thread_id in 0..100 {
             let store = store.clone();
-            let _ = tokio::spawn(async move {
+            tokio::spawn(async move {
                 for i in 0..100 {
                     let key_id = (i + thread_id) % 100;
                     assert_eq!(
@@ -268,3 +284,1984 @@ fn concurrent_get() -> Result<()> {
         Ok(())
     })
 }
+
+// Should refuse to open a log whose record fails its checksum
+#[test]
+fn detect_corrupted_record() -> Result<()> {
+    let rt = tokio::runtime::Runtime::new().context("failed to create tokio runtime")?;
+    rt.block_on(async move {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned()).await?;
+        store.set("key2".to_owned(), "value2".to_owned()).await?;
+        drop(store);
+
+        // flip the last byte of the value of key1
+        let log = temp_dir.path().join("1.log");
+        let mut content = fs::read(&log)?;
+        let pos = content.windows(6).position(|w| w == b"value1").unwrap() + 5;
+        content[pos] ^= 0xff;
+        fs::write(&log, content)?;
+
+        let err = KvStore::open(temp_dir.path())
+            .err()
+            .expect("corruption not detected");
+        match err.downcast_ref::<KvsError>() {
+            Some(KvsError::Corruption { file_id, offset }) => {
+                assert_eq!(*file_id, 1);
+                assert_eq!(*offset, 0);
+            }
+            _ => panic!("unexpected error {}", err),
+        }
+        Ok(())
+    })
+}
+
+// Should open logs written as bare serde_json commands
+#[test]
+fn open_legacy_json_log() -> Result<()> {
+    let rt = tokio::runtime::Runtime::new().context("failed to create tokio runtime")?;
+    rt.block_on(async move {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        fs::write(
+            temp_dir.path().join("1.log"),
+            r#"{"Set":{"key":"key1","value":"value1"}}{"Set":{"key":"key2","value":"value2"}}{"Rm":{"key":"key2"}}"#,
+        )?;
+
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(
+            store.get("key1".to_owned()).await?,
+            Some("value1".to_owned())
+        );
+        assert_eq!(store.get("key2".to_owned()).await?, None);
+        store.set("key3".to_owned(), "value3".to_owned()).await?;
+
+        drop(store);
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(
+            store.get("key1".to_owned()).await?,
+            Some("value1".to_owned())
+        );
+        assert_eq!(
+            store.get("key3".to_owned()).await?,
+            Some("value3".to_owned())
+        );
+        Ok(())
+    })
+}
+
+// Should drop an incomplete record at the tail of the newest log
+#[test]
+fn recover_torn_write() -> Result<()> {
+    let rt = tokio::runtime::Runtime::new().context("failed to create tokio runtime")?;
+    rt.block_on(async move {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned()).await?;
+        store.set("key2".to_owned(), "value2".to_owned()).await?;
+        drop(store);
+
+        // cut the last record in half
+        let log = temp_dir.path().join("1.log");
+        let len = fs::metadata(&log)?.len();
+        OpenOptions::new()
+            .write(true)
+            .open(&log)?
+            .set_len(len - 10)?;
+
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(
+            store.get("key1".to_owned()).await?,
+            Some("value1".to_owned())
+        );
+        assert_eq!(store.get("key2".to_owned()).await?, None);
+        store.set("key2".to_owned(), "value3".to_owned()).await?;
+
+        drop(store);
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(
+            store.get("key2".to_owned()).await?,
+            Some("value3".to_owned())
+        );
+
+        // a torn serde_json command is recovered as well
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        fs::write(
+            temp_dir.path().join("1.log"),
+            r#"{"Set":{"key":"key1","value":"value1"}}{"Set":{"key":"key2","va"#,
+        )?;
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(
+            store.get("key1".to_owned()).await?,
+            Some("value1".to_owned())
+        );
+        assert_eq!(store.get("key2".to_owned()).await?, None);
+        Ok(())
+    })
+}
+
+// Should refuse to open if a log other than the newest is incomplete
+#[test]
+fn refuse_torn_older_log() -> Result<()> {
+    let rt = tokio::runtime::Runtime::new().context("failed to create tokio runtime")?;
+    rt.block_on(async move {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned()).await?;
+        drop(store);
+        let store = KvStore::open(temp_dir.path())?;
+        store.set("key2".to_owned(), "value2".to_owned()).await?;
+        drop(store);
+
+        let log = temp_dir.path().join("1.log");
+        let len = fs::metadata(&log)?.len();
+        OpenOptions::new()
+            .write(true)
+            .open(&log)?
+            .set_len(len - 1)?;
+
+        let err = KvStore::open(temp_dir.path())
+            .err()
+            .expect("corruption not detected");
+        assert!(matches!(
+            err.downcast_ref::<KvsError>(),
+            Some(KvsError::Corruption { file_id: 1, .. })
+        ));
+        Ok(())
+    })
+}
+
+// Should refuse to open the newest log if a damaged length makes a record
+// in its middle look torn, rather than dropping the records after it
+#[test]
+fn refuse_damaged_length_in_newest_log() -> Result<()> {
+    let rt = tokio::runtime::Runtime::new().context("failed to create tokio runtime")?;
+    rt.block_on(async move {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned()).await?;
+        store.set("key2".to_owned(), "value2".to_owned()).await?;
+        store.set("key3".to_owned(), "value3".to_owned()).await?;
+        drop(store);
+
+        // flip the high bit of the key length of the first record
+        let log = temp_dir.path().join("1.log");
+        let mut content = fs::read(&log)?;
+        content[19] ^= 0x80;
+        fs::write(&log, content)?;
+
+        let err = KvStore::open(temp_dir.path())
+            .err()
+            .expect("corruption not detected");
+        assert!(matches!(
+            err.downcast_ref::<KvsError>(),
+            Some(KvsError::Corruption {
+                file_id: 1,
+                offset: 0
+            })
+        ));
+        Ok(())
+    })
+}
+
+// Should keep data under every sync policy, and sync as often as it says
+#[test]
+fn sync_policies() -> Result<()> {
+    let rt = tokio::runtime::Runtime::new().context("failed to create tokio runtime")?;
+    rt.block_on(async move {
+        let policies = vec![
+            SyncPolicy::Always,
+            SyncPolicy::Interval(Duration::from_millis(10)),
+            SyncPolicy::Never,
+        ];
+        for policy in policies {
+            let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+            let faults = FaultInjector::new();
+            let options = KvStoreOptions::from(EngineOptions::default().sync_policy(policy));
+            let store = KvStore::open_with_faults(temp_dir.path(), options, faults.clone())?;
+            for i in 0..100 {
+                store
+                    .set(format!("key{}", i), format!("value{}", i))
+                    .await?;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            match policy {
+                SyncPolicy::Always => assert!(faults.syncs() >= 100),
+                SyncPolicy::Interval(_) => assert!(faults.syncs() > 0),
+                SyncPolicy::Never => assert_eq!(faults.syncs(), 0),
+            }
+
+            drop(store);
+            let store = KvStore::open_with_faults(temp_dir.path(), options, faults.clone())?;
+            for i in 0..100 {
+                assert_eq!(
+                    store.get(format!("key{}", i)).await?,
+                    Some(format!("value{}", i))
+                );
+            }
+        }
+        Ok(())
+    })
+}
+
+// Compaction should leave hint files which rebuild the same index on reopen,
+// and a damaged or truncated hint file should fall back to replaying its log.
+#[test]
+fn compaction_hints() -> Result<()> {
+    let rt = tokio::runtime::Runtime::new().context("failed to create tokio runtime")?;
+    rt.block_on(async move {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path())?;
+
+        let hint_files = || {
+            fs::read_dir(temp_dir.path())
+                .unwrap()
+                .map(|entry| entry.unwrap().path())
+                .filter(|path| path.extension() == Some("hint".as_ref()))
+                .collect::<Vec<_>>()
+        };
+
+        let mut iter = 0;
+        while hint_files().is_empty() {
+            assert!(iter < 1000, "No compaction detected");
+            for key_id in 0..1000 {
+                store
+                    .set(format!("key{}", key_id), format!("{}", iter))
+                    .await?;
+            }
+            iter += 1;
+        }
+        store.set("key0".to_owned(), "latest".to_owned()).await?;
+        drop(store);
+
+        let check = |store: KvStore| async move {
+            assert_eq!(
+                store.get("key0".to_owned()).await?,
+                Some("latest".to_owned())
+            );
+            for key_id in 1..1000 {
+                assert_eq!(
+                    store.get(format!("key{}", key_id)).await?,
+                    Some(format!("{}", iter - 1))
+                );
+            }
+            Ok::<(), anyhow::Error>(())
+        };
+        check(KvStore::open(temp_dir.path())?).await?;
+
+        for hint in hint_files() {
+            let mut content = fs::read(&hint)?;
+            let last = content.len() - 1;
+            content[last] ^= 0xff;
+            fs::write(&hint, content)?;
+        }
+        check(KvStore::open(temp_dir.path())?).await?;
+
+        // a hint cut short after its header would hold no entries at all
+        for hint in hint_files() {
+            let content = fs::read(&hint)?;
+            fs::write(&hint, &content[..11])?;
+        }
+        check(KvStore::open(temp_dir.path())?).await?;
+        Ok(())
+    })
+}
+
+// Should reclaim space on demand while writes go on
+#[test]
+fn compact_while_writing() -> Result<()> {
+    let rt = tokio::runtime::Runtime::new().context("failed to create tokio runtime")?;
+    rt.block_on(async move {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path())?;
+        let dir_size = || {
+            WalkDir::new(temp_dir.path())
+                .into_iter()
+                .map(|entry| entry.unwrap().metadata().unwrap().len())
+                .sum::<u64>()
+        };
+
+        for iter in 0..10 {
+            for key_id in 0..1000 {
+                store
+                    .set(format!("key{}", key_id), format!("{}", iter))
+                    .await?;
+            }
+        }
+        let size = dir_size();
+
+        let writer = {
+            let store = store.clone();
+            tokio::spawn(async move {
+                for key_id in 0..1000 {
+                    store
+                        .set(format!("new_key{}", key_id), format!("{}", key_id))
+                        .await
+                        .unwrap();
+                }
+                for key_id in 0..500 {
+                    store.remove(format!("key{}", key_id)).await.unwrap();
+                }
+            })
+        };
+        store.compact().await?;
+        writer.await?;
+        store.compact().await?;
+        assert!(dir_size() < size);
+
+        let check = |store: KvStore| async move {
+            for key_id in 0..1000 {
+                let expected = if key_id < 500 {
+                    None
+                } else {
+                    Some("9".to_owned())
+                };
+                assert_eq!(store.get(format!("key{}", key_id)).await?, expected);
+                assert_eq!(
+                    store.get(format!("new_key{}", key_id)).await?,
+                    Some(format!("{}", key_id))
+                );
+            }
+            Ok::<(), anyhow::Error>(())
+        };
+        check(store.clone()).await?;
+        drop(store);
+        check(KvStore::open(temp_dir.path())?).await?;
+        Ok(())
+    })
+}
+
+// Should honor small files, buffers and file handle limits
+#[test]
+fn custom_options() -> Result<()> {
+    let rt = tokio::runtime::Runtime::new().context("failed to create tokio runtime")?;
+    rt.block_on(async move {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions::new()
+            .max_file_size(4 * 1024)
+            .compaction_threshold(64 * 1024)
+            .compaction_ratio(0.5)
+            .read_buffer_size(64)
+            .max_open_files(2);
+        let store = KvStore::open_with_options(temp_dir.path(), options)?;
+        let log_count = || {
+            fs::read_dir(temp_dir.path())
+                .unwrap()
+                .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("log".as_ref()))
+                .count()
+        };
+
+        for iter in 0..20 {
+            for key_id in 0..200 {
+                store
+                    .set(format!("key{}", key_id), format!("value{}", iter))
+                    .await?;
+            }
+        }
+        assert!(log_count() > 2);
+        for key_id in 0..200 {
+            assert_eq!(
+                store.get(format!("key{}", key_id)).await?,
+                Some("value19".to_owned())
+            );
+        }
+
+        drop(store);
+        let store = KvStore::open_with_options(temp_dir.path(), options)?;
+        for key_id in 0..200 {
+            assert_eq!(
+                store.get(format!("key{}", key_id)).await?,
+                Some("value19".to_owned())
+            );
+        }
+        // live data takes about 4 files, compaction keeps the rest away
+        assert!(log_count() < 40);
+
+        let invalid = KvStoreOptions::new().compaction_ratio(1.5);
+        assert!(KvStore::open_with_options(temp_dir.path(), invalid).is_err());
+        Ok(())
+    })
+}
+
+// Should scan ranges and prefixes in the order of keys
+#[test]
+fn scan_keys() -> Result<()> {
+    let rt = tokio::runtime::Runtime::new().context("failed to create tokio runtime")?;
+    rt.block_on(async move {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path())?;
+        for key in &["b", "a", "ab", "c", "abc", "d"] {
+            store.set(key.to_string(), format!("value_{}", key)).await?;
+        }
+        store.remove("c".to_owned()).await?;
+        let pair = |key: &str| (key.to_owned(), format!("value_{}", key));
+
+        assert_eq!(
+            store.scan(.., None).await?,
+            vec![pair("a"), pair("ab"), pair("abc"), pair("b"), pair("d")]
+        );
+        assert_eq!(
+            store.scan("ab".to_owned().."c".to_owned(), None).await?,
+            vec![pair("ab"), pair("abc"), pair("b")]
+        );
+        assert_eq!(
+            store.scan("b".to_owned().., Some(1)).await?,
+            vec![pair("b")]
+        );
+        assert_eq!(
+            store.scan_prefix("ab".to_owned(), None).await?,
+            vec![pair("ab"), pair("abc")]
+        );
+        assert_eq!(
+            store.scan_prefix("a".to_owned(), Some(2)).await?,
+            vec![pair("a"), pair("ab")]
+        );
+        assert_eq!(store.scan_prefix("x".to_owned(), None).await?, vec![]);
+        Ok(())
+    })
+}
+
+// Should store keys and values which are not valid UTF-8
+#[test]
+fn binary_keys_and_values() -> Result<()> {
+    let rt = tokio::runtime::Runtime::new().context("failed to create tokio runtime")?;
+    rt.block_on(async move {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let key = vec![0xff, 0x00, 0xfe];
+        let value = vec![0x80, 0x00, 0x01, 0xc3];
+        {
+            let store = KvStore::open(temp_dir.path())?;
+            store.set_bytes(key.clone(), value.clone()).await?;
+            store.set_bytes(vec![0xff, 0x01], vec![]).await?;
+            store.set("key".to_owned(), "value".to_owned()).await?;
+        }
+
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get_bytes(key.clone()).await?, Some(value.clone()));
+        assert_eq!(store.get_bytes(vec![0xff, 0x01]).await?, Some(vec![]));
+        assert_eq!(
+            store.get_bytes(b"key".to_vec()).await?,
+            Some(b"value".to_vec())
+        );
+        assert_eq!(
+            store.scan_prefix_bytes(vec![0xff], None).await?,
+            vec![(key.clone(), value), (vec![0xff, 0x01], vec![])]
+        );
+        // the string wrappers refuse values which aren't UTF-8
+        store.set_bytes(b"blob".to_vec(), vec![0xc3]).await?;
+        let err = store.get("blob".to_owned()).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<KvsError>(),
+            Some(KvsError::Utf8(_))
+        ));
+
+        store.remove_bytes(key.clone()).await?;
+        assert_eq!(store.get_bytes(key).await?, None);
+        Ok(())
+    })
+}
+
+// Should treat expired keys as absent, before and after reopening and compaction
+#[test]
+fn expire_keys() -> Result<()> {
+    let rt = tokio::runtime::Runtime::new().context("failed to create tokio runtime")?;
+    rt.block_on(async move {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let ttl = Duration::from_millis(300);
+        {
+            let store = KvStore::open(temp_dir.path())?;
+            store
+                .set_with_ttl("short".to_owned(), "1".to_owned(), ttl)
+                .await?;
+            store
+                .set_with_ttl("kept".to_owned(), "2".to_owned(), ttl)
+                .await?;
+            store
+                .set_with_ttl("long".to_owned(), "3".to_owned(), Duration::from_secs(3600))
+                .await?;
+            store.set("plain".to_owned(), "4".to_owned()).await?;
+
+            assert_eq!(store.get("short".to_owned()).await?, Some("1".to_owned()));
+            assert!(store.ttl("short".to_owned()).await?.unwrap() <= ttl);
+            assert_eq!(store.ttl("plain".to_owned()).await?, None);
+            store.persist("kept".to_owned()).await?;
+            assert_eq!(store.ttl("kept".to_owned()).await?, None);
+        }
+
+        std::thread::sleep(ttl);
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("short".to_owned()).await?, None);
+        assert_eq!(store.get("kept".to_owned()).await?, Some("2".to_owned()));
+        assert!(store.ttl("long".to_owned()).await?.unwrap() > ttl);
+        for key in ["short", "missing"] {
+            for err in [
+                store.ttl(key.to_owned()).await.unwrap_err(),
+                store.persist(key.to_owned()).await.unwrap_err(),
+                store.remove(key.to_owned()).await.unwrap_err(),
+            ] {
+                assert!(matches!(
+                    err.downcast_ref::<KvsError>(),
+                    Some(KvsError::KeyNotFound)
+                ));
+            }
+        }
+
+        store
+            .set_with_ttl("short".to_owned(), "5".to_owned(), ttl)
+            .await?;
+        std::thread::sleep(ttl);
+        assert_eq!(store.scan(.., None).await?.len(), 3);
+        store.compact().await?;
+        assert_eq!(store.remove_expired().await?, 0);
+        drop(store);
+
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("short".to_owned()).await?, None);
+        assert_eq!(
+            store.scan_prefix("".to_owned(), None).await?,
+            vec![
+                ("kept".to_owned(), "2".to_owned()),
+                ("long".to_owned(), "3".to_owned()),
+                ("plain".to_owned(), "4".to_owned())
+            ]
+        );
+        Ok(())
+    })
+}
+
+// Should swap values only if they are the expected ones
+#[test]
+fn compare_and_swap() -> Result<()> {
+    let rt = tokio::runtime::Runtime::new().context("failed to create tokio runtime")?;
+    rt.block_on(async move {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        check_compare_and_swap(KvStore::open(temp_dir.path().join("kvs"))?).await?;
+        check_compare_and_swap(SledKvsEngine::open(temp_dir.path().join("sled"))?).await?;
+        check_compare_and_swap(MemoryKvsEngine::new()).await
+    })
+}
+
+async fn check_compare_and_swap<E: KvsEngine>(store: E) -> Result<()> {
+    let mismatch = |err: anyhow::Error| match err.downcast::<KvsError>() {
+        Ok(KvsError::CasMismatch { current }) => current,
+        Ok(err) => panic!("unexpected error {}", err),
+        Err(err) => panic!("unexpected error {}", err),
+    };
+
+    store
+        .compare_and_swap("key".to_owned(), None, Some("1".to_owned()))
+        .await?;
+    assert_eq!(store.get("key".to_owned()).await?, Some("1".to_owned()));
+    let err = store
+        .compare_and_swap("key".to_owned(), None, Some("2".to_owned()))
+        .await
+        .unwrap_err();
+    assert_eq!(mismatch(err), Some(b"1".to_vec()));
+    store
+        .compare_and_swap("key".to_owned(), Some("1".to_owned()), Some("2".to_owned()))
+        .await?;
+    assert_eq!(store.get("key".to_owned()).await?, Some("2".to_owned()));
+    store
+        .compare_and_swap("key".to_owned(), Some("2".to_owned()), None)
+        .await?;
+    assert_eq!(store.get("key".to_owned()).await?, None);
+    let err = store
+        .compare_and_swap("key".to_owned(), Some("2".to_owned()), None)
+        .await
+        .unwrap_err();
+    assert_eq!(mismatch(err), None);
+
+    // an expired key is absent
+    store
+        .set_with_ttl("ttl".to_owned(), "1".to_owned(), Duration::from_millis(50))
+        .await?;
+    std::thread::sleep(Duration::from_millis(100));
+    store
+        .compare_and_swap("ttl".to_owned(), None, Some("2".to_owned()))
+        .await?;
+    assert_eq!(store.ttl("ttl".to_owned()).await?, None);
+
+    // the swapped value takes the expiry given along with it
+    store
+        .compare_and_swap_bytes(
+            b"ttl".to_vec(),
+            Some(b"2".to_vec()),
+            Some(b"3".to_vec()),
+            Some(Duration::from_secs(100)),
+        )
+        .await?;
+    let ttl = store.ttl("ttl".to_owned()).await?.unwrap();
+    assert!(ttl > Duration::from_secs(90) && ttl <= Duration::from_secs(100));
+    store
+        .compare_and_swap("ttl".to_owned(), Some("3".to_owned()), Some("4".to_owned()))
+        .await?;
+    assert_eq!(store.ttl("ttl".to_owned()).await?, None);
+
+    // concurrent increments don't lose updates
+    let mut wg = WaitGroup::new();
+    store.set("counter".to_owned(), "0".to_owned()).await?;
+    for _ in 0..8 {
+        let store = store.clone();
+        let worker = wg.worker();
+        tokio::spawn(async move {
+            for _ in 0..20 {
+                loop {
+                    let current = store.get("counter".to_owned()).await.unwrap();
+                    let next = current.as_ref().unwrap().parse::<u32>().unwrap() + 1;
+                    let result = store
+                        .compare_and_swap("counter".to_owned(), current, Some(next.to_string()))
+                        .await;
+                    if result.is_ok() {
+                        break;
+                    }
+                }
+            }
+            worker.done();
+        });
+    }
+    wg.wait().await;
+    assert_eq!(
+        store.get("counter".to_owned()).await?,
+        Some("160".to_owned())
+    );
+
+    // the swap is never split by a concurrent write, so whichever write
+    // lands last decides both the value and the TTL
+    for round in 0..100 {
+        let swapping = store.clone();
+        let swap = tokio::spawn(async move {
+            let current = swapping.get("race".to_owned()).await?;
+            let result = swapping
+                .compare_and_swap("race".to_owned(), current, Some(format!("swap{}", round)))
+                .await;
+            match result.map_err(|err| err.downcast::<KvsError>()) {
+                Ok(()) | Err(Ok(KvsError::CasMismatch { .. })) => Ok(()),
+                Err(Ok(err)) => Err(err.into()),
+                Err(Err(err)) => Err(err),
+            }
+        });
+        store
+            .set_with_ttl(
+                "race".to_owned(),
+                format!("ttl{}", round),
+                Duration::from_secs(3600),
+            )
+            .await?;
+        swap.await??;
+        let value = store.get("race".to_owned()).await?.unwrap();
+        let ttl = store.ttl("race".to_owned()).await?;
+        assert_eq!(
+            value.starts_with("ttl"),
+            ttl.is_some(),
+            "{} {:?}",
+            value,
+            ttl
+        );
+    }
+    Ok(())
+}
+
+// Should apply write batches all or nothing
+#[test]
+fn write_batches() -> Result<()> {
+    let rt = tokio::runtime::Runtime::new().context("failed to create tokio runtime")?;
+    rt.block_on(async move {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let log = temp_dir.path().join("1.log");
+        let store = KvStore::open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned()).await?;
+        let mut batch = WriteBatch::new();
+        batch.set("key2", "value2");
+        batch.set("key3", "value3");
+        batch.remove("key1");
+        batch.remove("missing");
+        batch.set("key3", "value4");
+        store.write_batch(batch).await?;
+        assert_eq!(store.get("key1".to_owned()).await?, None);
+        assert_eq!(
+            store.get("key3".to_owned()).await?,
+            Some("value4".to_owned())
+        );
+        let committed_len = fs::metadata(&log)?.len();
+
+        let mut batch = WriteBatch::new();
+        batch.set("key1", "value5");
+        batch.remove("key2");
+        store.write_batch(batch).await?;
+        assert_eq!(store.get("key2".to_owned()).await?, None);
+        drop(store);
+
+        // without its commit record, or with a torn one, a batch is not applied
+        let data = fs::read(&log)?;
+        for cut in [1, 32] {
+            fs::write(&log, &data[..data.len() - cut])?;
+            let _ = fs::remove_file(temp_dir.path().join("2.log"));
+            let store = KvStore::open(temp_dir.path())?;
+            assert_eq!(store.get("key1".to_owned()).await?, None);
+            assert_eq!(
+                store.get("key2".to_owned()).await?,
+                Some("value2".to_owned())
+            );
+            assert_eq!(
+                store.get("key3".to_owned()).await?,
+                Some("value4".to_owned())
+            );
+            assert_eq!(fs::metadata(&log)?.len(), committed_len);
+        }
+        Ok(())
+    })
+}
+
+// Should cut off a batch whose append failed and go on writing after it
+#[test]
+fn failed_batch_append() -> Result<()> {
+    let rt = tokio::runtime::Runtime::new().context("failed to create tokio runtime")?;
+    rt.block_on(async move {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let faults = FaultInjector::new();
+        let options = KvStoreOptions::new();
+        let store = KvStore::open_with_faults(temp_dir.path(), options, faults.clone())?;
+        store.set("key1".to_owned(), "value1".to_owned()).await?;
+        let len = fs::metadata(temp_dir.path().join("1.log"))?.len();
+
+        faults.fail_after(40);
+        let mut batch = WriteBatch::new();
+        batch.set("key2", "value2");
+        batch.remove("key1");
+        batch.set("key3", "value3");
+        assert!(store.write_batch(batch).await.is_err());
+        assert!(!faults.crashed());
+        assert_eq!(fs::metadata(temp_dir.path().join("1.log"))?.len(), len);
+        assert_eq!(store.get("key2".to_owned()).await?, None);
+        assert_eq!(
+            store.get("key1".to_owned()).await?,
+            Some("value1".to_owned())
+        );
+
+        let mut batch = WriteBatch::new();
+        batch.set("key4", "value4");
+        store.write_batch(batch).await?;
+        store.set("key5".to_owned(), "value5".to_owned()).await?;
+        drop(store);
+
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(
+            store.scan(.., None).await?,
+            vec![
+                ("key1".to_owned(), "value1".to_owned()),
+                ("key4".to_owned(), "value4".to_owned()),
+                ("key5".to_owned(), "value5".to_owned())
+            ]
+        );
+        Ok(())
+    })
+}
+
+// Once a failed write can't be cut off again, nothing is written after it
+// until the store is reopened
+#[test]
+fn failed_truncate_stops_writes() -> Result<()> {
+    let rt = tokio::runtime::Runtime::new().context("failed to create tokio runtime")?;
+    rt.block_on(async move {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let faults = FaultInjector::new();
+        let options = KvStoreOptions::new();
+        let store = KvStore::open_with_faults(temp_dir.path(), options, faults.clone())?;
+        store.set("key1".to_owned(), "value1".to_owned()).await?;
+        let len = fs::metadata(temp_dir.path().join("1.log"))?.len();
+
+        // the torn record can't be cut off, the store has crashed
+        faults.crash_after(10);
+        assert!(store
+            .set("key2".to_owned(), "value2".to_owned())
+            .await
+            .is_err());
+        assert!(faults.crashed());
+        assert!(fs::metadata(temp_dir.path().join("1.log"))?.len() > len);
+
+        faults.recover();
+        for result in [
+            store.set("key3".to_owned(), "value3".to_owned()).await,
+            store.remove("key1".to_owned()).await,
+        ] {
+            let err = result.expect_err("wrote after a torn record");
+            assert!(err.to_string().contains("reopen the store"), "{}", err);
+        }
+        assert_eq!(
+            store.get("key1".to_owned()).await?,
+            Some("value1".to_owned())
+        );
+        drop(store);
+
+        let store = KvStore::open_with_faults(temp_dir.path(), options, faults.clone())?;
+        store.set("key4".to_owned(), "value4".to_owned()).await?;
+        assert_eq!(
+            store.scan(.., None).await?,
+            vec![
+                ("key1".to_owned(), "value1".to_owned()),
+                ("key4".to_owned(), "value4".to_owned())
+            ]
+        );
+        Ok(())
+    })
+}
+
+// Should let readers see either none or all of a batch
+#[test]
+fn batches_are_read_whole() -> Result<()> {
+    let rt = tokio::runtime::Runtime::new().context("failed to create tokio runtime")?;
+    rt.block_on(async move {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path())?;
+        let keys = (0..10).map(|i| format!("key{}", i)).collect::<Vec<_>>();
+        let mut batch = WriteBatch::new();
+        for key in &keys {
+            batch.set(key.as_str(), "0");
+        }
+        store.write_batch(batch).await?;
+
+        let done = Arc::new(AtomicBool::new(false));
+        let writer = {
+            let store = store.clone();
+            let keys = keys.clone();
+            let done = done.clone();
+            tokio::spawn(async move {
+                for round in 1..200 {
+                    let mut batch = WriteBatch::new();
+                    for key in &keys {
+                        batch.set(key.as_str(), round.to_string());
+                    }
+                    store.write_batch(batch).await?;
+                }
+                done.store(true, Ordering::SeqCst);
+                Ok::<_, anyhow::Error>(())
+            })
+        };
+        while !done.load(Ordering::SeqCst) {
+            let pairs = store.scan_prefix("key".to_owned(), None).await?;
+            assert_eq!(pairs.len(), keys.len());
+            assert!(pairs.iter().all(|(_, value)| *value == pairs[0].1));
+            assert!(store.get(keys[0].clone()).await?.is_some());
+        }
+        writer.await?
+    })
+}
+
+// Should commit transactions unless a key they read has changed
+#[test]
+fn transactions() -> Result<()> {
+    let rt = tokio::runtime::Runtime::new().context("failed to create tokio runtime")?;
+    rt.block_on(async move {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path())?;
+        store.set("b".to_owned(), "1".to_owned()).await?;
+
+        let mut txn1 = store.begin();
+        let mut txn2 = store.begin();
+        assert_eq!(txn1.get("a").await?, None);
+        assert_eq!(txn2.get("a").await?, None);
+        txn1.set("a", "1");
+        txn1.remove("b");
+        // a transaction reads its own writes
+        assert_eq!(txn1.get("a").await?, Some(b"1".to_vec()));
+        assert_eq!(txn1.get("b").await?, None);
+        txn2.set("a", "2");
+        assert_eq!(store.get("a".to_owned()).await?, None);
+
+        txn1.commit().await?;
+        let err = txn2.commit().await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<KvsError>(),
+            Some(KvsError::Conflict)
+        ));
+        assert_eq!(store.get("a".to_owned()).await?, Some("1".to_owned()));
+        assert_eq!(store.get("b".to_owned()).await?, None);
+
+        // keys only written don't conflict
+        let mut txn = store.begin();
+        txn.set("c", "1");
+        store.set("c".to_owned(), "2".to_owned()).await?;
+        txn.commit().await?;
+        assert_eq!(store.get("c".to_owned()).await?, Some("1".to_owned()));
+
+        // a dropped transaction writes nothing
+        let mut txn = store.begin();
+        txn.set("d", "1");
+        drop(txn);
+        assert_eq!(store.get("d".to_owned()).await?, None);
+
+        check_rewritten_value_conflicts(&store).await?;
+        // a compaction moves the value read, but doesn't write it
+        let mut txn = store.begin();
+        assert_eq!(txn.get("a").await?, Some(b"1".to_vec()));
+        txn.set("e", "1");
+        store.compact().await?;
+        txn.commit().await?;
+
+        drop(store);
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("a".to_owned()).await?, Some("1".to_owned()));
+        assert_eq!(store.get("c".to_owned()).await?, Some("1".to_owned()));
+        assert_eq!(store.get("e".to_owned()).await?, Some("1".to_owned()));
+        Ok(())
+    })
+}
+
+// a transaction conflicts with a write of the key it read, even one
+// writing the value it has seen again
+async fn check_rewritten_value_conflicts<E: KvsEngine>(store: &E) -> Result<()> {
+    store.set("a".to_owned(), "1".to_owned()).await?;
+    let mut txn = store.begin();
+    assert_eq!(txn.get("a").await?, Some(b"1".to_vec()));
+    store.set("a".to_owned(), "2".to_owned()).await?;
+    store.set("a".to_owned(), "1".to_owned()).await?;
+    txn.set("f", "1");
+    let err = txn.commit().await.unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<KvsError>(),
+        Some(KvsError::Conflict)
+    ));
+    assert_eq!(store.get("f".to_owned()).await?, None);
+    Ok(())
+}
+
+#[test]
+fn snapshots() -> Result<()> {
+    let rt = tokio::runtime::Runtime::new().context("failed to create tokio runtime")?;
+    rt.block_on(async move {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions::new().max_file_size(1024);
+        let store = KvStore::open_with_options(temp_dir.path(), options)?;
+        store.set("a".to_owned(), "1".to_owned()).await?;
+        store.set("b".to_owned(), "1".to_owned()).await?;
+
+        let snapshot = store.snapshot();
+        store.set("a".to_owned(), "2".to_owned()).await?;
+        store.remove("b".to_owned()).await?;
+        store.set("c".to_owned(), "2".to_owned()).await?;
+        let later = store.snapshot();
+        assert!(later.seq() > snapshot.seq());
+
+        // old versions outlive compactions of the logs holding them
+        for i in 0..100 {
+            store.set(format!("key{}", i), "x".repeat(100)).await?;
+        }
+        store.compact().await?;
+        store.set("a".to_owned(), "3".to_owned()).await?;
+        store.compact().await?;
+
+        assert_eq!(snapshot.get("a".to_owned()).await?, Some("1".to_owned()));
+        assert_eq!(snapshot.get("b".to_owned()).await?, Some("1".to_owned()));
+        assert_eq!(snapshot.get("c".to_owned()).await?, None);
+        assert_eq!(
+            snapshot.scan(.., None).await?,
+            vec![
+                ("a".to_owned(), "1".to_owned()),
+                ("b".to_owned(), "1".to_owned())
+            ]
+        );
+        assert_eq!(later.get("a".to_owned()).await?, Some("2".to_owned()));
+        assert_eq!(later.get("b".to_owned()).await?, None);
+        assert_eq!(
+            later.scan_prefix("key".to_owned(), None).await?,
+            Vec::<(String, String)>::new()
+        );
+        assert_eq!(later.scan(.., Some(2)).await?.len(), 2);
+        assert_eq!(store.get("a".to_owned()).await?, Some("3".to_owned()));
+        assert_eq!(store.get("b".to_owned()).await?, None);
+
+        // superseded values kept for snapshots are not replayed
+        drop(snapshot);
+        drop(later);
+        drop(store);
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("a".to_owned()).await?, Some("3".to_owned()));
+        assert_eq!(store.get("b".to_owned()).await?, None);
+        assert_eq!(store.scan_prefix("key".to_owned(), None).await?.len(), 100);
+
+        // sequence numbers go on after a restart
+        let snapshot = store.snapshot();
+        store.set("a".to_owned(), "4".to_owned()).await?;
+        assert_eq!(snapshot.get("a".to_owned()).await?, Some("3".to_owned()));
+        Ok(())
+    })
+}
+
+#[test]
+fn snapshot_releases() -> Result<()> {
+    let rt = tokio::runtime::Runtime::new().context("failed to create tokio runtime")?;
+    rt.block_on(async move {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path())?;
+        store.set("a".to_owned(), "1".to_owned()).await?;
+        store.set("b".to_owned(), "1".to_owned()).await?;
+        let first = store.snapshot();
+        store.set("a".to_owned(), "2".to_owned()).await?;
+        store.remove("b".to_owned()).await?;
+        let second = store.snapshot();
+        store.set("a".to_owned(), "3".to_owned()).await?;
+        store.set("b".to_owned(), "3".to_owned()).await?;
+        let third = store.snapshot();
+        store.set("a".to_owned(), "4".to_owned()).await?;
+        store.remove("b".to_owned()).await?;
+
+        // scans stop at the limit and start past an excluded bound
+        assert_eq!(
+            first
+                .scan((Bound::Excluded("a".to_owned()), Bound::Unbounded), Some(1))
+                .await?,
+            vec![("b".to_owned(), "1".to_owned())]
+        );
+        assert_eq!(
+            third.scan(.., Some(1)).await?,
+            vec![("a".to_owned(), "3".to_owned())]
+        );
+        assert_eq!(
+            second.scan(.., None).await?,
+            vec![("a".to_owned(), "2".to_owned())]
+        );
+
+        // a removed key stays removed for later snapshots once older ones are gone
+        drop(first);
+        assert_eq!(second.get("b".to_owned()).await?, None);
+        assert_eq!(
+            second.scan_prefix("".to_owned(), None).await?,
+            vec![("a".to_owned(), "2".to_owned())]
+        );
+
+        // releasing a snapshot keeps the versions the others read
+        drop(second);
+        assert_eq!(
+            third.scan(.., None).await?,
+            vec![
+                ("a".to_owned(), "3".to_owned()),
+                ("b".to_owned(), "3".to_owned())
+            ]
+        );
+        let fourth = store.snapshot();
+        assert_eq!(
+            fourth.scan(.., None).await?,
+            vec![("a".to_owned(), "4".to_owned())]
+        );
+        drop(third);
+        assert_eq!(fourth.get("b".to_owned()).await?, None);
+        Ok(())
+    })
+}
+
+#[test]
+fn checkpoint() -> Result<()> {
+    let rt = tokio::runtime::Runtime::new().context("failed to create tokio runtime")?;
+    rt.block_on(async move {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let backup_dir = TempDir::new().expect("unable to create temporary backup directory");
+        let options = KvStoreOptions::new().max_file_size(1024);
+        let store = KvStore::open_with_options(temp_dir.path(), options)?;
+        for key_id in 0..100 {
+            store
+                .set(format!("key{}", key_id), format!("{}", key_id))
+                .await?;
+        }
+        store.compact().await?;
+        for key_id in 0..50 {
+            store.remove(format!("key{}", key_id)).await?;
+        }
+
+        // writes go on while checkpointing
+        let writer = {
+            let store = store.clone();
+            tokio::spawn(async move {
+                for key_id in 0..100 {
+                    store
+                        .set(format!("new_key{}", key_id), "x".repeat(100))
+                        .await
+                        .unwrap();
+                }
+            })
+        };
+        let dest = backup_dir.path().join("backup");
+        store.checkpoint(dest.clone()).await?;
+        writer.await?;
+        let err = store.checkpoint(dest.clone()).await.unwrap_err();
+        assert!(err.to_string().contains("is not empty"));
+
+        // the copy outlives compactions of the store
+        store.compact().await?;
+        drop(store);
+        let backup = KvStore::open(&dest)?;
+        for key_id in 0..100 {
+            let expected = if key_id < 50 {
+                None
+            } else {
+                Some(format!("{}", key_id))
+            };
+            assert_eq!(backup.get(format!("key{}", key_id)).await?, expected);
+        }
+        // concurrent writes are in the copy up to some point, in order
+        let new_keys = backup.scan_prefix("new_key".to_owned(), None).await?;
+        for key_id in 0..new_keys.len() {
+            assert!(backup.get(format!("new_key{}", key_id)).await?.is_some());
+        }
+        Ok(())
+    })
+}
+
+#[test]
+fn sled_checkpoint() -> Result<()> {
+    let rt = tokio::runtime::Runtime::new().context("failed to create tokio runtime")?;
+    rt.block_on(async move {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let backup_dir = TempDir::new().expect("unable to create temporary backup directory");
+        let store = SledKvsEngine::open(temp_dir.path())?;
+        for key_id in 0..1000 {
+            store
+                .set_with_ttl(
+                    format!("key{:04}", key_id),
+                    format!("{}", key_id),
+                    Duration::from_secs(3600),
+                )
+                .await?;
+        }
+
+        // writes go on while the trees are copied, more of them than a
+        // subscriber holds
+        let writer = {
+            let store = store.clone();
+            tokio::spawn(async move {
+                for key_id in 0..2000 {
+                    store
+                        .set(format!("key{:04}", key_id), "new".to_owned())
+                        .await
+                        .unwrap();
+                }
+            })
+        };
+        let dest = backup_dir.path().join("backup");
+        store.checkpoint(dest.clone()).await?;
+        writer.await?;
+        drop(store);
+
+        // the copy holds the writes up to some point, in order, with the
+        // expiry of the keys they didn't reach
+        let backup = SledKvsEngine::open(&dest)?;
+        let pairs = backup.scan(.., None).await?;
+        let written = pairs.iter().take_while(|(_, value)| value == "new").count();
+        assert!(pairs.len() == 1000 || pairs.len() == written);
+        for (key_id, (key, value)) in pairs.iter().enumerate().skip(written) {
+            assert_eq!(key, &format!("key{:04}", key_id));
+            assert_eq!(value, &format!("{}", key_id));
+            assert!(backup.ttl(key.clone()).await?.is_some());
+        }
+        for (key, _) in &pairs[..written] {
+            assert_eq!(backup.ttl(key.clone()).await?, None);
+        }
+        Ok(())
+    })
+}
+
+#[test]
+fn export_and_import() -> Result<()> {
+    let rt = tokio::runtime::Runtime::new().context("failed to create tokio runtime")?;
+    rt.block_on(async move {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path().join("kvs"))?;
+        // more pairs than a page of export
+        for key_id in 0..2500 {
+            store
+                .set(format!("key{}", key_id), format!("value{}", key_id))
+                .await?;
+        }
+        store.set_bytes(vec![0xff, 0], vec![0xfe]).await?;
+        let hour = Duration::from_secs(3600);
+        store
+            .set_with_ttl("key_ttl".to_owned(), "value_ttl".to_owned(), hour)
+            .await?;
+        let pairs = store.scan_bytes(.., None).await?;
+
+        for (format, name) in [(DumpFormat::Json, "json"), (DumpFormat::Binary, "binary")] {
+            let mut writer = DumpWriter::new(Vec::new(), format)?;
+            assert_eq!(kvs::export(&store, &mut writer).await?, 2502);
+            let dump = writer.finish()?;
+
+            let sled = SledKvsEngine::open(temp_dir.path().join(name))?;
+            let reader = DumpReader::new(dump.as_slice())?;
+            assert_eq!(reader.format(), format);
+            assert_eq!(kvs::import(&sled, reader).await?, 2502);
+            assert_eq!(sled.scan_bytes(.., None).await?, pairs);
+            // expiries are kept
+            let ttl = sled.ttl("key_ttl".to_owned()).await?.unwrap();
+            assert!(ttl <= hour && ttl > hour - Duration::from_secs(60));
+            assert_eq!(sled.ttl("key1".to_owned()).await?, None);
+
+            // a truncated dump is detected
+            let truncated = &dump[..dump.len() - 4];
+            let err = DumpReader::new(truncated)?
+                .collect::<Result<Vec<_>>>()
+                .unwrap_err();
+            assert!(matches!(
+                err.downcast_ref::<KvsError>(),
+                Some(KvsError::BrokenDump(_))
+            ));
+        }
+
+        // keys which have expired are skipped
+        let memory = MemoryKvsEngine::new();
+        let dump = "{\"key\":\"gone\",\"value\":\"1\",\"expires_at\":1}\n\
+                    {\"key\":\"kept\",\"value\":\"2\"}\n";
+        assert_eq!(
+            kvs::import(&memory, DumpReader::new(dump.as_bytes())?).await?,
+            2
+        );
+        assert_eq!(
+            memory.scan(.., None).await?,
+            vec![("kept".to_owned(), "2".to_owned())]
+        );
+
+        // binary dumps of version 1 have no expiries
+        let mut dump = b"KD\x01".to_vec();
+        dump.extend_from_slice(&3_u32.to_le_bytes());
+        dump.extend_from_slice(&5_u32.to_le_bytes());
+        dump.extend_from_slice(b"keyvalue");
+        dump.extend_from_slice(&u32::MAX.to_le_bytes());
+        dump.extend_from_slice(&1_u64.to_le_bytes());
+        let entries = DumpReader::new(dump.as_slice())?.collect::<Result<Vec<_>>>()?;
+        assert_eq!(
+            entries,
+            vec![DumpEntry {
+                key: b"key".to_vec(),
+                value: b"value".to_vec(),
+                expires_at: None,
+            }]
+        );
+        Ok(())
+    })
+}
+
+#[test]
+fn memory_engine() -> Result<()> {
+    let rt = tokio::runtime::Runtime::new().context("failed to create tokio runtime")?;
+    rt.block_on(async move {
+        let store = MemoryKvsEngine::new();
+        store.set("key1".to_owned(), "value1".to_owned()).await?;
+        store.set("key2".to_owned(), "value2".to_owned()).await?;
+        store
+            .set_with_ttl(
+                "key3".to_owned(),
+                "value3".to_owned(),
+                Duration::from_millis(50),
+            )
+            .await?;
+        assert_eq!(
+            store.get("key1".to_owned()).await?,
+            Some("value1".to_owned())
+        );
+        assert!(store.ttl("key3".to_owned()).await?.is_some());
+        assert!(matches!(
+            store
+                .remove("key4".to_owned())
+                .await
+                .unwrap_err()
+                .downcast_ref::<KvsError>(),
+            Some(KvsError::KeyNotFound)
+        ));
+
+        store
+            .compare_and_swap("key1".to_owned(), Some("value1".to_owned()), None)
+            .await?;
+        assert!(store
+            .compare_and_swap("key1".to_owned(), Some("value1".to_owned()), None)
+            .await
+            .is_err());
+        let mut txn = store.begin();
+        assert_eq!(txn.get(b"key2".to_vec()).await?, Some(b"value2".to_vec()));
+        txn.set(b"key2".to_vec(), b"value5".to_vec());
+        store.set("key2".to_owned(), "value6".to_owned()).await?;
+        assert!(txn.commit().await.is_err());
+        check_rewritten_value_conflicts(&MemoryKvsEngine::new()).await?;
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(store.get("key3".to_owned()).await?, None);
+        assert_eq!(store.remove_expired().await?, 1);
+        assert_eq!(
+            store.scan_prefix("key".to_owned(), None).await?,
+            vec![("key2".to_owned(), "value6".to_owned())]
+        );
+
+        // a checkpoint is opened as a new store, which writes it back when dropped
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let dest = temp_dir.path().join("backup");
+        store.checkpoint(dest.clone()).await?;
+        assert!(store.checkpoint(dest.clone()).await.is_err());
+        let reopened = MemoryKvsEngine::open(&dest)?;
+        assert_eq!(
+            reopened.get("key2".to_owned()).await?,
+            Some("value6".to_owned())
+        );
+        reopened.set("key7".to_owned(), "value7".to_owned()).await?;
+        drop(reopened);
+        let reopened = MemoryKvsEngine::open(&dest)?;
+        assert_eq!(
+            reopened.scan(.., None).await?,
+            vec![
+                ("key2".to_owned(), "value6".to_owned()),
+                ("key7".to_owned(), "value7".to_owned())
+            ]
+        );
+        Ok(())
+    })
+}
+
+#[test]
+fn conformance_kv_store() -> Result<()> {
+    let rt = tokio::runtime::Runtime::new().context("failed to create tokio runtime")?;
+    rt.block_on(async move {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        conformance::run_all(|dir| KvStore::open(dir), temp_dir.path()).await
+    })
+}
+
+#[test]
+fn conformance_sled() -> Result<()> {
+    let rt = tokio::runtime::Runtime::new().context("failed to create tokio runtime")?;
+    rt.block_on(async move {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        conformance::run_all(open_sled, temp_dir.path()).await
+    })
+}
+
+// sled releases its files in the background once dropped, so opening it
+// again right away may find them still locked
+fn open_sled(dir: &Path) -> Result<SledKvsEngine> {
+    for _ in 0..100 {
+        match SledKvsEngine::open(dir) {
+            Err(err) if is_locked(&err) => std::thread::sleep(Duration::from_millis(10)),
+            result => return result,
+        }
+    }
+    SledKvsEngine::open(dir)
+}
+
+fn is_locked(err: &anyhow::Error) -> bool {
+    matches!(
+        err.downcast_ref::<sled::Error>(),
+        Some(sled::Error::Io(err)) if err.to_string().contains("could not acquire lock")
+    )
+}
+
+#[test]
+fn conformance_memory() -> Result<()> {
+    let rt = tokio::runtime::Runtime::new().context("failed to create tokio runtime")?;
+    rt.block_on(async move {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        conformance::run_all(|dir| MemoryKvsEngine::open(dir), temp_dir.path()).await
+    })
+}
+
+// Random operations against a `KvStore` and a `BTreeMap`, with crashes,
+// reopens and damaged active logs. A reopened store should hold the writes
+// acknowledged before a crash. A flipped bit may only cost the last write,
+// unless the store refuses to open and the log is cut at the damage.
+#[test]
+fn model_based_crash_recovery() -> Result<()> {
+    let rt = tokio::runtime::Runtime::new().context("failed to create tokio runtime")?;
+    rt.block_on(async move {
+        for seed in 0..8 {
+            check_against_model(seed).await?;
+        }
+        Ok(())
+    })
+}
+
+async fn check_against_model(seed: u64) -> Result<()> {
+    let mut rng = SmallRng::seed_from_u64(seed);
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let faults = FaultInjector::new();
+    // small logs, so rotations and compactions are frequent
+    let options = KvStoreOptions::new()
+        .max_file_size(1024)
+        .compaction_threshold(2048);
+    let mut store = KvStore::open_with_faults(temp_dir.path(), options, faults.clone())?;
+    let mut model = BTreeMap::new();
+
+    for cycle in 0..30 {
+        if rng.gen_bool(0.5) {
+            faults.crash_after(rng.gen_range(0..4096));
+        }
+        // states after every acknowledged write, then the one of a write
+        // failed by the crash, which may or may not have been written
+        let mut states = vec![model.clone()];
+        let mut failed = false;
+        // the state before the last acknowledged write to the log
+        let mut before_last_write = 0;
+        for _ in 0..rng.gen_range(1..60) {
+            let mut next = model.clone();
+            let key = format!("key{}", rng.gen_range(0..20));
+            let result = match rng.gen_range(0..10) {
+                0..=4 => {
+                    let value = format!("value{}", rng.gen::<u32>());
+                    next.insert(key.clone(), value.clone());
+                    store.set(key, value).await
+                }
+                5..=6 => {
+                    if next.remove(&key).is_none() {
+                        let err = store.remove(key).await.unwrap_err();
+                        assert!(matches!(
+                            err.downcast_ref::<KvsError>(),
+                            Some(KvsError::KeyNotFound)
+                        ));
+                        continue;
+                    }
+                    store.remove(key).await
+                }
+                7 => {
+                    let mut batch = WriteBatch::new();
+                    for _ in 0..rng.gen_range(1..5) {
+                        let key = format!("key{}", rng.gen_range(0..20));
+                        if rng.gen_bool(0.7) {
+                            let value = format!("value{}", rng.gen::<u32>());
+                            next.insert(key.clone(), value.clone());
+                            batch.set(key, value);
+                        } else {
+                            next.remove(&key);
+                            batch.remove(key);
+                        }
+                    }
+                    store.write_batch(batch).await
+                }
+                8 => {
+                    assert_eq!(store.get(key.clone()).await?, model.get(&key).cloned());
+                    continue;
+                }
+                _ => store.compact().await,
+            };
+            if let Err(err) = result {
+                assert!(faults.crashed(), "seed {} cycle {}: {}", seed, cycle, err);
+                states.push(next);
+                failed = true;
+                break;
+            }
+            if next != model {
+                before_last_write = states.len() - 1;
+            }
+            model = next;
+            states.push(model.clone());
+        }
+        let acknowledged = states.len() - 1 - failed as usize;
+        drop(store);
+        faults.recover();
+
+        let damage = damage_newest_log(temp_dir.path(), &mut rng)?;
+        let valid = match damage {
+            Damage::None => &states[acknowledged..],
+            Damage::Flipped => &states[before_last_write..],
+            Damage::Truncated => &states[..],
+        };
+        let (reopened, valid) =
+            match KvStore::open_with_faults(temp_dir.path(), options, faults.clone()) {
+                Ok(store) => (store, valid),
+                Err(err) => match err.downcast_ref::<KvsError>() {
+                    // a damaged record must be in the newest log, cut it off as an operator would
+                    Some(&KvsError::Corruption { file_id, offset }) if damage != Damage::None => {
+                        let (newest, path) = newest_log(temp_dir.path())?;
+                        assert_eq!(file_id, newest, "seed {} cycle {}", seed, cycle);
+                        OpenOptions::new().write(true).open(path)?.set_len(offset)?;
+                        let store =
+                            KvStore::open_with_faults(temp_dir.path(), options, faults.clone())?;
+                        (store, &states[..])
+                    }
+                    _ => return Err(err),
+                },
+            };
+        store = reopened;
+        let state: BTreeMap<String, String> = store.scan(.., None).await?.into_iter().collect();
+        assert!(
+            valid.contains(&state),
+            "seed {} cycle {}: reopened with {:?}",
+            seed,
+            cycle,
+            state
+        );
+        model = state;
+    }
+    Ok(())
+}
+
+// Clients are told the server's protocol version and engine, requests the
+// server doesn't know are answered with an error and the connection is kept
+#[test]
+fn server_handshake() -> Result<()> {
+    let rt = tokio::runtime::Runtime::new().context("failed to create tokio runtime")?;
+    rt.block_on(async move {
+        let addr = "127.0.0.1:4009";
+        let state = Arc::new(AtomicBool::new(true));
+        let mut server = KvsServer::new(MemoryKvsEngine::new(), state.clone());
+        tokio::spawn(async move { server.start(addr).await });
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        for codec in [Codec::Json, Codec::Bincode] {
+            let client = KvsClient::connect_with_codec(addr, codec).await?;
+            let info = client.server_info();
+            assert_eq!(info.version, 3);
+            assert_eq!(info.server_version, env!("CARGO_PKG_VERSION"));
+            assert_eq!(info.engine, "memory");
+            assert!(info.features.iter().any(|feature| feature == "transaction"));
+        }
+
+        let mut stream = TcpStream::connect(addr).await?;
+        let resp = round_trip(&mut stream, r#"{"Hello":{"version":0,"features":[]}}"#).await?;
+        assert!(
+            resp.contains("protocol version 0 isn't supported"),
+            "{}",
+            resp
+        );
+        let resp = round_trip(&mut stream, r#"{"Frobnicate":{"key":[]}}"#).await?;
+        assert!(resp.contains("unknown request"), "{}", resp);
+        let resp = round_trip(
+            &mut stream,
+            r#"{"Hello":{"version":9,"features":["ttl","x"]}}"#,
+        )
+        .await?;
+        assert!(resp.contains(r#""version":3"#), "{}", resp);
+        assert!(resp.contains(r#""features":["ttl"]"#), "{}", resp);
+        // frames are tagged with the id of their request from now on
+        let resp = round_trip(&mut stream, r#"{"id":7,"body":{"Get":{"key":[107]}}}"#).await?;
+        assert_eq!(resp, r#"{"id":7,"body":{"Ok":null}}"#);
+        // only the unknown request fails, its id is still read
+        let resp = round_trip(&mut stream, r#"{"id":8,"body":{"Frobnicate":{}}}"#).await?;
+        assert!(resp.starts_with(r#"{"id":8,"#), "{}", resp);
+        assert!(resp.contains(r#""code":"ProtocolError""#), "{}", resp);
+        let resp = round_trip(&mut stream, r#"{"id":9,"body":{"Get":{"key":[107]}}}"#).await?;
+        assert_eq!(resp, r#"{"id":9,"body":{"Ok":null}}"#);
+        let resp = round_trip(&mut stream, r#"{"id":"ten"}"#).await?;
+        assert!(resp.starts_with(r#"{"id":0,"#), "{}", resp);
+
+        // clients which never say hello keep working
+        let mut stream = TcpStream::connect(addr).await?;
+        let resp = round_trip(&mut stream, r#"{"Get":{"key":[107]}}"#).await?;
+        assert_eq!(resp, r#"{"Ok":null}"#);
+        let resp = round_trip(&mut stream, r#"{"Rm":{"key":[107]}}"#).await?;
+        assert_eq!(resp, r#"{"Err":"err: Key not found"}"#);
+
+        close_server(state, addr).await;
+        Ok(())
+    })
+}
+
+// JSON frames carry keys and values as strings, or as base64 if they
+// aren't UTF-8, and arrays of bytes are still accepted
+#[test]
+fn json_frames() -> Result<()> {
+    let rt = tokio::runtime::Runtime::new().context("failed to create tokio runtime")?;
+    rt.block_on(async move {
+        let addr = "127.0.0.1:4014";
+        let state = Arc::new(AtomicBool::new(true));
+        let mut server = KvsServer::new(MemoryKvsEngine::new(), state.clone());
+        tokio::spawn(async move { server.start(addr).await });
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let mut stream = TcpStream::connect(addr).await?;
+        let resp = round_trip(&mut stream, r#"{"Set":{"key":"key","value":"value"}}"#).await?;
+        assert_eq!(resp, r#"{"Ok":null}"#);
+        let resp = round_trip(&mut stream, r#"{"Get":{"key":"key"}}"#).await?;
+        assert_eq!(resp, r#"{"Ok":"value"}"#);
+        let resp = round_trip(&mut stream, r#"{"Get":{"key":[107,101,121]}}"#).await?;
+        assert_eq!(resp, r#"{"Ok":"value"}"#);
+
+        let client = KvsClient::connect(addr).await?;
+        client.set_bytes(vec![0xff], vec![0xff, 0]).await?;
+        let resp = round_trip(&mut stream, r#"{"Get":{"key":{"base64":"/w=="}}}"#).await?;
+        assert_eq!(resp, r#"{"Ok":{"base64":"/wA="}}"#);
+        let resp = round_trip(
+            &mut stream,
+            r#"{"Scan":{"start":{"Included":"key"},"end":"Unbounded","limit":null}}"#,
+        )
+        .await?;
+        assert_eq!(
+            resp,
+            r#"{"Pairs":[["key","value"],[{"base64":"/w=="},{"base64":"/wA="}]]}"#
+        );
+        assert_eq!(client.get_bytes(vec![0xff]).await?, Some(vec![0xff, 0]));
+
+        close_server(state, addr).await;
+        Ok(())
+    })
+}
+
+// Errors of failed requests come back to the client as the `KvsError`
+// variants the engine failed with
+#[test]
+fn server_error_codes() -> Result<()> {
+    let rt = tokio::runtime::Runtime::new().context("failed to create tokio runtime")?;
+    rt.block_on(async move {
+        let addr = "127.0.0.1:4010";
+        let state = Arc::new(AtomicBool::new(true));
+        let mut server = KvsServer::new(MemoryKvsEngine::new(), state.clone());
+        tokio::spawn(async move { server.start(addr).await });
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let client = KvsClient::connect(addr).await?;
+        let err = client.remove("key1".to_owned()).await.unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(KvsError::KeyNotFound)));
+        let err = client.ttl("key1".to_owned()).await.unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(KvsError::KeyNotFound)));
+
+        // each transaction has a connection of its own
+        let mut txn1 = client.transaction().await?;
+        let mut txn2 = client.transaction().await?;
+        assert_eq!(txn1.get("key1").await?, None);
+        txn1.set("key1", "value1").await?;
+        txn2.set("key2", "value2").await?;
+        txn2.commit().await?;
+        client.set("key1".to_owned(), "value2".to_owned()).await?;
+        let err = txn1.commit().await.unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(KvsError::Conflict)));
+        assert_eq!(
+            client.get("key2".to_owned()).await?,
+            Some("value2".to_owned())
+        );
+
+        close_server(state, addr).await;
+        Ok(())
+    })
+}
+
+// Calls sharing one client are pipelined on its connection, each gets the
+// response to its own request
+#[test]
+fn pipelined_requests() -> Result<()> {
+    let rt = tokio::runtime::Runtime::new().context("failed to create tokio runtime")?;
+    rt.block_on(async move {
+        let addr = "127.0.0.1:4011";
+        let state = Arc::new(AtomicBool::new(true));
+        let mut server = KvsServer::new(MemoryKvsEngine::new(), state.clone());
+        tokio::spawn(async move { server.start(addr).await });
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        for codec in [Codec::Json, Codec::Bincode] {
+            let client = Arc::new(KvsClient::connect_with_codec(addr, codec).await?);
+            let mut tasks = Vec::new();
+            for task in 0..8 {
+                let client = client.clone();
+                tasks.push(tokio::spawn(async move {
+                    for i in 0..100 {
+                        let key = format!("{}_task{}_key{}", codec, task, i);
+                        client.set(key.clone(), format!("value{}", i)).await?;
+                        assert_eq!(client.get(key).await?, Some(format!("value{}", i)));
+                    }
+                    Ok::<_, anyhow::Error>(())
+                }));
+            }
+            for task in tasks {
+                task.await??;
+            }
+
+            // many calls in flight from one task
+            let gets = (0..100).map(|i| client.get(format!("{}_task0_key{}", codec, i)));
+            let values = futures::future::try_join_all(gets).await?;
+            for (i, value) in values.into_iter().enumerate() {
+                assert_eq!(value, Some(format!("value{}", i)));
+            }
+            let pairs = client.scan_prefix(format!("{}_", codec), None).await?;
+            assert_eq!(pairs.len(), 800);
+        }
+
+        close_server(state, addr).await;
+        Ok(())
+    })
+}
+
+// A request the engine is slow to answer doesn't hold up the requests
+// pipelined after it
+#[test]
+fn slow_pipelined_request() -> Result<()> {
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(4)
+        .enable_all()
+        .build()
+        .context("failed to create tokio runtime")?;
+    rt.block_on(async move {
+        let addr = "127.0.0.1:4016";
+        let state = Arc::new(AtomicBool::new(true));
+        let mut server = KvsServer::new(SlowEngine(MemoryKvsEngine::new()), state.clone());
+        tokio::spawn(async move { server.start(addr).await });
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let client = Arc::new(KvsClient::connect(addr).await?);
+        client.set("fast".to_owned(), "value".to_owned()).await?;
+        let slow = {
+            let client = client.clone();
+            tokio::spawn(async move { client.get("slow".to_owned()).await })
+        };
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let start = Instant::now();
+        assert_eq!(
+            client.get("fast".to_owned()).await?,
+            Some("value".to_owned())
+        );
+        assert!(start.elapsed() < SLOW_GET / 2, "{:?}", start.elapsed());
+        assert!(!slow.is_finished());
+        assert_eq!(slow.await??, None);
+
+        close_server(state, addr).await;
+        Ok(())
+    })
+}
+
+const SLOW_GET: Duration = Duration::from_secs(1);
+
+/// Blocks for `SLOW_GET` when getting the key `slow`, as an engine reading
+/// a cold disk would
+#[derive(Clone)]
+struct SlowEngine(MemoryKvsEngine);
+
+#[async_trait]
+impl KvsEngine for SlowEngine {
+    async fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.0.set_bytes(key, value).await
+    }
+
+    async fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        if key == b"slow" {
+            std::thread::sleep(SLOW_GET);
+        }
+        self.0.get_bytes(key).await
+    }
+
+    async fn set_with_ttl_bytes(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        self.0.set_with_ttl_bytes(key, value, ttl).await
+    }
+
+    async fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        self.0.remove_bytes(key).await
+    }
+
+    async fn ttl_bytes(&self, key: Vec<u8>) -> Result<Option<Duration>> {
+        self.0.ttl_bytes(key).await
+    }
+
+    async fn persist_bytes(&self, key: Vec<u8>) -> Result<()> {
+        self.0.persist_bytes(key).await
+    }
+
+    async fn remove_expired(&self) -> Result<usize> {
+        self.0.remove_expired().await
+    }
+
+    async fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        self.0.write_batch(batch).await
+    }
+
+    async fn commit_transaction(&self, reads: ReadSet, writes: WriteBatch) -> Result<()> {
+        self.0.commit_transaction(reads, writes).await
+    }
+
+    async fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+        ttl: Option<Duration>,
+    ) -> Result<()> {
+        self.0.compare_and_swap_bytes(key, expected, new, ttl).await
+    }
+
+    async fn scan_bytes<R>(&self, range: R, limit: Option<usize>) -> Result<Vec<(Vec<u8>, Vec<u8>)>>
+    where
+        R: RangeBounds<Vec<u8>> + Send + 'static,
+    {
+        self.0.scan_bytes(range, limit).await
+    }
+
+    async fn scan_prefix_bytes(
+        &self,
+        prefix: Vec<u8>,
+        limit: Option<usize>,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.0.scan_prefix_bytes(prefix, limit).await
+    }
+}
+
+// A server speaking protocol version 2 answers one request at a time, so
+// calls sharing a client wait for each other's responses
+#[test]
+fn serial_fallback() -> Result<()> {
+    let rt = tokio::runtime::Runtime::new().context("failed to create tokio runtime")?;
+    rt.block_on(async move {
+        let addr = "127.0.0.1:4017";
+        let listener = TcpListener::bind(addr).await?;
+        // answers a hello with version 2 and every get with its key
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await?;
+            while let Ok(len) = stream.read_u32().await {
+                let mut frame = vec![0; len as usize];
+                stream.read_exact(&mut frame).await?;
+                let request: serde_json::Value = serde_json::from_slice(&frame)?;
+                let resp = if request.get("Hello").is_some() {
+                    serde_json::json!({"Hello": {
+                        "version": 2,
+                        "server_version": "0.1.0",
+                        "engine": "kvs",
+                        "features": [],
+                    }})
+                } else {
+                    serde_json::json!({ "Ok": request["Get"]["key"] })
+                };
+                let resp = serde_json::to_vec(&resp)?;
+                stream.write_u32(resp.len() as u32).await?;
+                stream.write_all(&resp).await?;
+            }
+            Ok::<_, anyhow::Error>(())
+        });
+
+        let client = KvsClient::connect(addr).await?;
+        assert_eq!(client.server_info().version, 2);
+        let gets = (0..100).map(|i| client.get(format!("key{}", i)));
+        let values = futures::future::try_join_all(gets).await?;
+        for (i, value) in values.into_iter().enumerate() {
+            assert_eq!(value, Some(format!("key{}", i)));
+        }
+        Ok(())
+    })
+}
+
+// A server from before the handshake drops the connection on the hello,
+// the client connects again and speaks to it without one
+#[test]
+fn legacy_server() -> Result<()> {
+    let rt = tokio::runtime::Runtime::new().context("failed to create tokio runtime")?;
+    rt.block_on(async move {
+        let addr = "127.0.0.1:4019";
+        let listener = TcpListener::bind(addr).await?;
+        // fails on a hello like any request it doesn't know, and answers
+        // every get with its key
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                while let Ok(len) = stream.read_u32().await {
+                    let mut frame = vec![0; len as usize];
+                    stream.read_exact(&mut frame).await?;
+                    let request: serde_json::Value = serde_json::from_slice(&frame)?;
+                    if request.get("Get").is_none() {
+                        break;
+                    }
+                    let resp = serde_json::json!({ "Ok": request["Get"]["key"] });
+                    let resp = serde_json::to_vec(&resp)?;
+                    stream.write_u32(resp.len() as u32).await?;
+                    stream.write_all(&resp).await?;
+                }
+            }
+            Ok::<_, anyhow::Error>(())
+        });
+
+        let client = KvsClient::connect(addr).await?;
+        assert_eq!(client.server_info().version, 1);
+        assert_eq!(client.server_info().engine, "");
+        for i in 0..3 {
+            let key = format!("key{}", i);
+            assert_eq!(client.get(key.clone()).await?, Some(key));
+        }
+        Ok(())
+    })
+}
+
+// A client keeps working after the server answers a request whose id it
+// couldn't read
+#[test]
+fn undecodable_request_id() -> Result<()> {
+    let rt = tokio::runtime::Runtime::new().context("failed to create tokio runtime")?;
+    rt.block_on(async move {
+        let addr = "127.0.0.1:4018";
+        let listener = TcpListener::bind(addr).await?;
+        // answers every get as if it were unreadable first, then with its key
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await?;
+            while let Ok(len) = stream.read_u32().await {
+                let mut frame = vec![0; len as usize];
+                stream.read_exact(&mut frame).await?;
+                let request: serde_json::Value = serde_json::from_slice(&frame)?;
+                let resps = if request.get("Hello").is_some() {
+                    vec![serde_json::json!({"Hello": {
+                        "version": 3,
+                        "server_version": "0.1.0",
+                        "engine": "kvs",
+                        "features": [],
+                    }})]
+                } else {
+                    vec![
+                        serde_json::json!({"id": 0, "body": {"Error": {
+                            "code": "ProtocolError",
+                            "message": "unknown request",
+                        }}}),
+                        serde_json::json!({
+                            "id": request["id"],
+                            "body": { "Ok": request["body"]["Get"]["key"] },
+                        }),
+                    ]
+                };
+                for resp in resps {
+                    let resp = serde_json::to_vec(&resp)?;
+                    stream.write_u32(resp.len() as u32).await?;
+                    stream.write_all(&resp).await?;
+                }
+            }
+            Ok::<_, anyhow::Error>(())
+        });
+
+        let client = KvsClient::connect(addr).await?;
+        assert_eq!(client.server_info().version, 3);
+        for i in 0..3 {
+            let key = format!("key{}", i);
+            assert_eq!(client.get(key.clone()).await?, Some(key));
+        }
+        Ok(())
+    })
+}
+
+// send a JSON frame and read the one answering it
+async fn round_trip(stream: &mut TcpStream, frame: &str) -> Result<String> {
+    stream.write_u32(frame.len() as u32).await?;
+    stream.write_all(frame.as_bytes()).await?;
+    let mut resp = vec![0; stream.read_u32().await? as usize];
+    stream.read_exact(&mut resp).await?;
+    Ok(String::from_utf8(resp)?)
+}
+
+// truncate the newest log, or flip one of its bytes, at a random offset,
+// return whether it has been damaged
+#[derive(Debug, PartialEq)]
+enum Damage {
+    None,
+    Truncated,
+    Flipped,
+}
+
+fn damage_newest_log(dir: &Path, rng: &mut SmallRng) -> Result<Damage> {
+    let (_, path) = newest_log(dir)?;
+    let len = fs::metadata(&path)?.len();
+    if len == 0 || rng.gen_bool(0.5) {
+        return Ok(Damage::None);
+    }
+    let offset = rng.gen_range(0..len);
+    if rng.gen_bool(0.5) {
+        OpenOptions::new()
+            .write(true)
+            .open(&path)?
+            .set_len(offset)?;
+        Ok(Damage::Truncated)
+    } else {
+        let mut bytes = fs::read(&path)?;
+        bytes[offset as usize] ^= 1 << rng.gen_range(0..8);
+        fs::write(&path, bytes)?;
+        Ok(Damage::Flipped)
+    }
+}
+
+fn newest_log(dir: &Path) -> Result<(u64, PathBuf)> {
+    let file_id = fs::read_dir(dir)?
+        .filter_map(|entry| {
+            let name = entry.ok()?.file_name().into_string().ok()?;
+            name.strip_suffix(".log")?.parse::<u64>().ok()
+        })
+        .max()
+        .expect("no log file");
+    Ok((file_id, dir.join(format!("{}.log", file_id))))
+}